@@ -0,0 +1,290 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::{from_utf8, FromStr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Local};
+use serde_json::json;
+use signal_hook::SigId;
+
+use crate::extract_header;
+
+/// The format of access log lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    // Common Log Format.
+    Common,
+    // Combined Log Format: Common plus the Referer and User-Agent headers.
+    Combined,
+    // One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown access log format {:?}", s)),
+        }
+    }
+}
+
+/// One request, as it's written to the access log.
+pub struct Entry<'a> {
+    pub client: SocketAddr,
+    // The request header as the client sent it. It may be incomplete if the
+    // client never finished sending it.
+    pub head: &'a [u8],
+    pub backend: Option<SocketAddr>,
+    pub status: u16,
+    // Bytes received from and sent to the client.
+    pub bytes: (u64, u64),
+    pub time: DateTime<Local>,
+    pub duration: Duration,
+    // How long the backend took to send its response header.
+    pub backend_duration: Option<Duration>,
+}
+
+impl<'a> Entry<'a> {
+    fn request_line(&self) -> &'a str {
+        let head = match from_utf8(self.head) {
+            Ok(head) => head,
+            Err(e) => from_utf8(&self.head[..e.valid_up_to()]).unwrap_or(""),
+        };
+        head.split("\r\n").next().unwrap_or("")
+    }
+
+    fn header(&self, name: &str) -> Option<&'a str> {
+        extract_header(from_utf8(self.head).ok()?, name)
+    }
+
+    fn format(&self, format: LogFormat) -> String {
+        let request_line = self.request_line();
+        let mut parts = request_line.split(' ');
+        let method = parts.next().unwrap_or("");
+        let uri = parts.next().unwrap_or("");
+        let host = self.header("Host");
+        let backend_duration_ms = self
+            .backend_duration
+            .map(|duration| duration.as_secs_f64() * 1000.0);
+        match format {
+            LogFormat::Json => json!({
+                "time": self.time.to_rfc3339(),
+                "client": self.client.ip().to_string(),
+                "host": host,
+                "method": method,
+                "uri": uri,
+                "backend": self.backend.map(|addr| addr.to_string()),
+                "status": self.status,
+                "bytes_in": self.bytes.0,
+                "bytes_out": self.bytes.1,
+                "duration_ms": self.duration.as_secs_f64() * 1000.0,
+                "backend_duration_ms": backend_duration_ms,
+                "referer": self.header("Referer"),
+                "user_agent": self.header("User-Agent"),
+            })
+            .to_string(),
+            LogFormat::Common | LogFormat::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{}\" {} {}",
+                    self.client.ip(),
+                    self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                    quote(request_line),
+                    self.status,
+                    self.bytes.1
+                );
+                if format == LogFormat::Combined {
+                    line += &format!(
+                        " \"{}\" \"{}\"",
+                        quote(self.header("Referer").unwrap_or("-")),
+                        quote(self.header("User-Agent").unwrap_or("-"))
+                    );
+                }
+                // The standard formats have no room for these, so they're
+                // added at the end where log parsers ignore them.
+                line += &format!(
+                    " host={} backend={} bytes_in={} duration={:.3}",
+                    host.unwrap_or("-"),
+                    self.backend
+                        .map_or("-".to_string(), |addr| addr.to_string()),
+                    self.bytes.0,
+                    self.duration.as_secs_f64()
+                );
+                if let Some(backend_duration) = self.backend_duration {
+                    line += &format!(" backend_duration={:.3}", backend_duration.as_secs_f64());
+                }
+                line
+            }
+        }
+    }
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+enum Output {
+    Stdout,
+    File(File),
+}
+
+/// AccessLog writes a line for every request to stdout or a file. A file
+/// is reopened after a SIGHUP, so that it can be rotated.
+pub struct AccessLog {
+    format: LogFormat,
+    output: Mutex<Output>,
+    path: Option<PathBuf>,
+    reopen: Arc<AtomicBool>,
+    // The SIGHUP handler that sets `reopen`, removed when the log is dropped
+    // after a config reload.
+    hangup: Option<SigId>,
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open access log {}: {}", path.display(), e))
+}
+
+impl AccessLog {
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            output: Mutex::new(Output::Stdout),
+            path: None,
+            reopen: Arc::new(AtomicBool::new(false)),
+            hangup: None,
+        }
+    }
+
+    pub fn file(format: LogFormat, path: &Path) -> Result<AccessLog, String> {
+        let reopen = Arc::new(AtomicBool::new(false));
+        let output = Output::File(open(path)?);
+        let hangup = signal_hook::flag::register(signal_hook::consts::SIGHUP, reopen.clone())
+            .map_err(|e| format!("Failed to handle SIGHUP: {}", e))?;
+        Ok(AccessLog {
+            format,
+            output: Mutex::new(output),
+            path: Some(path.to_path_buf()),
+            reopen,
+            hangup: Some(hangup),
+        })
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        let mut output = self.output.lock().unwrap();
+        if let Some(path) = &self.path {
+            if self.reopen.swap(false, Ordering::SeqCst) {
+                match open(path) {
+                    Ok(file) => *output = Output::File(file),
+                    Err(e) => error!("{}", e),
+                }
+            }
+        }
+        let result = match &mut *output {
+            Output::Stdout => io::stdout().write_all(line.as_bytes()),
+            Output::File(file) => file.write_all(line.as_bytes()),
+        };
+        if let Err(e) = result {
+            error!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        if let Some(hangup) = self.hangup {
+            signal_hook::low_level::unregister(hangup);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::{env, fs};
+
+    fn entry(head: &[u8]) -> Entry<'_> {
+        Entry {
+            client: "10.0.0.1:5000".parse().unwrap(),
+            head,
+            backend: Some("127.0.0.1:8080".parse().unwrap()),
+            status: 200,
+            bytes: (80, 1234),
+            time: Local.timestamp_opt(0, 0).unwrap(),
+            duration: Duration::from_millis(15),
+            backend_duration: Some(Duration::from_millis(10)),
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let head = b"GET /a?b=1 HTTP/1.1\r\nHost: a.com\r\nUser-Agent: curl \"7\"\r\n\r\n";
+        let complete = entry(head);
+        let time = complete.time.format("%d/%b/%Y:%H:%M:%S %z");
+        assert_eq!(
+            complete.format(LogFormat::Common),
+            format!(
+                "10.0.0.1 - - [{}] \"GET /a?b=1 HTTP/1.1\" 200 1234 host=a.com \
+                 backend=127.0.0.1:8080 bytes_in=80 duration=0.015 backend_duration=0.010",
+                time
+            )
+        );
+        assert!(complete
+            .format(LogFormat::Combined)
+            .contains("1234 \"-\" \"curl \\\"7\\\"\" host=a.com"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&complete.format(LogFormat::Json)).unwrap();
+        assert_eq!(json["host"], "a.com");
+        assert_eq!(json["method"], "GET");
+        assert_eq!(json["uri"], "/a?b=1");
+        assert_eq!(json["backend"], "127.0.0.1:8080");
+        assert_eq!(json["status"], 200);
+        assert_eq!(json["bytes_in"], 80);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+
+        // A request header that never finished still gets a line.
+        let partial = Entry {
+            backend: None,
+            status: 408,
+            ..entry(b"GET / HT")
+        };
+        assert!(partial
+            .format(LogFormat::Common)
+            .contains("\"GET / HT\" 408 1234 host=- backend=-"));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = env::temp_dir().join(format!("robby-access-{}.log", std::process::id()));
+        let rotated = path.with_extension("log.1");
+        let log = AccessLog::file(LogFormat::Common, &path).unwrap();
+        log.log(&entry(b"GET / HTTP/1.1\r\n\r\n"));
+        fs::rename(&path, &rotated).unwrap();
+        log.reopen.store(true, Ordering::SeqCst);
+        log.log(&entry(b"GET /second HTTP/1.1\r\n\r\n"));
+        assert!(fs::read_to_string(&rotated).unwrap().contains("GET / "));
+        assert!(fs::read_to_string(&path).unwrap().contains("GET /second "));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&rotated).unwrap();
+    }
+}
@@ -0,0 +1,779 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use openssl::{asn1::Asn1Time, x509::X509};
+use rcgen::{Certificate, CertificateParams};
+use reqwest::{header::HeaderMap, Client, Response, StatusCode};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_rustls::rustls::sign::CertifiedKey;
+
+use crate::{
+    consul::ConsulClient,
+    registry::{ServiceProvider, ServiceRegistry},
+    tls::{self, CertStore},
+};
+
+/// Where HTTP-01 challenges are served from.
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+// How often to look for new hosts and expiring certificates.
+const CHECK_INTERVAL: Duration = Duration::from_secs(300);
+
+// After an order for a host fails, it isn't ordered again for CHECK_INTERVAL,
+// doubling with every failure in a row up to MAX_BACKOFF, to stay clear of
+// the ACME server's rate limits for failed validations.
+const MAX_BACKOFF: Duration = Duration::from_secs(86400);
+
+// A lock older than this belongs to an instance that died while it was
+// ordering a certificate.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(600);
+
+// How long to wait for the ACME server to validate a challenge or issue a
+// certificate.
+const POLL_ATTEMPTS: u32 = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Storage holds everything robby's ACME client needs to share between
+/// instances: the account key, issued certificates, challenge responses,
+/// and locks that keep two instances from ordering the same certificate.
+/// Keys are relative paths like `certs/example.com.pem`.
+pub enum Storage {
+    Dir(PathBuf),
+    Consul {
+        client: ConsulClient,
+        prefix: String,
+    },
+}
+
+impl Storage {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            Storage::Dir(dir) => match fs::read(dir.join(key)) {
+                Ok(value) => Ok(Some(value)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(format!("Failed to read {}: {}", key, e)),
+            },
+            Storage::Consul { client, prefix } => client.kv_get(&format!("{}/{}", prefix, key)),
+        }
+    }
+
+    /// The key authorization to answer the HTTP-01 challenge `token` with.
+    pub fn challenge(&self, token: &str) -> Result<Option<Vec<u8>>, String> {
+        self.get(&challenge_key(token))
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        match self {
+            Storage::Dir(dir) => {
+                let path = dir.join(key);
+                create_parent(&path)?;
+                // Write to a temporary file first so that readers never see
+                // half of a certificate.
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, value)
+                    .and_then(|_| fs::rename(&tmp, &path))
+                    .map_err(|e| format!("Failed to write {}: {}", key, e))
+            }
+            Storage::Consul { client, prefix } => {
+                client.kv_put(&format!("{}/{}", prefix, key), value, None)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Set `key` only if it doesn't exist yet. Returns whether it was set.
+    fn create(&self, key: &str, value: &[u8]) -> Result<bool, String> {
+        match self {
+            Storage::Dir(dir) => {
+                let path = dir.join(key);
+                create_parent(&path)?;
+                let file = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path);
+                match file {
+                    Ok(mut file) => io::Write::write_all(&mut file, value)
+                        .map(|_| true)
+                        .map_err(|e| format!("Failed to write {}: {}", key, e)),
+                    Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+                    Err(e) => Err(format!("Failed to create {}: {}", key, e)),
+                }
+            }
+            Storage::Consul { client, prefix } => {
+                client.kv_put(&format!("{}/{}", prefix, key), value, Some(0))
+            }
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        match self {
+            Storage::Dir(dir) => match fs::remove_file(dir.join(key)) {
+                Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+                    Err(format!("Failed to delete {}: {}", key, e))
+                }
+                _ => Ok(()),
+            },
+            Storage::Consul { client, prefix } => client.kv_delete(&format!("{}/{}", prefix, key)),
+        }
+    }
+}
+
+fn create_parent(path: &Path) -> Result<(), String> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e)),
+        None => Ok(()),
+    }
+}
+
+/// Returns the token of an HTTP-01 challenge request for `uri`. Tokens are
+/// base64url, so anything else can't be a challenge.
+pub fn challenge_token(uri: &str) -> Option<&str> {
+    let token = uri.get(CHALLENGE_PATH.len()..)?;
+    let valid = uri.starts_with(CHALLENGE_PATH)
+        && !token.is_empty()
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if valid {
+        Some(token)
+    } else {
+        None
+    }
+}
+
+fn challenge_key(token: &str) -> String {
+    format!("challenges/{}", token)
+}
+
+fn cert_key(name: &str) -> String {
+    format!("certs/{}.pem", name)
+}
+
+fn lock_key(name: &str) -> String {
+    format!("locks/{}", name)
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+/// AcmeClient orders certificates from an ACME server (RFC 8555), proving
+/// control of each name with an HTTP-01 challenge.
+pub struct AcmeClient {
+    directory_url: String,
+    email: String,
+    client: Client,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+}
+
+// An ACME session: the server's directory, the account URL, and the nonce
+// to use for the next request.
+struct Session {
+    directory: Directory,
+    account: String,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// `account_key` is the PKCS#8 encoded P-256 account key. `ca_file`
+    /// names an extra CA certificate to trust for the ACME server, e.g.
+    /// Pebble's test CA.
+    pub fn new(
+        directory_url: &str,
+        email: &str,
+        ca_file: Option<&Path>,
+        account_key: &[u8],
+    ) -> Result<AcmeClient, String> {
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, account_key)
+            .map_err(|e| format!("Bad ACME account key: {}", e))?;
+        let mut builder = Client::builder().timeout(Duration::from_secs(30));
+        if let Some(ca_file) = ca_file {
+            let pem = fs::read(ca_file)
+                .map_err(|e| format!("Failed to read {}: {}", ca_file.display(), e))?;
+            let cert = reqwest::Certificate::from_pem(&pem)
+                .map_err(|e| format!("Bad CA certificate {}: {}", ca_file.display(), e))?;
+            builder = builder.add_root_certificate(cert);
+        }
+        let client = builder
+            .build()
+            .map_err(|e| format!("Failed to build ACME client: {}", e))?;
+        Ok(AcmeClient {
+            directory_url: directory_url.to_string(),
+            email: email.to_string(),
+            client,
+            key,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn generate_key() -> Result<Vec<u8>, String> {
+        EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+            .map(|key| key.as_ref().to_vec())
+            .map_err(|_| "Failed to generate ACME account key".to_string())
+    }
+
+    fn jwk(&self) -> Value {
+        // The public key is 0x04 followed by the x and y coordinates.
+        let public = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&public[1..33]),
+            "y": b64(&public[33..65]),
+        })
+    }
+
+    /// The JWK thumbprint (RFC 7638) that goes into key authorizations.
+    fn thumbprint(&self) -> String {
+        // serde_json sorts object keys, which is the order RFC 7638 wants.
+        let jwk = self.jwk().to_string();
+        b64(digest(&SHA256, jwk.as_bytes()).as_ref())
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint())
+    }
+
+    fn sign(&self, protected: &Value, payload: &str) -> Result<Value, String> {
+        let protected = b64(protected.to_string().as_bytes());
+        let signing_input = format!("{}.{}", protected, payload);
+        let signature = self
+            .key
+            .sign(&self.rng, signing_input.as_bytes())
+            .map_err(|_| "Failed to sign ACME request".to_string())?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature.as_ref()),
+        }))
+    }
+
+    fn new_nonce(&self, session: &Session) -> Result<String, String> {
+        let response = self
+            .client
+            .head(&session.directory.new_nonce)
+            .send()
+            .map_err(|e| format!("Failed to get ACME nonce: {}", e))?;
+        replay_nonce(response.headers()).ok_or_else(|| "ACME server sent no nonce".to_string())
+    }
+
+    /// POST a JWS signed request to `url`. A `payload` of None makes a
+    /// POST-as-GET request. Requests that fail with a bad nonce are retried
+    /// once with a fresh one.
+    fn post(
+        &self,
+        session: &mut Session,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<Response, String> {
+        let payload = match payload {
+            Some(payload) => b64(payload.to_string().as_bytes()),
+            None => String::new(),
+        };
+        let mut retried = false;
+        loop {
+            let nonce = match session.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce(session)?,
+            };
+            let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+            if session.account.is_empty() {
+                protected["jwk"] = self.jwk();
+            } else {
+                protected["kid"] = json!(session.account);
+            }
+            let body = self.sign(&protected, &payload)?;
+            let mut response = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .map_err(|e| format!("ACME request to {} failed: {}", url, e))?;
+            session.nonce = replay_nonce(response.headers());
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let status = response.status();
+            let problem = response.text().unwrap_or_default();
+            if status == StatusCode::BAD_REQUEST && problem.contains("badNonce") && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(format!(
+                "ACME request to {} failed: {} {}",
+                url, status, problem
+            ));
+        }
+    }
+
+    fn post_json<T>(
+        &self,
+        session: &mut Session,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<T, String>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        self.post(session, url, payload)?
+            .json()
+            .map_err(|e| format!("Bad ACME response from {}: {}", url, e))
+    }
+
+    fn start_session(&self) -> Result<Session, String> {
+        let directory: Directory = self
+            .client
+            .get(&self.directory_url)
+            .send()
+            .and_then(|mut response| response.json())
+            .map_err(|e| format!("Failed to get ACME directory: {}", e))?;
+        let mut session = Session {
+            directory,
+            account: String::new(),
+            nonce: None,
+        };
+        // Registering an existing key just returns the existing account.
+        let mut payload = json!({"termsOfServiceAgreed": true});
+        if !self.email.is_empty() {
+            payload["contact"] = json!([format!("mailto:{}", self.email)]);
+        }
+        let new_account = session.directory.new_account.clone();
+        let response = self.post(&mut session, &new_account, Some(&payload))?;
+        session.account = location(response.headers())?;
+        Ok(session)
+    }
+
+    /// Order a certificate for `name`. Challenge responses are put in
+    /// `storage` for whichever robby instance the ACME server reaches.
+    /// Returns the certificate chain and its private key as one PEM bundle.
+    pub fn order(&self, name: &str, storage: &Storage) -> Result<Vec<u8>, String> {
+        let mut session = self.start_session()?;
+        let payload = json!({"identifiers": [{"type": "dns", "value": name}]});
+        let new_order = session.directory.new_order.clone();
+        let response = self.post(&mut session, &new_order, Some(&payload))?;
+        let order_url = location(response.headers())?;
+        let order: Order = parse_json(response, &new_order)?;
+
+        for authorization in order.authorizations.iter() {
+            self.authorize(&mut session, authorization, storage)?;
+        }
+
+        let params = CertificateParams::new(vec![name.to_string()]);
+        let cert =
+            Certificate::from_params(params).map_err(|e| format!("Failed to make key: {}", e))?;
+        let csr = cert
+            .serialize_request_der()
+            .map_err(|e| format!("Failed to make CSR: {}", e))?;
+        let payload = json!({ "csr": b64(&csr) });
+        self.post(&mut session, &order.finalize, Some(&payload))?;
+
+        let order: Order = self.poll(&mut session, &order_url, |order: &Order| {
+            order.status != "processing" && order.status != "ready"
+        })?;
+        let certificate = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(certificate)) => certificate,
+            (status, _) => return Err(format!("Order for {} is {}", name, status)),
+        };
+        let mut chain = String::new();
+        self.post(&mut session, &certificate, None)?
+            .read_to_string(&mut chain)
+            .map_err(|e| format!("Failed to download certificate: {}", e))?;
+
+        let mut bundle = chain.into_bytes();
+        bundle.extend_from_slice(cert.serialize_private_key_pem().as_bytes());
+        Ok(bundle)
+    }
+
+    fn authorize(&self, session: &mut Session, url: &str, storage: &Storage) -> Result<(), String> {
+        let authorization: Authorization = self.post_json(session, url, None)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.kind == "http-01")
+            .ok_or_else(|| "ACME server offered no http-01 challenge".to_string())?;
+
+        let key = challenge_key(&challenge.token);
+        let key_authorization = self.key_authorization(&challenge.token);
+        storage.put(&key, key_authorization.as_bytes())?;
+        let result = self
+            .post(session, &challenge.url, Some(&json!({})))
+            .and_then(|_| {
+                self.poll(session, url, |authorization: &Authorization| {
+                    authorization.status != "pending"
+                })
+            });
+        if let Err(e) = storage.delete(&key) {
+            warn!("Failed to clean up ACME challenge: {}", e);
+        }
+        match result?.status.as_str() {
+            "valid" => Ok(()),
+            status => Err(format!("Authorization {} is {}", url, status)),
+        }
+    }
+
+    /// POST-as-GET `url` until `done` says the resource is settled.
+    fn poll<T, F>(&self, session: &mut Session, url: &str, done: F) -> Result<T, String>
+    where
+        T: for<'de> Deserialize<'de>,
+        F: Fn(&T) -> bool,
+    {
+        for _ in 0..POLL_ATTEMPTS {
+            let resource: T = self.post_json(session, url, None)?;
+            if done(&resource) {
+                return Ok(resource);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Err(format!("Timed out waiting for {}", url))
+    }
+}
+
+fn parse_json<T>(mut response: Response, url: &str) -> Result<T, String>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    response
+        .json()
+        .map_err(|e| format!("Bad ACME response from {}: {}", url, e))
+}
+
+fn replay_nonce(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("Replay-Nonce")
+        .and_then(|nonce| nonce.to_str().ok())
+        .map(|nonce| nonce.to_string())
+}
+
+fn location(headers: &HeaderMap) -> Result<String, String> {
+    headers
+        .get("Location")
+        .and_then(|location| location.to_str().ok())
+        .map(|location| location.to_string())
+        .ok_or_else(|| "ACME response has no Location".to_string())
+}
+
+/// When the DER encoded certificate `cert` expires, in seconds since the
+/// epoch.
+fn not_after(cert: &[u8]) -> Option<u64> {
+    let cert = X509::from_der(cert).ok()?;
+    let epoch = Asn1Time::from_unix(0).ok()?;
+    let diff = epoch.diff(cert.not_after()).ok()?;
+    let seconds = i64::from(diff.days) * 86400 + i64::from(diff.secs);
+    if seconds < 0 {
+        None
+    } else {
+        Some(seconds as u64)
+    }
+}
+
+/// AcmeManager keeps a certificate for every host in the routing table.
+/// Every robby instance runs one. Whichever instance notices first that a
+/// certificate is missing or due for renewal orders it, and the others pick
+/// it up from the shared storage.
+pub struct AcmeManager {
+    client: AcmeClient,
+    storage: Arc<Storage>,
+    store: Arc<CertStore>,
+    // Renew certificates that expire sooner than this.
+    renew_before: Duration,
+    // For each host whose last order failed, how many orders in a row
+    // failed and when it may be ordered again.
+    failures: Mutex<HashMap<String, (u32, Instant)>>,
+}
+
+impl AcmeManager {
+    pub fn new(
+        client: AcmeClient,
+        storage: Arc<Storage>,
+        store: Arc<CertStore>,
+        renew_before: Duration,
+    ) -> AcmeManager {
+        AcmeManager {
+            client,
+            storage,
+            store,
+            renew_before,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Load the ACME account key from `storage`, creating it if this is the
+    /// first instance to need it.
+    pub fn account_key(storage: &Storage) -> Result<Vec<u8>, String> {
+        if let Some(key) = storage.get("account.key")? {
+            return Ok(key);
+        }
+        let key = AcmeClient::generate_key()?;
+        if storage.create("account.key", &key)? {
+            return Ok(key);
+        }
+        // Another instance created it first.
+        storage
+            .get("account.key")?
+            .ok_or_else(|| "ACME account key disappeared".to_string())
+    }
+
+    /// Check every routed host, then sleep. This never returns.
+    pub fn run<T: ServiceProvider>(&self, registry: Arc<ServiceRegistry<T>>) {
+        loop {
+            match registry.hosts() {
+                Ok(hosts) => self.check_hosts(&hosts),
+                Err(e) => error!("Failed to list hosts: {}", e),
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    }
+
+    fn check_hosts(&self, hosts: &[String]) {
+        let mut certs = HashMap::new();
+        for host in hosts {
+            let name = host.to_lowercase();
+            // HTTP-01 can't prove control of a wildcard, and certificates
+            // are only issued for DNS names.
+            if name.contains('*') || name.contains(':') || name.parse::<std::net::IpAddr>().is_ok()
+            {
+                continue;
+            }
+            let may_order = match self.failures.lock().unwrap().get(&name) {
+                Some((_, retry_at)) => Instant::now() >= *retry_at,
+                None => true,
+            };
+            match self.certificate(&name, may_order) {
+                Ok(Some(cert)) => {
+                    certs.insert(name, cert);
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    let mut failures = self.failures.lock().unwrap();
+                    let count = failures.get(&name).map_or(0, |(count, _)| *count) + 1;
+                    let backoff = CHECK_INTERVAL
+                        .saturating_mul(2u32.saturating_pow(count - 1))
+                        .min(MAX_BACKOFF);
+                    error!(
+                        "Failed to get a certificate for {}, trying again in {:?}: {}",
+                        name, backoff, e
+                    );
+                    failures.insert(name, (count, Instant::now() + backoff));
+                }
+            }
+        }
+        self.store.set_source("acme", certs);
+    }
+
+    /// Get the certificate for `name` from storage, ordering a new one if
+    /// it's missing or expiring, `may_order` is set and no other instance
+    /// is already on it. A stored certificate that can't be parsed counts
+    /// as missing.
+    fn certificate(&self, name: &str, may_order: bool) -> Result<Option<CertifiedKey>, String> {
+        let current = match self.storage.get(&cert_key(name))? {
+            Some(pem) => match tls::parse_pem(name, &pem) {
+                Ok(cert) => Some(cert),
+                Err(e) => {
+                    warn!("Ignoring the stored certificate for {}: {}", name, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        let expires = current
+            .as_ref()
+            .and_then(|cert| cert.end_entity_cert().ok())
+            .and_then(|cert| not_after(&cert.0))
+            .unwrap_or(0);
+        if expires > now() + self.renew_before.as_secs() {
+            return Ok(current);
+        }
+
+        if !may_order || !self.lock(name)? {
+            return Ok(current);
+        }
+        info!("Ordering a certificate for {}", name);
+        let result = self.client.order(name, &self.storage).and_then(|pem| {
+            let cert = tls::parse_pem(name, &pem)?;
+            self.storage.put(&cert_key(name), &pem)?;
+            Ok(cert)
+        });
+        if let Err(e) = self.storage.delete(&lock_key(name)) {
+            warn!("Failed to unlock {}: {}", name, e);
+        }
+        let cert = result?;
+        self.failures.lock().unwrap().remove(name);
+        info!("Got a certificate for {}", name);
+        Ok(Some(cert))
+    }
+
+    /// Take the lock for ordering `name`. Locks left behind by an instance
+    /// that died are broken once they time out.
+    fn lock(&self, name: &str) -> Result<bool, String> {
+        let key = lock_key(name);
+        if self.storage.create(&key, now().to_string().as_bytes())? {
+            return Ok(true);
+        }
+        let locked_at = self
+            .storage
+            .get(&key)?
+            .and_then(|at| String::from_utf8(at).ok())
+            .and_then(|at| at.parse::<u64>().ok())
+            .unwrap_or(0);
+        if locked_at + LOCK_TIMEOUT.as_secs() < now() {
+            self.storage.delete(&key)?;
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEM: &[u8] = include_bytes!("../testdata/localhost.pem");
+
+    #[test]
+    fn test_challenge_token() {
+        assert_eq!(
+            challenge_token("/.well-known/acme-challenge/abc-DEF_123"),
+            Some("abc-DEF_123")
+        );
+        assert_eq!(challenge_token("/.well-known/acme-challenge/"), None);
+        assert_eq!(challenge_token("/.well-known/acme-challenge/../x"), None);
+        assert_eq!(challenge_token("/foo"), None);
+    }
+
+    #[test]
+    fn test_not_after() {
+        let cert = tls::parse_pem("localhost", PEM).unwrap();
+        let der = &cert.end_entity_cert().unwrap().0;
+        // notAfter=Sep 23 02:20:13 2126 GMT
+        assert_eq!(not_after(der), Some(4_945_803_613));
+        assert_eq!(not_after(&der[..100]), None);
+    }
+
+    #[test]
+    fn test_jws() {
+        let key = AcmeClient::generate_key().unwrap();
+        let client = AcmeClient::new("http://localhost/dir", "", None, &key).unwrap();
+        let jwk = client.jwk().to_string();
+        assert!(jwk.starts_with(r#"{"crv":"P-256","kty":"EC","x":""#));
+        assert_eq!(client.thumbprint().len(), 43);
+        assert!(client.key_authorization("token").starts_with("token."));
+
+        let jws = client.sign(&json!({"alg": "ES256"}), "").unwrap();
+        let signature =
+            base64::decode_config(jws["signature"].as_str().unwrap(), base64::URL_SAFE_NO_PAD)
+                .unwrap();
+        assert_eq!(signature.len(), 64);
+    }
+
+    #[test]
+    fn test_dir_storage() {
+        let dir = std::env::temp_dir().join(format!("robby-acme-test-{}", std::process::id()));
+        let storage = Storage::Dir(dir.clone());
+        assert_eq!(storage.get("certs/foo.pem"), Ok(None));
+        storage.put("certs/foo.pem", b"cert").unwrap();
+        assert_eq!(storage.get("certs/foo.pem"), Ok(Some(b"cert".to_vec())));
+
+        assert_eq!(storage.create("locks/foo", b"1"), Ok(true));
+        assert_eq!(storage.create("locks/foo", b"2"), Ok(false));
+        storage.delete("locks/foo").unwrap();
+        storage.delete("locks/foo").unwrap();
+        assert_eq!(storage.create("locks/foo", b"3"), Ok(true));
+
+        let key = AcmeManager::account_key(&storage).unwrap();
+        assert_eq!(AcmeManager::account_key(&storage).unwrap(), key);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_order_backoff() {
+        let dir = std::env::temp_dir().join(format!("robby-acme-backoff-{}", std::process::id()));
+        let storage = Arc::new(Storage::Dir(dir.clone()));
+        // A certificate that doesn't parse is ordered again.
+        storage.put(&cert_key("foo.com"), b"garbage").unwrap();
+        let key = AcmeClient::generate_key().unwrap();
+        // Nothing listens on port 1, so every order fails.
+        let client = AcmeClient::new("http://127.0.0.1:1/dir", "", None, &key).unwrap();
+        let manager = AcmeManager::new(
+            client,
+            storage.clone(),
+            Arc::new(CertStore::new()),
+            Duration::from_secs(86400),
+        );
+        let hosts = vec!["foo.com".to_string()];
+        manager.check_hosts(&hosts);
+        let (count, retry_at) = manager.failures.lock().unwrap()["foo.com"];
+        assert_eq!(count, 1);
+        assert!(retry_at > Instant::now() + CHECK_INTERVAL / 2);
+
+        // The host isn't ordered again until its backoff is over.
+        manager.check_hosts(&hosts);
+        assert_eq!(manager.failures.lock().unwrap()["foo.com"].0, 1);
+        manager
+            .failures
+            .lock()
+            .unwrap()
+            .get_mut("foo.com")
+            .unwrap()
+            .1 = Instant::now();
+        manager.check_hosts(&hosts);
+        let (count, retry_at) = manager.failures.lock().unwrap()["foo.com"];
+        assert_eq!(count, 2);
+        assert!(retry_at > Instant::now() + CHECK_INTERVAL);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+use std::{collections::HashMap, str::from_utf8, sync::Arc};
+
+use chrono::{DateTime, Local};
+use serde_json::{json, Value};
+
+use tokio::{
+    io::{shutdown, write_all},
+    net::TcpStream,
+    prelude::*,
+};
+use url::form_urlencoded::parse;
+
+use crate::{
+    proxy::Proxy,
+    read_http_header::read_http_header,
+    registry::{RouteInfo, ServiceProvider, ServiceRegistry, SourceStatus, UpdateStatus},
+};
+
+const MAX_HEADER: usize = 16384;
+
+/// A response from the admin listener: status line, content type and body.
+type Response = (&'static str, &'static str, String);
+
+/// Answer a single request on the admin listener, then close the
+/// connection.
+pub fn serve_admin<T>(
+    proxy: Arc<Proxy<T>>,
+    client: TcpStream,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    read_http_header(client, vec![0; MAX_HEADER], 0)
+        .and_then(move |(client, buffer, _, split)| {
+            let (status, content_type, body) = if split == 0 {
+                ("431 Request Header Fields Too Large", "text/plain", String::new())
+            } else {
+                respond(&proxy, &buffer[..split])
+            };
+            let mut response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                content_type,
+                body.len()
+            )
+            .into_bytes();
+            response.extend(body.into_bytes());
+            write_all(client, response)
+        })
+        .and_then(|(client, _)| shutdown(client))
+        .map(|_| ())
+        .map_err(|e| warn!("Admin error: {:?}", e))
+}
+
+fn respond<T>(proxy: &Proxy<T>, head: &[u8]) -> Response
+where
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    let mut request_line = from_utf8(head).unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let uri = request_line.next().unwrap_or("");
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    if method != "GET" {
+        return ("405 Method Not Allowed", "text/plain", String::new());
+    }
+    let result = match path {
+        "/metrics" => proxy
+            .registry
+            .stats()
+            .map(|stats| ("text/plain; version=0.0.4", proxy.metrics.render(&stats))),
+        "/routes" => routes(&proxy.registry).map(|body| ("application/json", body)),
+        "/lookup" => {
+            let params: HashMap<String, String> = parse(query.as_bytes()).into_owned().collect();
+            match params.get("host") {
+                Some(host) => {
+                    let path = params.get("path").map_or("/", |path| path.as_str());
+                    lookup(&proxy.registry, host, path).map(|body| ("application/json", body))
+                }
+                None => return ("400 Bad Request", "text/plain", "Missing host".to_string()),
+            }
+        }
+        _ => return ("404 Not Found", "text/plain", "Not Found".to_string()),
+    };
+    match result {
+        Ok((content_type, body)) => ("200 OK", content_type, body),
+        Err(e) => {
+            error!("Failed to read the routing table: {}", e);
+            ("500 Internal Server Error", "text/plain", String::new())
+        }
+    }
+}
+
+fn route_json(route: &RouteInfo) -> Value {
+    let backends: Vec<Value> = route
+        .backends
+        .iter()
+        .map(|backend| {
+            let health_check = match &backend.health_check {
+                Some(Ok(())) => json!({ "passing": true }),
+                Some(Err(e)) => json!({ "passing": false, "error": e }),
+                None => Value::Null,
+            };
+            json!({
+                "addr": backend.addr,
+                "source": backend.source,
+                "status": format!("{:?}", backend.status).to_lowercase(),
+                "weight": backend.weight,
+                "active_connections": backend.active,
+                "health_check": health_check,
+                "ejected": backend.ejected,
+                "marked_down": backend.marked_down,
+            })
+        })
+        .collect();
+    let mut sources: Vec<&str> = route.backends.iter().map(|b| b.source.as_str()).collect();
+    sources.sort();
+    sources.dedup();
+    json!({
+        "host": route.host,
+        "path": route.path,
+        "policy": route.policy.to_string(),
+        "sources": sources,
+        "backends": backends,
+    })
+}
+
+fn update_json(update: Option<UpdateStatus>) -> Value {
+    match update {
+        Some(update) => json!({
+            "time": DateTime::<Local>::from(update.at).to_rfc3339(),
+            "duration_ms": update.duration.as_secs_f64() * 1000.0,
+            "error": update.error,
+        }),
+        None => Value::Null,
+    }
+}
+
+fn source_json(source: &SourceStatus) -> Value {
+    json!({
+        "name": source.name,
+        "routes": source.routes,
+        "last_success": source
+            .last_success
+            .map(|at| DateTime::<Local>::from(at).to_rfc3339()),
+        "error": source.error,
+    })
+}
+
+/// The whole routing table, when it was last updated, and how each source
+/// of routes is doing.
+fn routes<T: ServiceProvider>(registry: &ServiceRegistry<T>) -> Result<String, String> {
+    let routes: Vec<Value> = registry.routes()?.iter().map(route_json).collect();
+    let sources: Vec<Value> = registry.sources()?.iter().map(source_json).collect();
+    let body = json!({
+        "routes": routes,
+        "last_update": update_json(registry.last_update()?),
+        "sources": sources,
+    });
+    Ok(body.to_string())
+}
+
+/// The route a request to `host` and `path` would take.
+fn lookup<T: ServiceProvider>(
+    registry: &ServiceRegistry<T>,
+    host: &str,
+    path: &str,
+) -> Result<String, String> {
+    let (route, backend) = match registry.explain(host, path)? {
+        Some((route, backend)) => (route_json(&route), json!(backend.map(|b| b.to_string()))),
+        None => (Value::Null, Value::Null),
+    };
+    let body = json!({
+        "host": host,
+        "path": path,
+        "route": route,
+        "backend": backend,
+    });
+    Ok(body.to_string())
+}
@@ -0,0 +1,422 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use rand::{seq::SliceRandom, thread_rng, Rng};
+
+use crate::{extract_header, registry::AddressPort};
+
+/// What a LoadBalancer knows about the request it is choosing a backend for.
+pub struct RequestContext<'a> {
+    pub client: IpAddr,
+    pub header: &'a str,
+    // Backends already tried for this request.
+    pub exclude: &'a [SocketAddr],
+}
+
+/// A LoadBalancer picks one of a route's healthy backends for a request.
+/// Each route gets its own LoadBalancer, so implementations may keep
+/// per-route state such as a round-robin counter. A route keeps its
+/// LoadBalancer when the routing table is rebuilt, as long as its policy
+/// doesn't change.
+pub trait LoadBalancer: Send + Sync {
+    fn choose<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        request: &RequestContext,
+    ) -> Option<&'a AddressPort>;
+
+    /// The backend `choose` would pick next, without changing the
+    /// balancer's state. Balancers without state just choose.
+    fn peek<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        request: &RequestContext,
+    ) -> Option<&'a AddressPort> {
+        self.choose(backends, request)
+    }
+}
+
+/// What a ConsistentHash balancer hashes to pick a backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashKey {
+    ClientIp,
+    Header(String),
+    Cookie(String),
+}
+
+/// Policy names a load balancing strategy. Policies are parsed from the
+/// `load_balancing` config setting and from `lb=` tag options:
+///
+/// * `random`
+/// * `round_robin`
+/// * `least_conn`
+/// * `weighted` - random, weighted by each instance's `weight=` tag option
+/// * `p2c` - the less loaded of two random instances
+/// * `hash`, `hash:header:<name>`, `hash:cookie:<name>` - consistent hashing
+///   on the client IP, a request header, or a cookie
+#[derive(Debug, Clone, PartialEq)]
+pub enum Policy {
+    Random,
+    RoundRobin,
+    LeastConnections,
+    WeightedRandom,
+    PowerOfTwo,
+    ConsistentHash(HashKey),
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Policy, String> {
+        match s {
+            "random" => return Ok(Policy::Random),
+            "round_robin" => return Ok(Policy::RoundRobin),
+            "least_conn" => return Ok(Policy::LeastConnections),
+            "weighted" => return Ok(Policy::WeightedRandom),
+            "p2c" => return Ok(Policy::PowerOfTwo),
+            "hash" => return Ok(Policy::ConsistentHash(HashKey::ClientIp)),
+            _ => (),
+        }
+        let parts: Vec<&str> = s.splitn(3, ':').collect();
+        match parts[..] {
+            ["hash", "header", name] if !name.is_empty() => {
+                Ok(Policy::ConsistentHash(HashKey::Header(name.to_string())))
+            }
+            ["hash", "cookie", name] if !name.is_empty() => {
+                Ok(Policy::ConsistentHash(HashKey::Cookie(name.to_string())))
+            }
+            _ => Err(format!("Unknown load balancing policy {:?}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Policy::Random => write!(f, "random"),
+            Policy::RoundRobin => write!(f, "round_robin"),
+            Policy::LeastConnections => write!(f, "least_conn"),
+            Policy::WeightedRandom => write!(f, "weighted"),
+            Policy::PowerOfTwo => write!(f, "p2c"),
+            Policy::ConsistentHash(HashKey::ClientIp) => write!(f, "hash"),
+            Policy::ConsistentHash(HashKey::Header(name)) => write!(f, "hash:header:{}", name),
+            Policy::ConsistentHash(HashKey::Cookie(name)) => write!(f, "hash:cookie:{}", name),
+        }
+    }
+}
+
+impl Policy {
+    pub fn balancer(&self) -> Arc<dyn LoadBalancer> {
+        match self {
+            Policy::Random => Arc::new(Random),
+            Policy::RoundRobin => Arc::new(RoundRobin {
+                next: AtomicUsize::new(0),
+            }),
+            Policy::LeastConnections => Arc::new(LeastConnections),
+            Policy::WeightedRandom => Arc::new(WeightedRandom),
+            Policy::PowerOfTwo => Arc::new(PowerOfTwo),
+            Policy::ConsistentHash(key) => Arc::new(ConsistentHash { key: key.clone() }),
+        }
+    }
+}
+
+struct Random;
+
+impl LoadBalancer for Random {
+    fn choose<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        _request: &RequestContext,
+    ) -> Option<&'a AddressPort> {
+        backends.choose(&mut thread_rng()).copied()
+    }
+}
+
+struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl LoadBalancer for RoundRobin {
+    fn choose<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        _request: &RequestContext,
+    ) -> Option<&'a AddressPort> {
+        if backends.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(backends[next % backends.len()])
+    }
+
+    fn peek<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        _request: &RequestContext,
+    ) -> Option<&'a AddressPort> {
+        if backends.is_empty() {
+            return None;
+        }
+        let next = self.next.load(Ordering::Relaxed);
+        Some(backends[next % backends.len()])
+    }
+}
+
+struct LeastConnections;
+
+impl LoadBalancer for LeastConnections {
+    fn choose<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        _request: &RequestContext,
+    ) -> Option<&'a AddressPort> {
+        let least = backends.iter().map(|b| b.active_connections()).min()?;
+        // Break ties randomly so that idle backends share the load.
+        let candidates: Vec<&'a AddressPort> = backends
+            .iter()
+            .copied()
+            .filter(|b| b.active_connections() == least)
+            .collect();
+        candidates.choose(&mut thread_rng()).copied()
+    }
+}
+
+struct WeightedRandom;
+
+impl LoadBalancer for WeightedRandom {
+    fn choose<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        _request: &RequestContext,
+    ) -> Option<&'a AddressPort> {
+        backends
+            .choose_weighted(&mut thread_rng(), |b| b.weight)
+            .ok()
+            .copied()
+            // All of the weights may be 0.
+            .or_else(|| backends.choose(&mut thread_rng()).copied())
+    }
+}
+
+struct PowerOfTwo;
+
+impl LoadBalancer for PowerOfTwo {
+    fn choose<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        _request: &RequestContext,
+    ) -> Option<&'a AddressPort> {
+        let mut rng = thread_rng();
+        if backends.len() < 2 {
+            return backends.first().copied();
+        }
+        let first = rng.gen_range(0, backends.len());
+        let mut second = rng.gen_range(0, backends.len() - 1);
+        if second >= first {
+            second += 1;
+        }
+        let (first, second) = (backends[first], backends[second]);
+        if second.active_connections() < first.active_connections() {
+            Some(second)
+        } else {
+            Some(first)
+        }
+    }
+}
+
+/// ConsistentHash uses rendezvous hashing: every backend is scored by
+/// hashing it together with the request's key, and the highest score wins.
+/// Adding or removing a backend only moves the keys that scored highest on
+/// that backend.
+struct ConsistentHash {
+    key: HashKey,
+}
+
+impl ConsistentHash {
+    fn request_key(&self, request: &RequestContext) -> String {
+        let key = match &self.key {
+            HashKey::ClientIp => None,
+            HashKey::Header(name) => extract_header(request.header, name),
+            HashKey::Cookie(name) => extract_cookie(request.header, name),
+        };
+        // Requests without the header or cookie stick to a backend by IP.
+        match key {
+            Some(key) => key.to_string(),
+            None => request.client.to_string(),
+        }
+    }
+}
+
+impl LoadBalancer for ConsistentHash {
+    fn choose<'a>(
+        &self,
+        backends: &[&'a AddressPort],
+        request: &RequestContext,
+    ) -> Option<&'a AddressPort> {
+        let key = self.request_key(request);
+        backends.iter().copied().max_by_key(|b| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            b.address.hash(&mut hasher);
+            b.port.hash(&mut hasher);
+            hasher.finish()
+        })
+    }
+}
+
+fn extract_cookie<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    extract_header(header, "Cookie")?
+        .split(';')
+        .filter_map(|cookie| {
+            let mut parts = cookie.trim().splitn(2, '=');
+            Some((parts.next()?, parts.next()?))
+        })
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{BackendState, HealthStatus};
+    use std::sync::Arc;
+
+    fn backends(weights: &[u32]) -> Vec<AddressPort> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| AddressPort {
+                address: "127.0.0.1".to_string(),
+                port: 8080 + i as u16,
+                source: "consul".to_string(),
+                status: HealthStatus::Passing,
+                weight: *weight,
+                proxy_protocol: None,
+                health_check: None,
+                timeouts: Default::default(),
+                state: Arc::new(BackendState::default()),
+            })
+            .collect()
+    }
+
+    fn request(header: &str) -> RequestContext<'_> {
+        RequestContext {
+            client: "10.0.0.1".parse().unwrap(),
+            header,
+            exclude: &[],
+        }
+    }
+
+    fn choose_port(policy: &Policy, backends: &[AddressPort], header: &str) -> u16 {
+        let refs: Vec<&AddressPort> = backends.iter().collect();
+        policy
+            .balancer()
+            .choose(&refs, &request(header))
+            .unwrap()
+            .port
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("random".parse(), Ok(Policy::Random));
+        assert_eq!("round_robin".parse(), Ok(Policy::RoundRobin));
+        assert_eq!("least_conn".parse(), Ok(Policy::LeastConnections));
+        assert_eq!("weighted".parse(), Ok(Policy::WeightedRandom));
+        assert_eq!("p2c".parse(), Ok(Policy::PowerOfTwo));
+        assert_eq!(
+            "hash".parse(),
+            Ok(Policy::ConsistentHash(HashKey::ClientIp))
+        );
+        assert_eq!(
+            "hash:header:X-User".parse(),
+            Ok(Policy::ConsistentHash(HashKey::Header(
+                "X-User".to_string()
+            )))
+        );
+        assert_eq!(
+            "hash:cookie:session".parse(),
+            Ok(Policy::ConsistentHash(HashKey::Cookie(
+                "session".to_string()
+            )))
+        );
+        assert!("hash:header:".parse::<Policy>().is_err());
+        assert!("fastest".parse::<Policy>().is_err());
+
+        for policy in &["round_robin", "hash", "hash:cookie:session"] {
+            assert_eq!(policy.parse::<Policy>().unwrap().to_string(), *policy);
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let backends = backends(&[1, 1, 1]);
+        let refs: Vec<&AddressPort> = backends.iter().collect();
+        let balancer = Policy::RoundRobin.balancer();
+        let ports: Vec<u16> = (0..6)
+            .map(|_| balancer.choose(&refs, &request("")).unwrap().port)
+            .collect();
+        assert_eq!(ports, vec![8080, 8081, 8082, 8080, 8081, 8082]);
+
+        // Peeking shows the next choice without moving on.
+        assert_eq!(balancer.peek(&refs, &request("")).unwrap().port, 8080);
+        assert_eq!(balancer.peek(&refs, &request("")).unwrap().port, 8080);
+        assert_eq!(balancer.choose(&refs, &request("")).unwrap().port, 8080);
+        assert_eq!(balancer.peek(&refs, &request("")).unwrap().port, 8081);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let backends = backends(&[1, 1, 1]);
+        // The first backend is the busiest, so p2c never picks it.
+        let _a = backends[0].connect();
+        let _b = backends[0].connect();
+        let _c = backends[1].connect();
+        for _ in 0..10 {
+            assert_eq!(choose_port(&Policy::LeastConnections, &backends, ""), 8082);
+            assert_ne!(choose_port(&Policy::PowerOfTwo, &backends, ""), 8080);
+        }
+    }
+
+    #[test]
+    fn test_weighted_random() {
+        let backends = backends(&[0, 5, 0]);
+        for _ in 0..10 {
+            assert_eq!(choose_port(&Policy::WeightedRandom, &backends, ""), 8081);
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let backends = backends(&[1, 1, 1, 1]);
+        let policy = "hash:cookie:session".parse().unwrap();
+        let header = "GET / HTTP/1.1\r\nCookie: theme=dark; session=abc123\r\n\r\n";
+        let port = choose_port(&policy, &backends, header);
+        for _ in 0..10 {
+            assert_eq!(choose_port(&policy, &backends, header), port);
+        }
+
+        // Removing a different backend doesn't move the request.
+        let remaining: Vec<AddressPort> = backends
+            .into_iter()
+            .filter(|b| b.port == port || b.port == 8080 || b.port == 8081)
+            .collect();
+        assert_eq!(choose_port(&policy, &remaining, header), port);
+    }
+
+    #[test]
+    fn test_extract_cookie() {
+        let header = "GET / HTTP/1.1\r\nCookie: theme=dark; session=abc123\r\n\r\n";
+        assert_eq!(extract_cookie(header, "session"), Some("abc123"));
+        assert_eq!(extract_cookie(header, "theme"), Some("dark"));
+        assert_eq!(extract_cookie(header, "user"), None);
+    }
+}
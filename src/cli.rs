@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches};
+
+/// The command line options. Those that set config keys take precedence
+/// over the config file and environment variables.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Options {
+    // Read this config file instead of looking for /etc/robby.*.
+    pub config: Option<PathBuf>,
+    // Sets bind_host and bind_port.
+    pub bind: Option<(String, String)>,
+    // Sets consul_address.
+    pub consul_address: Option<String>,
+    // Check the config and exit.
+    pub check_config: bool,
+    // Print the routing table once and exit.
+    pub print_routes: bool,
+}
+
+pub fn app() -> App<'static, 'static> {
+    App::new("robby")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("An HTTP reverse proxy that routes requests to services in Consul or Nomad.")
+        .after_help(
+            "Every config key can also be set with an environment variable named ROBBY_<KEY>, \
+             e.g. ROBBY_BIND_PORT=8080.",
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .value_name("FILE")
+                .help("The config file [default: /etc/robby.{toml,json,yaml,yml,hjson,ini}]"),
+        )
+        .arg(
+            Arg::with_name("bind")
+                .long("bind")
+                .value_name("HOST:PORT")
+                .help("The address of the HTTP listener"),
+        )
+        .arg(
+            Arg::with_name("consul-addr")
+                .long("consul-addr")
+                .value_name("URL")
+                .help("Consul's HTTP API, e.g. http://127.0.0.1:8500"),
+        )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .help("Check the config and exit"),
+        )
+        .arg(
+            Arg::with_name("print-routes")
+                .long("print-routes")
+                .conflicts_with("check-config")
+                .help("Load the routing table once, print it and exit"),
+        )
+}
+
+impl Options {
+    pub fn from_matches(matches: &ArgMatches) -> Result<Options, String> {
+        let bind = match matches.value_of("bind") {
+            Some(bind) => {
+                let (host, port) = bind
+                    .rsplit_once(':')
+                    .ok_or_else(|| format!("--bind needs HOST:PORT, not {:?}", bind))?;
+                Some((host.to_string(), port.to_string()))
+            }
+            None => None,
+        };
+        Ok(Options {
+            config: matches.value_of("config").map(PathBuf::from),
+            bind,
+            consul_address: matches.value_of("consul-addr").map(str::to_string),
+            check_config: matches.is_present("check-config"),
+            print_routes: matches.is_present("print-routes"),
+        })
+    }
+
+    /// The config files to read and watch: the one given with `--config`,
+    /// or /etc/robby with any extension the config crate reads.
+    pub fn config_files(&self) -> Vec<PathBuf> {
+        match &self.config {
+            Some(config) => vec![config.clone()],
+            None => ["toml", "json", "yaml", "yml", "hjson", "ini"]
+                .iter()
+                .map(|extension| PathBuf::from(format!("/etc/robby.{}", extension)))
+                .collect(),
+        }
+    }
+
+    /// The config keys set on the command line.
+    pub fn overrides(&self) -> Vec<(&'static str, String)> {
+        let mut overrides = Vec::new();
+        if let Some((host, port)) = &self.bind {
+            overrides.push(("bind_host", host.clone()));
+            overrides.push(("bind_port", port.clone()));
+        }
+        if let Some(address) = &self.consul_address {
+            overrides.push(("consul_address", address.clone()));
+        }
+        overrides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        let matches = app()
+            .get_matches_from_safe(std::iter::once("robby").chain(args.iter().cloned()))
+            .map_err(|e| e.to_string())?;
+        Options::from_matches(&matches)
+    }
+
+    #[test]
+    fn test_options() {
+        assert_eq!(parse(&[]), Ok(Options::default()));
+        assert_eq!(parse(&[]).unwrap().config_files().len(), 6);
+
+        let options = parse(&[
+            "--config",
+            "/tmp/robby.yml",
+            "--bind",
+            "[::1]:8080",
+            "--consul-addr",
+            "http://consul:8500",
+            "--check-config",
+        ])
+        .unwrap();
+        assert_eq!(
+            options.config_files(),
+            vec![PathBuf::from("/tmp/robby.yml")]
+        );
+        assert!(options.check_config);
+        assert_eq!(
+            options.overrides(),
+            vec![
+                ("bind_host", "[::1]".to_string()),
+                ("bind_port", "8080".to_string()),
+                ("consul_address", "http://consul:8500".to_string()),
+            ]
+        );
+
+        assert!(parse(&["--bind", "8080"]).is_err());
+        assert!(parse(&["--check-config", "--print-routes"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+    }
+}
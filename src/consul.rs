@@ -0,0 +1,449 @@
+use std::{collections::HashMap, fs, path::Path, time::Duration};
+
+use openssl::{pkcs12::Pkcs12, pkey::PKey, x509::X509};
+use reqwest::{
+    header::{HeaderMap, HeaderValue},
+    Certificate, Client, Identity, RequestBuilder, Response, StatusCode,
+};
+use serde::Deserialize;
+
+use crate::registry::{HealthStatus, Indexed, ServiceNode, ServiceProvider};
+
+/// Keys from the KV store with their values.
+pub type KvPairs = Vec<(String, Vec<u8>)>;
+
+/// How to reach Consul. Empty strings leave a setting out.
+#[derive(Debug, Clone, Default)]
+pub struct ConsulConfig {
+    // The HTTP API, e.g. `http://127.0.0.1:8500` or `https://consul:8501`.
+    pub address: String,
+    // The ACL token sent with every request.
+    pub token: String,
+    // A PEM file of CA certificates to trust for an https address.
+    pub ca_file: String,
+    // PEM files with a client certificate and its private key, for Consul
+    // agents that verify incoming connections.
+    pub cert_file: String,
+    pub key_file: String,
+    pub datacenter: String,
+    // The Enterprise namespace and admin partition.
+    pub namespace: String,
+    pub partition: String,
+}
+
+fn read(kind: &str, path: &str) -> Result<Vec<u8>, String> {
+    fs::read(Path::new(path)).map_err(|e| format!("Failed to read {} {}: {}", kind, path, e))
+}
+
+/// The client certificate and key as an Identity, which reqwest only takes
+/// as PKCS #12.
+fn identity(cert_file: &str, key_file: &str) -> Result<Identity, String> {
+    let cert = X509::from_pem(&read("client certificate", cert_file)?)
+        .map_err(|e| format!("Bad client certificate {}: {}", cert_file, e))?;
+    let key = PKey::private_key_from_pem(&read("client key", key_file)?)
+        .map_err(|e| format!("Bad client key {}: {}", key_file, e))?;
+    let der = Pkcs12::builder()
+        .name("robby")
+        .pkey(&key)
+        .cert(&cert)
+        .build2("")
+        .and_then(|pkcs12| pkcs12.to_der())
+        .map_err(|e| format!("Failed to use client certificate {}: {}", cert_file, e))?;
+    Identity::from_pkcs12_der(&der, "")
+        .map_err(|e| format!("Failed to use client certificate {}: {}", cert_file, e))
+}
+
+/// An HTTP client for the Consul or Nomad API. It sends `token` in the
+/// header named with every request unless it's empty, trusts the CA
+/// certificates in `ca_file`, and presents the certificate in `cert_file`.
+pub fn api_client(
+    timeout: Duration,
+    (header, token): (&'static str, &str),
+    ca_file: &str,
+    cert_file: &str,
+    key_file: &str,
+) -> Result<Client, String> {
+    let mut builder = Client::builder().timeout(timeout);
+    if !token.is_empty() {
+        let mut headers = HeaderMap::new();
+        let token = HeaderValue::from_str(token).map_err(|e| format!("Bad token: {}", e))?;
+        headers.insert(header, token);
+        builder = builder.default_headers(headers);
+    }
+    if !ca_file.is_empty() {
+        let ca = Certificate::from_pem(&read("CA file", ca_file)?)
+            .map_err(|e| format!("Bad CA file {}: {}", ca_file, e))?;
+        builder = builder.add_root_certificate(ca);
+    }
+    match (cert_file.is_empty(), key_file.is_empty()) {
+        (true, true) => (),
+        (false, false) => builder = builder.identity(identity(cert_file, key_file)?),
+        _ => return Err("A client certificate needs both a cert and a key".to_string()),
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// ConsulClient talks to the Consul HTTP API using blocking queries.
+/// Every query passes the index from the previous response, so Consul holds
+/// the request open until the data changes or `wait` elapses.
+pub struct ConsulClient {
+    address: String,
+    wait: Duration,
+    client: Client,
+    // The dc, ns and partition parameters sent with every request.
+    scope: Vec<(&'static str, String)>,
+}
+
+impl ConsulClient {
+    pub fn new(config: &ConsulConfig, wait: Duration) -> Result<ConsulClient, String> {
+        // Consul adds up to wait/16 of jitter to a blocking query, so leave
+        // some room before giving up on the request.
+        let client = api_client(
+            wait + wait / 16 + Duration::from_secs(5),
+            ("X-Consul-Token", &config.token),
+            &config.ca_file,
+            &config.cert_file,
+            &config.key_file,
+        )
+        .map_err(|e| format!("Failed to build consul client: {}", e))?;
+        let scope = [
+            ("dc", &config.datacenter),
+            ("ns", &config.namespace),
+            ("partition", &config.partition),
+        ]
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect();
+        Ok(ConsulClient {
+            address: config.address.trim_end_matches('/').to_string(),
+            wait,
+            client,
+            scope,
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn request(&self, request: RequestBuilder) -> RequestBuilder {
+        request.query(&self.scope)
+    }
+
+    fn send(&self, endpoint: &str, index: u64) -> Result<Response, String> {
+        let url = format!("{}/v1/{}", self.address, endpoint);
+        let mut request = self.request(self.client.get(&url));
+        if index > 0 {
+            request = request.query(&[
+                ("index", index.to_string()),
+                ("wait", format!("{}s", self.wait.as_secs())),
+            ]);
+        }
+        request
+            .send()
+            .map_err(|e| format!("Request to {} failed: {}", url, e))
+    }
+
+    fn get(&self, endpoint: &str, index: u64) -> Result<Response, String> {
+        check_status(self.send(endpoint, index)?)
+    }
+
+    /// List every key under `prefix` in the KV store along with its value.
+    pub fn kv_list(&self, prefix: &str, index: u64) -> Result<Indexed<KvPairs>, String> {
+        let response = self.send(&format!("kv/{}?recurse=true", prefix), index)?;
+        // Consul answers 404 when there are no keys under the prefix, but
+        // still sends an index to block on.
+        if response.status() == StatusCode::NOT_FOUND {
+            let index = consul_index(response.headers())?;
+            return Ok(Indexed {
+                index,
+                value: Vec::new(),
+            });
+        }
+        let mut response = check_status(response)?;
+        let index = consul_index(response.headers())?;
+        let entries: Vec<KvEntry> = response
+            .json()
+            .map_err(|e| format!("Error parsing consul response: {}", e))?;
+        let mut value = Vec::new();
+        for entry in entries {
+            // Keys without a value, such as "folders", have a null Value.
+            let key = entry.key;
+            let data = match entry.value {
+                Some(data) => base64::decode(&data)
+                    .map_err(|e| format!("Bad value for key {}: {}", key, e))?,
+                None => continue,
+            };
+            value.push((key, data));
+        }
+        Ok(Indexed { index, value })
+    }
+
+    /// Get the value of `key` from the KV store, or None if it isn't set.
+    pub fn kv_get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let response = self.send(&format!("kv/{}?raw=true", key), 0)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let mut response = check_status(response)?;
+        let mut value = Vec::new();
+        response
+            .copy_to(&mut value)
+            .map_err(|e| format!("Failed to read key {}: {}", key, e))?;
+        Ok(Some(value))
+    }
+
+    /// Set `key` in the KV store. With `cas` set to 0 the key is only
+    /// created if it doesn't exist yet. Returns whether the key was set.
+    pub fn kv_put(&self, key: &str, value: &[u8], cas: Option<u64>) -> Result<bool, String> {
+        let url = format!("{}/v1/kv/{}", self.address, key);
+        let mut request = self.request(self.client.put(&url)).body(value.to_vec());
+        if let Some(cas) = cas {
+            request = request.query(&[("cas", cas.to_string())]);
+        }
+        let response = request
+            .send()
+            .map_err(|e| format!("Request to {} failed: {}", url, e))?;
+        let mut response = check_status(response)?;
+        let set = response
+            .text()
+            .map_err(|e| format!("Error reading consul response: {}", e))?;
+        Ok(set.trim() == "true")
+    }
+
+    pub fn kv_delete(&self, key: &str) -> Result<(), String> {
+        let url = format!("{}/v1/kv/{}", self.address, key);
+        let response = self
+            .request(self.client.delete(&url))
+            .send()
+            .map_err(|e| format!("Request to {} failed: {}", url, e))?;
+        check_status(response).map(|_| ())
+    }
+}
+
+// An entry in a /v1/kv/<prefix>?recurse response. Values are base64 encoded.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KvEntry {
+    key: String,
+    value: Option<String>,
+}
+
+fn check_status(response: Response) -> Result<Response, String> {
+    if !response.status().is_success() {
+        return Err(format!(
+            "Request to {} failed: {}",
+            response.url(),
+            response.status()
+        ));
+    }
+    Ok(response)
+}
+
+// These mirror the parts of a /v1/health/service/<name> response that we
+// care about.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    node: HealthNode,
+    service: HealthService,
+    checks: Vec<HealthCheck>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthNode {
+    address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthService {
+    address: String,
+    port: u16,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthCheck {
+    status: String,
+}
+
+impl HealthEntry {
+    fn status(&self) -> HealthStatus {
+        let mut status = HealthStatus::Passing;
+        for check in self.checks.iter() {
+            match check.status.as_str() {
+                "passing" => (),
+                "warning" => status = HealthStatus::Warning,
+                // Critical, maintenance, or anything we don't understand.
+                _ => return HealthStatus::Critical,
+            }
+        }
+        status
+    }
+
+    fn into_service_node(self) -> ServiceNode {
+        let status = self.status();
+        // Services registered without an address are reachable at the
+        // address of the node they run on.
+        let address = if self.service.address.is_empty() {
+            self.node.address
+        } else {
+            self.service.address
+        };
+        ServiceNode {
+            address,
+            port: self.service.port,
+            tags: self.service.tags.unwrap_or_default(),
+            status,
+        }
+    }
+}
+
+fn consul_index(headers: &HeaderMap) -> Result<u64, String> {
+    headers
+        .get("X-Consul-Index")
+        .ok_or_else(|| "Consul response is missing X-Consul-Index".to_string())?
+        .to_str()
+        .map_err(|e| format!("Bad X-Consul-Index: {}", e))?
+        .parse()
+        .map_err(|e| format!("Bad X-Consul-Index: {}", e))
+}
+
+impl ServiceProvider for ConsulClient {
+    fn services(&self, index: u64) -> Result<Indexed<HashMap<String, Vec<String>>>, String> {
+        let mut response = self.get("catalog/services", index)?;
+        let index = consul_index(response.headers())?;
+        let value = response
+            .json()
+            .map_err(|e| format!("Error parsing consul response: {}", e))?;
+        Ok(Indexed { index, value })
+    }
+
+    fn get_nodes(&self, service: &str, index: u64) -> Result<Indexed<Vec<ServiceNode>>, String> {
+        // We ask for every instance rather than only passing ones, so that
+        // the registry can decide what to do with instances in warning.
+        let mut response = self.get(&format!("health/service/{}", service), index)?;
+        let index = consul_index(response.headers())?;
+        let entries: Vec<HealthEntry> = response
+            .json()
+            .map_err(|e| format!("Error parsing consul response: {}", e))?;
+        let value = entries
+            .into_iter()
+            .map(HealthEntry::into_service_node)
+            .collect();
+        Ok(Indexed { index, value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(json: &str) -> ServiceNode {
+        let entry: HealthEntry = serde_json::from_str(json).unwrap();
+        entry.into_service_node()
+    }
+
+    #[test]
+    fn test_health_entry() {
+        let node = entry(
+            r#"{
+                "Node": {"Node": "foo", "Address": "10.0.0.1"},
+                "Service": {"ID": "web-1", "Service": "web", "Address": "",
+                            "Port": 8080, "Tags": ["urlprefix-foo.com/"]},
+                "Checks": [{"Status": "passing"}, {"Status": "warning"}]
+            }"#,
+        );
+        assert_eq!(node.address, "10.0.0.1");
+        assert_eq!(node.port, 8080);
+        assert_eq!(node.tags, vec!["urlprefix-foo.com/".to_string()]);
+        assert_eq!(node.status, HealthStatus::Warning);
+
+        let node = entry(
+            r#"{
+                "Node": {"Node": "foo", "Address": "10.0.0.1"},
+                "Service": {"ID": "web-1", "Service": "web", "Address": "10.0.0.2",
+                            "Port": 8080, "Tags": null},
+                "Checks": [{"Status": "critical"}, {"Status": "warning"}]
+            }"#,
+        );
+        assert_eq!(node.address, "10.0.0.2");
+        assert!(node.tags.is_empty());
+        assert_eq!(node.status, HealthStatus::Critical);
+    }
+
+    #[test]
+    fn test_request_settings() {
+        use std::{
+            net::TcpListener,
+            sync::{mpsc, Mutex},
+            thread,
+        };
+
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (sender, requests) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let server = rouille::Server::new(format!("127.0.0.1:{}", port), move |request| {
+            let token = request.header("X-Consul-Token").unwrap_or("").to_string();
+            sender
+                .lock()
+                .unwrap()
+                .send((request.raw_url().to_string(), token))
+                .unwrap();
+            rouille::Response::text("{}").with_additional_header("X-Consul-Index", "7")
+        })
+        .unwrap();
+        thread::spawn(move || server.run());
+
+        let config = ConsulConfig {
+            address: format!("http://127.0.0.1:{}/", port),
+            token: "secret".to_string(),
+            datacenter: "dc2".to_string(),
+            partition: "web".to_string(),
+            ..ConsulConfig::default()
+        };
+        let client = ConsulClient::new(&config, Duration::from_secs(1)).unwrap();
+        assert_eq!(client.services(3).unwrap().index, 7);
+        assert_eq!(
+            requests.recv().unwrap(),
+            (
+                "/v1/catalog/services?dc=dc2&partition=web&index=3&wait=1s".to_string(),
+                "secret".to_string()
+            )
+        );
+
+        let half = ConsulConfig {
+            cert_file: "/etc/robby/consul.pem".to_string(),
+            ..config.clone()
+        };
+        assert!(ConsulClient::new(&half, Duration::from_secs(1)).is_err());
+        let missing = ConsulConfig {
+            ca_file: "/nonexistent/ca.pem".to_string(),
+            ..config
+        };
+        assert!(ConsulClient::new(&missing, Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_identity() {
+        let cert = rcgen::generate_simple_self_signed(vec!["robby".to_string()]).unwrap();
+        let dir = std::env::temp_dir();
+        let cert_file = dir.join(format!("robby-consul-{}.crt", std::process::id()));
+        let key_file = cert_file.with_extension("key");
+        fs::write(&cert_file, cert.serialize_pem().unwrap()).unwrap();
+        fs::write(&key_file, cert.serialize_private_key_pem()).unwrap();
+        let (cert_file, key_file) = (cert_file.to_str().unwrap(), key_file.to_str().unwrap());
+        assert!(identity(cert_file, key_file).is_ok());
+        assert!(identity(key_file, cert_file).is_err());
+        fs::remove_file(cert_file).unwrap();
+        fs::remove_file(key_file).unwrap();
+    }
+}
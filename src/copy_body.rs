@@ -0,0 +1,356 @@
+use std::mem;
+
+use futures::*;
+use tokio::{io, prelude::*};
+
+use crate::http::BodyLength;
+
+const BUFFER_SIZE: usize = 8192;
+
+// Chunk size lines and trailers are short. Anything longer is an attack or a
+// broken client.
+const MAX_LINE: usize = 4096;
+
+/// CopyBody copies one HTTP message body from a reader to a writer. Chunked
+/// bodies are copied as-is, including their framing; the framing is only
+/// parsed to find where the body ends.
+pub struct CopyBody<R, W> {
+    state: State<R, W>,
+}
+
+enum State<R, W> {
+    Copying {
+        reader: R,
+        writer: W,
+        framing: Framing,
+        buf: Vec<u8>,
+        // buf[start..end] is body that still has to be written.
+        start: usize,
+        end: usize,
+        // Bytes read past the end of the body.
+        leftover: Vec<u8>,
+        done: bool,
+    },
+    Empty,
+}
+
+enum Framing {
+    Length(u64),
+    Chunked(Chunked),
+    UntilClose,
+}
+
+#[derive(Debug, PartialEq)]
+enum Chunked {
+    // The chunk size line, up to its '\n'.
+    Size(Vec<u8>),
+    Data(u64),
+    // The CRLF after a chunk's data.
+    DataEnd,
+    // Trailer fields, ended by an empty line. Holds the length of the
+    // current line.
+    Trailer(usize),
+    Done,
+}
+
+fn bad_chunk(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// An error from the writer, as opposed to the reader or the body itself.
+#[derive(Debug)]
+struct WriteError(io::Error);
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for WriteError {}
+
+fn write_error(e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), WriteError(e))
+}
+
+/// Whether a CopyBody failed writing, rather than reading or because the
+/// body was malformed.
+pub fn is_write_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<WriteError>())
+}
+
+impl Chunked {
+    fn parse_size(line: &[u8]) -> Result<u64, io::Error> {
+        let line = std::str::from_utf8(line).map_err(|_| bad_chunk("bad chunk size"))?;
+        // Chunk extensions follow a ';'.
+        let size = line.split(';').next().unwrap_or("").trim();
+        u64::from_str_radix(size, 16).map_err(|_| bad_chunk("bad chunk size"))
+    }
+
+    /// Consume as much of `data` as belongs to the body. Returns how many
+    /// bytes were consumed.
+    fn advance(&mut self, data: &[u8]) -> Result<usize, io::Error> {
+        let mut i = 0;
+        while i < data.len() {
+            match self {
+                Chunked::Size(line) => {
+                    if data[i] == b'\n' {
+                        let size = Self::parse_size(line)?;
+                        *self = if size == 0 {
+                            Chunked::Trailer(0)
+                        } else {
+                            Chunked::Data(size)
+                        };
+                    } else if line.len() == MAX_LINE {
+                        return Err(bad_chunk("chunk size line too long"));
+                    } else {
+                        line.push(data[i]);
+                    }
+                    i += 1;
+                }
+                Chunked::Data(remaining) => {
+                    let n = std::cmp::min(*remaining, (data.len() - i) as u64);
+                    *remaining -= n;
+                    i += n as usize;
+                    if *remaining == 0 {
+                        *self = Chunked::DataEnd;
+                    }
+                }
+                Chunked::DataEnd => {
+                    if data[i] == b'\n' {
+                        *self = Chunked::Size(Vec::new());
+                    }
+                    i += 1;
+                }
+                Chunked::Trailer(len) => {
+                    match data[i] {
+                        b'\n' if *len == 0 => *self = Chunked::Done,
+                        b'\n' => *len = 0,
+                        b'\r' => (),
+                        _ if *len == MAX_LINE => return Err(bad_chunk("trailer too long")),
+                        _ => *len += 1,
+                    }
+                    i += 1;
+                }
+                Chunked::Done => break,
+            }
+        }
+        Ok(i)
+    }
+}
+
+impl Framing {
+    /// Consume as much of `data` as belongs to the body. Returns how many
+    /// bytes were consumed and whether the body is complete.
+    fn advance(&mut self, data: &[u8]) -> Result<(usize, bool), io::Error> {
+        match self {
+            Framing::Length(remaining) => {
+                let n = std::cmp::min(*remaining, data.len() as u64);
+                *remaining -= n;
+                Ok((n as usize, *remaining == 0))
+            }
+            Framing::Chunked(chunked) => {
+                let n = chunked.advance(data)?;
+                Ok((n, *chunked == Chunked::Done))
+            }
+            Framing::UntilClose => Ok((data.len(), false)),
+        }
+    }
+}
+
+impl<R, W> Future for CopyBody<R, W>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    type Item = (R, W, Vec<u8>);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        match self.state {
+            State::Copying {
+                ref mut reader,
+                ref mut writer,
+                ref mut framing,
+                ref mut buf,
+                ref mut start,
+                ref mut end,
+                ref mut leftover,
+                ref mut done,
+            } => loop {
+                while *start < *end {
+                    let n = try_ready!(writer.poll_write(&buf[*start..*end]).map_err(write_error));
+                    if n == 0 {
+                        return Err(write_error(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "write zero bytes while copying body",
+                        )));
+                    }
+                    *start += n;
+                }
+                if *done {
+                    try_ready!(writer.poll_flush().map_err(write_error));
+                    break;
+                }
+
+                if buf.len() < BUFFER_SIZE {
+                    buf.resize(BUFFER_SIZE, 0);
+                }
+                let n = try_ready!(reader.poll_read(buf));
+                if n == 0 {
+                    if let Framing::UntilClose = framing {
+                        *done = true;
+                        continue;
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before end of body",
+                    ));
+                }
+                let (body, finished) = framing.advance(&buf[..n])?;
+                *start = 0;
+                *end = body;
+                if finished {
+                    *leftover = buf[body..n].to_vec();
+                    *done = true;
+                }
+            },
+            State::Empty => panic!("poll a CopyBody after it's done"),
+        }
+
+        match mem::replace(&mut self.state, State::Empty) {
+            State::Copying {
+                reader,
+                writer,
+                leftover,
+                ..
+            } => Ok(Async::Ready((reader, writer, leftover))),
+            State::Empty => panic!(),
+        }
+    }
+}
+
+/// Copy a body of length `length` from `reader` to `writer`. `buf` holds
+/// bytes that were already read from `reader`, e.g. along with the header.
+/// The future resolves to the reader, the writer, and any bytes that were
+/// read past the end of the body.
+pub fn copy_body<R, W>(
+    reader: R,
+    writer: W,
+    length: BodyLength,
+    buf: Vec<u8>,
+) -> Result<CopyBody<R, W>, io::Error>
+where
+    R: AsyncRead,
+    W: AsyncWrite,
+{
+    let mut framing = match length {
+        BodyLength::Length(length) => Framing::Length(length),
+        BodyLength::Chunked => Framing::Chunked(Chunked::Size(Vec::new())),
+        BodyLength::UntilClose => Framing::UntilClose,
+    };
+    let (body, done) = framing.advance(&buf)?;
+    let leftover = if done {
+        buf[body..].to_vec()
+    } else {
+        Vec::new()
+    };
+    Ok(CopyBody {
+        state: State::Copying {
+            reader,
+            writer,
+            framing,
+            buf,
+            start: 0,
+            end: body,
+            leftover,
+            done,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn copy(length: BodyLength, buffered: &[u8], rest: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let reader = Cursor::new(rest.to_vec());
+        let writer = Cursor::new(Vec::new());
+        let (_, writer, leftover) = copy_body(reader, writer, length, buffered.to_vec())
+            .unwrap()
+            .wait()
+            .unwrap();
+        (writer.into_inner(), leftover)
+    }
+
+    #[test]
+    fn test_copy_length() {
+        let (body, leftover) = copy(BodyLength::Length(11), b"hello", b" worldGET /");
+        assert_eq!(body, b"hello world");
+        assert_eq!(leftover, b"GET /");
+
+        let (body, leftover) = copy(BodyLength::Length(0), b"GET /", b"");
+        assert!(body.is_empty());
+        assert_eq!(leftover, b"GET /");
+
+        let (body, leftover) = copy(BodyLength::Length(3), b"abcdef", b"");
+        assert_eq!(body, b"abc");
+        assert_eq!(leftover, b"def");
+    }
+
+    #[test]
+    fn test_copy_chunked() {
+        let chunked = b"5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: yes\r\n\r\n";
+        let (body, leftover) = copy(
+            BodyLength::Chunked,
+            &chunked[..7],
+            &[&chunked[7..], b"GET /"].concat(),
+        );
+        assert_eq!(&body[..], &chunked[..]);
+        assert_eq!(leftover, b"GET /");
+
+        let chunked = b"B\r\nhello world\r\n0\r\n\r\n";
+        let (body, leftover) = copy(BodyLength::Chunked, chunked, b"");
+        assert_eq!(&body[..], &chunked[..]);
+        assert!(leftover.is_empty());
+    }
+
+    #[test]
+    fn test_copy_until_close() {
+        let (body, leftover) = copy(BodyLength::UntilClose, b"hello", b" world");
+        assert_eq!(body, b"hello world");
+        assert!(leftover.is_empty());
+    }
+
+    #[test]
+    fn test_copy_errors() {
+        let reader = Cursor::new(b"lo".to_vec());
+        let writer = Cursor::new(Vec::new());
+        let result = copy_body(reader, writer, BodyLength::Length(6), b"hel".to_vec())
+            .unwrap()
+            .wait();
+        assert!(!is_write_error(&result.err().unwrap()));
+
+        // A writer with no room left.
+        let mut full = [0; 4];
+        let writer = Cursor::new(&mut full[..]);
+        let result = copy_body(
+            Cursor::new(Vec::new()),
+            writer,
+            BodyLength::Length(6),
+            b"hello!".to_vec(),
+        )
+        .unwrap()
+        .wait();
+        assert!(is_write_error(&result.err().unwrap()));
+
+        let result = copy_body(
+            Cursor::new(Vec::new()),
+            Cursor::new(Vec::new()),
+            BodyLength::Chunked,
+            b"zz\r\n".to_vec(),
+        );
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+const HTML_TEMPLATE: &str = "<!DOCTYPE html>
+<html>
+<head><title>{status} {reason}</title></head>
+<body>
+<h1>{status} {reason}</h1>
+<p>{message}</p>
+</body>
+</html>
+";
+
+const JSON_TEMPLATE: &str =
+    "{\"status\": {status}, \"error\": \"{reason}\", \"message\": \"{message}\"}\n";
+
+/// The format of the bodies of the error responses robby sends itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "html" => Ok(Format::Html),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown error format {:?}", s)),
+        }
+    }
+}
+
+impl Format {
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Html => "html",
+            Format::Json => "json",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+}
+
+/// The reason phrase and a description for each status robby sends.
+fn describe(status: u16) -> (&'static str, &'static str) {
+    match status {
+        400 => ("Bad Request", "The request could not be understood."),
+        404 => ("Not Found", "No service is routed for this host and path."),
+        408 => (
+            "Request Timeout",
+            "The request header wasn't received in time.",
+        ),
+        431 => (
+            "Request Header Fields Too Large",
+            "The request header is too large.",
+        ),
+        502 => (
+            "Bad Gateway",
+            "The upstream server could not be reached or sent an invalid response.",
+        ),
+        504 => (
+            "Gateway Timeout",
+            "The upstream server didn't respond in time.",
+        ),
+        _ => ("Error", "The request failed."),
+    }
+}
+
+/// ErrorPages builds the responses robby sends when it can't forward a
+/// request. Bodies are rendered from templates in which `{status}`,
+/// `{reason}` and `{message}` are replaced.
+#[derive(Debug, Clone)]
+pub struct ErrorPages {
+    format: Format,
+    // Templates by status. Status 0 holds the template for every status
+    // without its own.
+    templates: HashMap<u16, String>,
+}
+
+impl Default for ErrorPages {
+    fn default() -> ErrorPages {
+        ErrorPages::new(Format::Html)
+    }
+}
+
+impl ErrorPages {
+    /// Error pages using the built-in template for `format`.
+    pub fn new(format: Format) -> ErrorPages {
+        let template = match format {
+            Format::Html => HTML_TEMPLATE,
+            Format::Json => JSON_TEMPLATE,
+        };
+        let mut templates = HashMap::new();
+        templates.insert(0, template.to_string());
+        ErrorPages { format, templates }
+    }
+
+    /// Error pages with templates from `dir`. A file named `<status>.html`
+    /// or `<status>.json`, depending on `format`, is used for that status,
+    /// and `default.html` or `default.json` for every other status. Any
+    /// status without a file uses the built-in template.
+    pub fn load(format: Format, dir: &Path) -> Result<ErrorPages, String> {
+        let mut pages = ErrorPages::new(format);
+        let entries =
+            fs::read_dir(dir).map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?;
+        for entry in entries {
+            let path = entry
+                .map_err(|e| format!("Failed to read {}: {}", dir.display(), e))?
+                .path();
+            if path.extension() != Some(format.extension().as_ref()) {
+                continue;
+            }
+            let status = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some("default") => 0,
+                Some(stem) => match stem.parse() {
+                    Ok(status) => status,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            let template = fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            pages.templates.insert(status, template);
+        }
+        Ok(pages)
+    }
+
+    /// A complete response with `status`. The connection is closed after
+    /// it, since the rest of the request may not have been read.
+    pub fn response(&self, status: u16) -> Vec<u8> {
+        let (reason, message) = describe(status);
+        let template = self
+            .templates
+            .get(&status)
+            .or_else(|| self.templates.get(&0))
+            .map_or("", |template| template.as_str());
+        let body = template
+            .replace("{status}", &status.to_string())
+            .replace("{reason}", reason)
+            .replace("{message}", message);
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            status,
+            reason,
+            self.format.content_type(),
+            body.len()
+        )
+        .into_bytes();
+        response.extend(body.into_bytes());
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(response: &[u8]) -> String {
+        let response = String::from_utf8(response.to_vec()).unwrap();
+        response.split_once("\r\n\r\n").unwrap().1.to_string()
+    }
+
+    #[test]
+    fn test_response() {
+        let response = ErrorPages::default().response(502);
+        let text = String::from_utf8(response.clone()).unwrap();
+        assert!(text.starts_with("HTTP/1.1 502 Bad Gateway\r\n"));
+        assert!(text.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(text.contains(&format!("Content-Length: {}\r\n", body(&response).len())));
+        assert!(body(&response).contains("<h1>502 Bad Gateway</h1>"));
+
+        let json = ErrorPages::new(Format::Json).response(404);
+        let json: serde_json::Value = serde_json::from_str(&body(&json)).unwrap();
+        assert_eq!(json["status"], 404);
+        assert_eq!(json["error"], "Not Found");
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir().join(format!("robby-error-pages-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("404.html"), "missing {status}").unwrap();
+        fs::write(dir.join("default.html"), "oops {reason}").unwrap();
+        fs::write(dir.join("404.json"), "ignored").unwrap();
+
+        let pages = ErrorPages::load(Format::Html, &dir).unwrap();
+        assert_eq!(body(&pages.response(404)), "missing 404");
+        assert_eq!(body(&pages.response(502)), "oops Bad Gateway");
+
+        // Only templates in the configured format are used.
+        let pages = ErrorPages::load(Format::Json, &dir).unwrap();
+        assert_eq!(body(&pages.response(404)), "ignored");
+        assert!(body(&pages.response(502)).starts_with("{\"status\": 502"));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(ErrorPages::load(Format::Html, &dir).is_err());
+    }
+}
@@ -0,0 +1,289 @@
+use std::{net::IpAddr, str::FromStr};
+
+// Headers robby sets. Values from untrusted clients are dropped.
+const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+const X_FORWARDED_PORT: &str = "X-Forwarded-Port";
+const X_REAL_IP: &str = "X-Real-IP";
+const FORWARDED: &str = "Forwarded";
+
+const FORWARDING_HEADERS: [&str; 6] = [
+    X_FORWARDED_FOR,
+    X_FORWARDED_HOST,
+    X_FORWARDED_PROTO,
+    X_FORWARDED_PORT,
+    X_REAL_IP,
+    FORWARDED,
+];
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8`. A bare address is a
+/// network of one.
+#[derive(Debug, Clone, PartialEq)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts
+            .next()
+            .unwrap_or("")
+            .trim()
+            .parse()
+            .map_err(|e| format!("Bad address {:?}: {}", s, e))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("Bad prefix length in {:?}", s))?,
+            None => max,
+        };
+        Ok(Network { addr, prefix })
+    }
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                (u128::from(u32::from(net)), u128::from(u32::from(ip)), 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        let shift = bits - u32::from(self.prefix);
+        if shift >= 128 {
+            return true;
+        }
+        net >> shift == ip >> shift
+    }
+}
+
+/// The proxies in front of robby whose forwarding headers can be believed.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<Network>,
+}
+
+impl TrustedProxies {
+    /// Parse a list of addresses and CIDR networks.
+    pub fn parse(networks: &[String]) -> Result<TrustedProxies, String> {
+        let networks = networks
+            .iter()
+            .map(|network| network.parse())
+            .collect::<Result<_, _>>()?;
+        Ok(TrustedProxies { networks })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // An IPv4 client on a dual-stack socket shows up as ::ffff:a.b.c.d.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        self.networks.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// What robby knows about the client that the backend should too.
+pub struct Forwarding<'a> {
+    pub client: IpAddr,
+    pub host: &'a str,
+    pub proto: &'a str,
+    pub port: u16,
+    // Whether the client is a trusted proxy whose forwarding headers are
+    // kept.
+    pub trusted: bool,
+}
+
+/// Rewrite the request header `head` to tell the backend about the client.
+/// The client's address is appended to X-Forwarded-For and Forwarded. When
+/// the client is a trusted proxy, the values it sent are kept; otherwise
+/// any forwarding headers it sent are dropped, since they can't be
+/// believed.
+pub fn rewrite_header(head: &str, forwarding: &Forwarding) -> Vec<u8> {
+    let mut lines = head.trim_end_matches("\r\n").split("\r\n");
+    let mut out = String::with_capacity(head.len() + 256);
+    out.push_str(lines.next().unwrap_or(""));
+    out.push_str("\r\n");
+
+    let mut existing: Vec<(&str, &str)> = Vec::new();
+    for line in lines {
+        let (name, value) = line.split_once(':').unwrap_or((line, ""));
+        match FORWARDING_HEADERS
+            .iter()
+            .find(|header| header.eq_ignore_ascii_case(name.trim()))
+        {
+            Some(header) => {
+                if forwarding.trusted {
+                    existing.push((header, value.trim()));
+                }
+            }
+            None => {
+                out.push_str(line);
+                out.push_str("\r\n");
+            }
+        }
+    }
+    let first = |name: &str| {
+        existing
+            .iter()
+            .find(|(header, _)| *header == name)
+            .map(|(_, value)| *value)
+    };
+    // Headers that may be repeated are joined into one list.
+    let joined = |name: &str| {
+        existing
+            .iter()
+            .filter(|(header, _)| *header == name)
+            .map(|(_, value)| *value)
+            .collect::<Vec<&str>>()
+            .join(", ")
+    };
+
+    let client = forwarding.client.to_string();
+    let mut push = |name: &str, value: &str| {
+        out.push_str(name);
+        out.push_str(": ");
+        out.push_str(value);
+        out.push_str("\r\n");
+    };
+
+    let port = forwarding.port.to_string();
+    let forwarded_for = joined(X_FORWARDED_FOR);
+    if forwarded_for.is_empty() {
+        push(X_FORWARDED_FOR, &client);
+    } else {
+        push(X_FORWARDED_FOR, &format!("{}, {}", forwarded_for, client));
+    }
+    push(
+        X_FORWARDED_HOST,
+        first(X_FORWARDED_HOST).unwrap_or(forwarding.host),
+    );
+    push(
+        X_FORWARDED_PROTO,
+        first(X_FORWARDED_PROTO).unwrap_or(forwarding.proto),
+    );
+    push(X_FORWARDED_PORT, first(X_FORWARDED_PORT).unwrap_or(&port));
+    push(X_REAL_IP, first(X_REAL_IP).unwrap_or(&client));
+
+    let element = forwarded_element(forwarding);
+    let forwarded = joined(FORWARDED);
+    if forwarded.is_empty() {
+        push(FORWARDED, &element);
+    } else {
+        push(FORWARDED, &format!("{}, {}", forwarded, element));
+    }
+
+    out.push_str("\r\n");
+    out.into_bytes()
+}
+
+/// An RFC 7239 Forwarded element for this hop.
+fn forwarded_element(forwarding: &Forwarding) -> String {
+    // IPv6 addresses have to be bracketed and quoted.
+    let client = match forwarding.client {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let mut element = format!("for={}", client);
+    if !forwarding.host.is_empty() {
+        element.push_str(&format!(";host={}", quote(forwarding.host)));
+    }
+    element.push_str(&format!(";proto={}", forwarding.proto));
+    element
+}
+
+/// Quote `value` if it isn't a valid RFC 7230 token, e.g. a host with a
+/// port.
+fn quote(value: &str) -> String {
+    let token = value
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarding(client: &str, trusted: bool) -> Forwarding<'static> {
+        Forwarding {
+            client: client.parse().unwrap(),
+            host: "foo.com",
+            proto: "https",
+            port: 443,
+            trusted,
+        }
+    }
+
+    fn rewrite(head: &str, forwarding: &Forwarding) -> String {
+        String::from_utf8(rewrite_header(head, forwarding)).unwrap()
+    }
+
+    #[test]
+    fn test_networks() {
+        let trusted =
+            TrustedProxies::parse(&["10.0.0.0/8".to_string(), "2001:db8::1".to_string()]).unwrap();
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!trusted.contains("11.0.0.1".parse().unwrap()));
+        assert!(trusted.contains("2001:db8::1".parse().unwrap()));
+        assert!(!trusted.contains("2001:db8::2".parse().unwrap()));
+
+        let everything = TrustedProxies::parse(&["0.0.0.0/0".to_string()]).unwrap();
+        assert!(everything.contains("192.168.1.1".parse().unwrap()));
+        assert!(!TrustedProxies::default().contains("127.0.0.1".parse().unwrap()));
+
+        assert!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(TrustedProxies::parse(&["foo".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_untrusted() {
+        let head = "GET / HTTP/1.1\r\nHost: foo.com\r\nX-Forwarded-For: 6.6.6.6\r\n\
+                    x-real-ip: 6.6.6.6\r\nAccept: */*\r\n\r\n";
+        assert_eq!(
+            rewrite(head, &forwarding("1.2.3.4", false)),
+            "GET / HTTP/1.1\r\nHost: foo.com\r\nAccept: */*\r\n\
+             X-Forwarded-For: 1.2.3.4\r\nX-Forwarded-Host: foo.com\r\n\
+             X-Forwarded-Proto: https\r\nX-Forwarded-Port: 443\r\n\
+             X-Real-IP: 1.2.3.4\r\nForwarded: for=1.2.3.4;host=foo.com;proto=https\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_trusted() {
+        let head = "GET / HTTP/1.1\r\nHost: foo.com\r\nX-Forwarded-For: 5.5.5.5\r\n\
+                    X-Forwarded-For: 6.6.6.6\r\nX-Forwarded-Proto: http\r\n\
+                    Forwarded: for=5.5.5.5\r\n\r\n";
+        let rewritten = rewrite(head, &forwarding("2001:db8::1", true));
+        assert!(rewritten.contains("X-Forwarded-For: 5.5.5.5, 6.6.6.6, 2001:db8::1\r\n"));
+        assert!(rewritten.contains("X-Forwarded-Proto: http\r\n"));
+        assert!(rewritten.contains("X-Forwarded-Port: 443\r\n"));
+        assert!(rewritten.contains("X-Real-IP: 2001:db8::1\r\n"));
+        assert!(rewritten.contains(
+            "Forwarded: for=5.5.5.5, for=\"[2001:db8::1]\";host=foo.com;proto=https\r\n"
+        ));
+        assert!(rewritten.ends_with("\r\n\r\n"));
+        assert_eq!(rewritten.matches("X-Forwarded-For").count(), 1);
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("foo.com"), "foo.com");
+        assert_eq!(quote("foo.com:8080"), "\"foo.com:8080\"");
+    }
+}
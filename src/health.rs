@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use reqwest::{Client, RedirectPolicy};
+
+/// An HTTP health check that robby runs against a backend itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    // The path to request. Checks with an empty path are disabled.
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    // The statuses that count as passing.
+    pub status: RangeInclusive<u16>,
+}
+
+impl Default for HealthCheck {
+    fn default() -> HealthCheck {
+        HealthCheck {
+            path: String::new(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            status: 200..=399,
+        }
+    }
+}
+
+/// Parse a status like `200` or a range like `200-299`.
+pub fn parse_status(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |s: &str| {
+        s.parse::<u16>()
+            .map_err(|e| format!("Bad status {:?}: {}", s, e))
+    };
+    match s.split_once('-') {
+        Some((low, high)) => Ok(parse(low)?..=parse(high)?),
+        None => {
+            let status = parse(s)?;
+            Ok(status..=status)
+        }
+    }
+}
+
+impl HealthCheck {
+    /// The check for a route with the given tag options, starting from
+    /// `self`. `health=` sets the path, `health_interval=` and
+    /// `health_timeout=` are in seconds, and `health_status=` takes a status
+    /// or a range of them. Returns None if the check has no path.
+    pub fn with_options(
+        &self,
+        options: &HashMap<String, String>,
+    ) -> Result<Option<HealthCheck>, String> {
+        let mut check = self.clone();
+        if let Some(path) = options.get("health") {
+            check.path = path.clone();
+        }
+        let seconds = |key: &str, value: &str| {
+            value
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|e| format!("Bad {} {:?}: {}", key, value, e))
+        };
+        if let Some(interval) = options.get("health_interval") {
+            check.interval = seconds("health_interval", interval)?;
+        }
+        if let Some(timeout) = options.get("health_timeout") {
+            check.timeout = seconds("health_timeout", timeout)?;
+        }
+        if let Some(status) = options.get("health_status") {
+            check.status = parse_status(status)?;
+        }
+        if check.path.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(check))
+        }
+    }
+
+    /// Request the check's path from the backend at `addr`, with a client
+    /// from `clients`.
+    pub fn probe(&self, clients: &Clients, addr: SocketAddr) -> Result<(), String> {
+        let client = clients.get(self.timeout)?;
+        let url = format!("http://{}{}", addr, self.path);
+        let response = client
+            .get(&url)
+            .send()
+            .map_err(|e| format!("Request to {} failed: {}", url, e))?;
+        let status = response.status().as_u16();
+        if self.status.contains(&status) {
+            Ok(())
+        } else {
+            Err(format!("{} answered {}", url, status))
+        }
+    }
+}
+
+/// The HTTP clients that health checks are sent with, one for each check
+/// timeout. Each client has threads of its own, so they're built once and
+/// shared rather than built for every check.
+#[derive(Default)]
+pub struct Clients {
+    clients: Mutex<HashMap<Duration, Client>>,
+}
+
+impl Clients {
+    fn get(&self, timeout: Duration) -> Result<Client, String> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&timeout) {
+            return Ok(client.clone());
+        }
+        let client = Client::builder()
+            .timeout(timeout)
+            .redirect(RedirectPolicy::none())
+            .build()
+            .map_err(|e| format!("Failed to build health check client: {}", e))?;
+        clients.insert(timeout, client.clone());
+        Ok(client)
+    }
+}
+
+/// The health check history of one backend.
+#[derive(Debug, Default)]
+pub struct HealthState {
+    // The outcome of the most recent check, with the reason it failed.
+    result: Mutex<Option<Result<(), String>>>,
+    next_check: Mutex<Option<Instant>>,
+}
+
+impl HealthState {
+    /// Whether the backend may receive traffic. Backends that haven't been
+    /// checked yet do, so that a restart doesn't take every route down.
+    pub fn is_passing(&self) -> bool {
+        self.passing().unwrap_or(true)
+    }
+
+    /// Whether the last check passed, or None if there hasn't been one.
+    pub fn passing(&self) -> Option<bool> {
+        self.result
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|result| result.is_ok())
+    }
+
+    /// The outcome of the last check, or None if there hasn't been one.
+    pub fn last_result(&self) -> Option<Result<(), String>> {
+        self.result.lock().unwrap().clone()
+    }
+
+    /// Returns true and schedules the next check if a check is due.
+    pub fn start_check(&self, interval: Duration) -> bool {
+        let now = Instant::now();
+        let mut next_check = self.next_check.lock().unwrap();
+        if next_check.is_some_and(|next| now < next) {
+            return false;
+        }
+        *next_check = Some(now + interval);
+        true
+    }
+
+    /// Record the outcome of a check. Returns whether the previous check
+    /// passed, or None if this was the first.
+    pub fn record(&self, outcome: Result<(), String>) -> Option<bool> {
+        let previous = self.result.lock().unwrap().replace(outcome);
+        previous.map(|previous| previous.is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    fn options(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_with_options() {
+        let defaults = HealthCheck::default();
+        assert_eq!(defaults.with_options(&options(&[])), Ok(None));
+
+        let check = defaults
+            .with_options(&options(&[
+                ("health", "/healthz"),
+                ("health_interval", "5"),
+                ("health_status", "200-299"),
+            ]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(check.path, "/healthz");
+        assert_eq!(check.interval, Duration::from_secs(5));
+        assert_eq!(check.timeout, defaults.timeout);
+        assert_eq!(check.status, 200..=299);
+
+        let with_path = HealthCheck {
+            path: "/ping".to_string(),
+            ..HealthCheck::default()
+        };
+        let check = with_path
+            .with_options(&options(&[("health_status", "204")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(check.path, "/ping");
+        assert_eq!(check.status, 204..=204);
+
+        assert!(defaults
+            .with_options(&options(&[("health", "/"), ("health_timeout", "soon")]))
+            .is_err());
+        assert!(parse_status("2xx").is_err());
+    }
+
+    #[test]
+    fn test_probe() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = rouille::Server::new(format!("127.0.0.1:{}", port), |request| {
+            match request.url().as_str() {
+                "/healthz" => rouille::Response::text("ok"),
+                _ => rouille::Response::empty_404(),
+            }
+        })
+        .unwrap();
+        thread::spawn(move || server.run());
+        let addr = format!("127.0.0.1:{}", port).parse().unwrap();
+
+        let check = |path: &str| HealthCheck {
+            path: path.to_string(),
+            ..HealthCheck::default()
+        };
+        let clients = Clients::default();
+        assert!(check("/healthz").probe(&clients, addr).is_ok());
+        assert!(check("/missing").probe(&clients, addr).is_err());
+        // Checks with the same timeout share a client.
+        assert_eq!(clients.clients.lock().unwrap().len(), 1);
+
+        let state = HealthState::default();
+        assert!(state.is_passing());
+        assert!(state.start_check(Duration::from_secs(60)));
+        assert!(!state.start_check(Duration::from_secs(60)));
+        state.record(check("/missing").probe(&clients, addr));
+        assert!(!state.is_passing());
+        assert!(state.last_result().unwrap().unwrap_err().contains("404"));
+        assert_eq!(state.record(Ok(())), Some(false));
+        assert!(state.is_passing());
+    }
+}
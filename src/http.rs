@@ -0,0 +1,247 @@
+use httparse::{Header, Request, Response, Status, EMPTY_HEADER};
+
+const MAX_HEADERS: usize = 100;
+
+/// How the end of a message body is found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyLength {
+    Length(u64),
+    Chunked,
+    // Only responses can be delimited by the server closing the connection.
+    UntilClose,
+}
+
+/// The parts of a request header that decide how robby forwards it.
+#[derive(Debug)]
+pub struct RequestInfo {
+    pub method: String,
+    pub body: BodyLength,
+    pub keep_alive: bool,
+    // Upgrade and CONNECT requests turn the connection into a tunnel.
+    pub upgrade: bool,
+    pub expect_continue: bool,
+}
+
+#[derive(Debug)]
+pub struct ResponseInfo {
+    pub status: u16,
+    pub body: BodyLength,
+    pub keep_alive: bool,
+}
+
+fn header_values<'a>(headers: &'a [Header], name: &'a str) -> impl Iterator<Item = &'a str> {
+    headers
+        .iter()
+        .filter(move |h| h.name.eq_ignore_ascii_case(name))
+        .filter_map(|h| std::str::from_utf8(h.value).ok())
+}
+
+/// Whether a comma separated header such as Connection contains `token`.
+fn has_token(headers: &[Header], name: &str, token: &str) -> bool {
+    header_values(headers, name)
+        .flat_map(|value| value.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn keep_alive(version: u8, headers: &[Header]) -> bool {
+    if version == 0 {
+        has_token(headers, "Connection", "keep-alive")
+    } else {
+        !has_token(headers, "Connection", "close")
+    }
+}
+
+/// Finds the body length from Transfer-Encoding and Content-Length, or
+/// None if neither is present. Transfer-Encoding wins when both are sent,
+/// which only responses may do.
+fn body_length(headers: &[Header]) -> Result<Option<BodyLength>, String> {
+    let encodings: Vec<&str> = header_values(headers, "Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(|t| t.trim())
+        .collect();
+    if let Some(last) = encodings.last() {
+        if last.eq_ignore_ascii_case("chunked") {
+            return Ok(Some(BodyLength::Chunked));
+        }
+        return Err(format!("Unsupported Transfer-Encoding {:?}", last));
+    }
+
+    let mut length = None;
+    for value in header_values(headers, "Content-Length").flat_map(|v| v.split(',')) {
+        let value: u64 = value
+            .trim()
+            .parse()
+            .map_err(|e| format!("Bad Content-Length {:?}: {}", value, e))?;
+        if length.is_some() && length != Some(value) {
+            return Err("Conflicting Content-Length headers".to_string());
+        }
+        length = Some(value);
+    }
+    Ok(length.map(BodyLength::Length))
+}
+
+pub fn parse_request(head: &[u8]) -> Result<RequestInfo, String> {
+    let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+    let mut request = Request::new(&mut headers);
+    match request.parse(head) {
+        Ok(Status::Complete(_)) => (),
+        Ok(Status::Partial) => return Err("Incomplete request header".to_string()),
+        Err(e) => return Err(format!("Failed to parse request header: {}", e)),
+    }
+    let method = request.method.unwrap_or("").to_string();
+    let version = request.version.unwrap_or(1);
+    let headers = request.headers;
+
+    // A backend that went by Content-Length would find a different end of
+    // the body than robby does, so requests with both are refused rather
+    // than smuggled through.
+    if header_values(headers, "Transfer-Encoding").next().is_some()
+        && header_values(headers, "Content-Length").next().is_some()
+    {
+        return Err("Request has both Transfer-Encoding and Content-Length".to_string());
+    }
+    let body = match body_length(headers)? {
+        Some(BodyLength::Chunked) if version == 0 => {
+            return Err("Chunked request body in HTTP/1.0".to_string())
+        }
+        Some(body) => body,
+        None => BodyLength::Length(0),
+    };
+    let upgrade = method == "CONNECT"
+        || (has_token(headers, "Connection", "upgrade")
+            && header_values(headers, "Upgrade").next().is_some());
+    Ok(RequestInfo {
+        body,
+        keep_alive: keep_alive(version, headers),
+        upgrade,
+        expect_continue: has_token(headers, "Expect", "100-continue"),
+        method,
+    })
+}
+
+/// Parse a response header. The request method matters because responses
+/// to HEAD never have a body, whatever their headers say.
+pub fn parse_response(head: &[u8], method: &str) -> Result<ResponseInfo, String> {
+    let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+    let mut response = Response::new(&mut headers);
+    match response.parse(head) {
+        Ok(Status::Complete(_)) => (),
+        Ok(Status::Partial) => return Err("Incomplete response header".to_string()),
+        Err(e) => return Err(format!("Failed to parse response header: {}", e)),
+    }
+    let status = response.code.unwrap_or(0);
+    let version = response.version.unwrap_or(1);
+    let headers = response.headers;
+
+    let body = if method == "HEAD" || status < 200 || status == 204 || status == 304 {
+        BodyLength::Length(0)
+    } else {
+        body_length(headers)?.unwrap_or(BodyLength::UntilClose)
+    };
+    Ok(ResponseInfo {
+        status,
+        body,
+        keep_alive: body != BodyLength::UntilClose && keep_alive(version, headers),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request = parse_request(b"GET / HTTP/1.1\r\nHost: foo.com\r\n\r\n").unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.body, BodyLength::Length(0));
+        assert!(request.keep_alive);
+        assert!(!request.upgrade);
+        assert!(!request.expect_continue);
+
+        let request = parse_request(
+            b"POST /upload HTTP/1.1\r\nHost: foo.com\r\nContent-Length: 12\r\n\
+              Connection: close\r\nExpect: 100-continue\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.body, BodyLength::Length(12));
+        assert!(!request.keep_alive);
+        assert!(request.expect_continue);
+
+        let request =
+            parse_request(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").unwrap();
+        assert_eq!(request.body, BodyLength::Chunked);
+
+        let request = parse_request(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap();
+        assert!(request.keep_alive);
+        let request = parse_request(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        assert!(!request.keep_alive);
+
+        let request = parse_request(
+            b"GET /ws HTTP/1.1\r\nConnection: keep-alive, Upgrade\r\nUpgrade: websocket\r\n\r\n",
+        )
+        .unwrap();
+        assert!(request.upgrade);
+        let request = parse_request(b"CONNECT foo.com:443 HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.upgrade);
+    }
+
+    #[test]
+    fn test_parse_bad_request() {
+        assert!(parse_request(b"").is_err());
+        assert!(parse_request(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_request(b"somegarbage\r\n\r\n").is_err());
+        assert!(parse_request(b"POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n").is_err());
+        assert!(parse_request(
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"
+        )
+        .is_err());
+        assert!(parse_request(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n").is_err());
+        // Transfer-Encoding with Content-Length could be used to smuggle a
+        // request past robby.
+        assert!(parse_request(
+            b"POST / HTTP/1.1\r\nContent-Length: 12\r\nTransfer-Encoding: chunked\r\n\r\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response =
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n", "GET").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, BodyLength::Length(11));
+        assert!(response.keep_alive);
+
+        let response =
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n", "HEAD").unwrap();
+        assert_eq!(response.body, BodyLength::Length(0));
+
+        let response = parse_response(b"HTTP/1.1 304 Not Modified\r\n\r\n", "GET").unwrap();
+        assert_eq!(response.body, BodyLength::Length(0));
+        assert!(response.keep_alive);
+
+        let response = parse_response(b"HTTP/1.1 200 OK\r\n\r\n", "GET").unwrap();
+        assert_eq!(response.body, BodyLength::UntilClose);
+        assert!(!response.keep_alive);
+
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+            "GET",
+        )
+        .unwrap();
+        assert_eq!(response.body, BodyLength::Chunked);
+        assert!(!response.keep_alive);
+
+        // Transfer-Encoding wins over Content-Length in a response.
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 12\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "GET",
+        )
+        .unwrap();
+        assert_eq!(response.body, BodyLength::Chunked);
+
+        let response = parse_response(b"HTTP/1.1 100 Continue\r\n\r\n", "POST").unwrap();
+        assert_eq!(response.status, 100);
+        assert_eq!(response.body, BodyLength::Length(0));
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    sync::{Once, RwLock},
+};
+
+use chrono::Local;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+/// The format of log lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    // `<time> <level> <module>: <message>`
+    Text,
+    // One JSON object per line.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {:?}", s)),
+        }
+    }
+}
+
+/// Which messages to log: a default level and levels for particular
+/// modules, written like `warn,robby::registry=debug`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    default: LevelFilter,
+    // Module paths and their levels, longest first.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Filter, String> {
+        let level = |level: &str| {
+            level
+                .trim()
+                .parse::<LevelFilter>()
+                .map_err(|_| format!("Bad log level {:?}", level))
+        };
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        };
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, module_level)) => filter
+                    .modules
+                    .push((module.trim().to_string(), level(module_level)?)),
+                None => filter.default = level(directive)?,
+            }
+        }
+        filter
+            .modules
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(filter)
+    }
+}
+
+impl Filter {
+    /// The level for messages from the module `target`.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| target == module || target.starts_with(&format!("{}::", module)))
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |max, level| max.max(level))
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    format: LogFormat,
+}
+
+impl Logger {
+    fn format(&self, record: &Record) -> String {
+        let time = Local::now();
+        match self.format {
+            LogFormat::Text => format!(
+                "{} {:5} {}: {}",
+                time.format("%Y-%m-%dT%H:%M:%S%.3f%z"),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Json => json!({
+                "time": time.to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            })
+            .to_string(),
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let line = self.format(record);
+            let _ = writeln!(io::stderr(), "{}", line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+lazy_static! {
+    static ref LOGGER: RwLock<Logger> = RwLock::new(Logger {
+        filter: Filter {
+            default: LevelFilter::Info,
+            modules: Vec::new(),
+        },
+        format: LogFormat::Text,
+    });
+}
+
+/// The logger handed to the log crate. It passes messages on to LOGGER,
+/// which is replaced when the config is reloaded.
+struct Current;
+
+impl Log for Current {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        LOGGER.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        LOGGER.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {}
+}
+
+static CURRENT: Current = Current;
+static INSTALL: Once = Once::new();
+
+/// Send log messages that pass `filter` to stderr in `format`.
+pub fn init(filter: Filter, format: LogFormat) -> Result<(), String> {
+    let mut result = Ok(());
+    INSTALL.call_once(|| {
+        result = log::set_logger(&CURRENT).map_err(|e| format!("Failed to set up logging: {}", e));
+    });
+    result?;
+    set(filter, format);
+    Ok(())
+}
+
+/// Change the filter and format of a logger set up with `init`.
+pub fn set(filter: Filter, format: LogFormat) {
+    let max_level = filter.max_level();
+    *LOGGER.write().unwrap() = Logger { filter, format };
+    log::set_max_level(max_level);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn test_filter() {
+        let filter: Filter = "warn, robby::registry=debug,robby::registry::x=off"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level("robby::proxy"), LevelFilter::Warn);
+        assert_eq!(filter.level("robby::registry"), LevelFilter::Debug);
+        assert_eq!(filter.level("robby::registry::x"), LevelFilter::Off);
+        assert_eq!(filter.level("robby::registry_x"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        let default: Filter = "robby::tls=trace".parse().unwrap();
+        assert_eq!(default.level("robby"), LevelFilter::Info);
+        assert!("loud".parse::<Filter>().is_err());
+        assert!("robby=loud".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn test_format() {
+        let logger = Logger {
+            filter: "info".parse().unwrap(),
+            format: LogFormat::Json,
+        };
+        let format = |logger: &Logger| {
+            logger.format(
+                &Record::builder()
+                    .args(format_args!("Routed {}", "a.com/"))
+                    .level(Level::Info)
+                    .target("robby::proxy")
+                    .build(),
+            )
+        };
+        let json: serde_json::Value = serde_json::from_str(&format(&logger)).unwrap();
+        assert_eq!(json["level"], "INFO");
+        assert_eq!(json["target"], "robby::proxy");
+        assert_eq!(json["message"], "Routed a.com/");
+
+        let logger = Logger {
+            format: LogFormat::Text,
+            ..logger
+        };
+        assert!(format(&logger).ends_with(" INFO  robby::proxy: Routed a.com/"));
+        let debug = Metadata::builder()
+            .level(Level::Debug)
+            .target("robby::proxy")
+            .build();
+        assert!(!logger.enabled(&debug));
+    }
+
+    #[test]
+    fn test_reinit() {
+        init("robby::proxy=debug".parse().unwrap(), LogFormat::Json).unwrap();
+        assert_eq!(log::max_level(), LevelFilter::Debug);
+        assert_eq!(LOGGER.read().unwrap().format, LogFormat::Json);
+        // Tests don't need the noise, so this one leaves logging off.
+        init("off".parse().unwrap(), LogFormat::Text).unwrap();
+        assert_eq!(log::max_level(), LevelFilter::Off);
+        assert!(!log_enabled!(Level::Error));
+    }
+}
@@ -1,79 +1,801 @@
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate log;
+mod access_log;
+mod acme;
+mod admin;
+mod balancer;
+mod cli;
+mod consul;
+mod copy_body;
+mod error_page;
+mod forwarded;
+mod health;
+mod http;
+mod logging;
+mod metrics;
+mod nomad;
+mod outlier;
+mod pool;
+mod provider;
+mod proxy;
+mod proxy_protocol;
+mod read_client_hello;
 mod read_http_header;
 mod registry;
+mod reload;
+mod static_routes;
+mod timeout;
+mod tls;
 
-use std::{error::Error, str::from_utf8, sync::Arc, thread, time::Duration};
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::Duration,
+};
 
-use regex::{Regex, RegexBuilder};
+use futures::sync::oneshot;
+use regex::Regex;
 use tokio::{
-    io::{copy, write_all},
+    io::copy,
     net::{TcpListener, TcpStream},
     prelude::*,
-    runtime::Builder,
+    runtime::{Builder, Runtime, TaskExecutor},
 };
+use tokio_rustls::TlsAcceptor;
 
-use read_http_header::read_http_header;
-use registry::{GetHostError, ServiceRegistry};
+use access_log::AccessLog;
+use acme::{AcmeClient, AcmeManager, Storage};
+use admin::serve_admin;
+use cli::Options;
+use consul::{ConsulClient, ConsulConfig};
+use error_page::ErrorPages;
+use forwarded::TrustedProxies;
+use health::{parse_status, HealthCheck};
+use logging::Filter;
+use nomad::{NomadClient, NomadConfig};
+use outlier::OutlierDetection;
+use pool::Pool;
+use provider::Provider;
+use proxy::{serve_connection, serve_passthrough, ClientInfo, Proxy};
+use proxy_protocol::read_proxy_header;
+use registry::{RegistrySettings, ServiceRegistry};
+use timeout::{with_timeout, Timeouts};
+use tls::CertStore;
 
 #[cfg(test)]
 mod tests;
 
 fn main() {
-    launch().map_err(|e| eprintln!("Error: {}", e)).ok();
+    let matches = cli::app().get_matches();
+    let result = Options::from_matches(&matches)
+        .map_err(|e| e.into())
+        .and_then(launch);
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
 }
 
-fn launch() -> Result<(), Box<dyn Error>> {
-    let conf = get_config();
+fn launch(options: Options) -> Result<(), Box<dyn Error>> {
+    let conf = get_config(&options, std::env::vars())?;
+    if options.check_config {
+        check_config(&conf)?;
+        println!("Config OK");
+        return Ok(());
+    }
+    let (filter, format) = log_settings(&conf)?;
+    logging::init(filter, format)?;
 
-    // Weird syntax for default type parameters. See:
-    // https://github.com/rust-lang/rust/issues/36980#issuecomment-251726254
-    // https://github.com/rust-lang-nursery/reference/issues/24
-    let registry = Arc::new(<ServiceRegistry>::new());
+    let consul_wait = get_secs(&conf, "consul_wait")?;
+    let fallback = get_secs(&conf, "consul_fallback_interval")?;
+    let providers = providers(&conf, consul_wait)?;
+    let addresses: Vec<String> = providers
+        .iter()
+        .map(|(_, provider)| format!("{} running on {}", provider.name(), provider.address()))
+        .collect();
+    let registry = Arc::new(ServiceRegistry::new(providers, registry_settings(&conf)?));
+    let static_routes = conf.get_str("static_routes")?;
+    let static_nodes = if static_routes.is_empty() {
+        Vec::new()
+    } else {
+        static_routes::load(Path::new(&static_routes))?
+    };
+    if !static_routes.is_empty() {
+        registry.set_static_nodes(static_nodes.clone())?;
+    }
     registry
         .update()
-        .map_err(|e| format!("{} is consul running on 127.0.0.1:8500?", e))?;
+        .map_err(|e| format!("{} Is {}?", e, addresses.join(" or ")))?;
+    if options.print_routes {
+        print_routes(&registry)?;
+        return Ok(());
+    }
     let refresh_copy = registry.clone();
-    thread::spawn(move || refresh_service_map(refresh_copy));
-    let bind_address = format!(
-        "{}:{}",
-        conf.get_str("bind_host").unwrap(),
-        conf.get_str("bind_port").unwrap()
-    );
-    run_server(&bind_address, registry).map_err(|e| e.into())
+    thread::spawn(move || ServiceRegistry::watch(refresh_copy, fallback));
+    let health_copy = registry.clone();
+    thread::spawn(move || ServiceRegistry::check_health(health_copy));
+    if !static_routes.is_empty() {
+        let static_copy = registry.clone();
+        thread::spawn(move || {
+            static_routes::watch(static_copy, static_routes.into(), static_nodes)
+        });
+    }
+    let mut proxy = build_proxy(&conf, registry.clone(), None)?;
+    let acceptor = if conf.get_bool("tls_enabled")? {
+        let store = start_tls(&conf, consul_wait, fallback)?;
+        if conf.get_bool("acme_enabled")? {
+            proxy.acme = Some(start_acme(&conf, store.clone(), registry)?);
+        }
+        Some(TlsAcceptor::from(Arc::new(tls::server_config(store))))
+    } else if conf.get_bool("acme_enabled")? {
+        return Err("acme_enabled needs tls_enabled".into());
+    } else {
+        None
+    };
+    let (runtime, server) = start_server(listeners(&conf, &acceptor)?, Arc::new(proxy))?;
+
+    let watcher = reload::Watcher::new(options.config_files())?;
+    thread::spawn(move || {
+        watcher.watch(
+            || match reload_config(&server, &options, &conf, &acceptor) {
+                Ok(()) => info!("Reloaded the config"),
+                Err(e) => error!("Keeping the old config: {}", e),
+            },
+        )
+    });
+    runtime.shutdown_on_idle().wait().unwrap();
+    Ok(())
+}
+
+/// Check everything in `conf` that can be checked without binding listeners
+/// or asking Consul for anything. An access log file is created if it
+/// doesn't exist, to make sure it can be written.
+fn check_config(conf: &config::Config) -> Result<(), Box<dyn Error>> {
+    log_settings(conf)?;
+    for key in &["consul_wait", "consul_fallback_interval", "acme_renew_days"] {
+        get_unsigned(conf, key)?;
+    }
+    let providers = providers(conf, Duration::from_secs(0))?;
+    let registry = Arc::new(ServiceRegistry::new(providers, registry_settings(conf)?));
+    build_proxy(conf, registry, None)?;
+    let listeners = listeners(conf, &None)?;
+    let addresses = std::iter::once(&listeners.http)
+        .chain(&listeners.passthrough)
+        .chain(&listeners.admin);
+    for address in addresses {
+        address
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("Can't parse address {}. {}", address, e))?;
+    }
+    let static_routes = conf.get_str("static_routes")?;
+    if !static_routes.is_empty() {
+        static_routes::load(Path::new(&static_routes))?;
+    }
+    if conf.get_bool("tls_enabled")? {
+        let cert_dir = conf.get_str("tls_cert_dir")?;
+        if !cert_dir.is_empty() {
+            tls::load_dir(Path::new(&cert_dir))?;
+        }
+    } else if conf.get_bool("acme_enabled")? {
+        return Err("acme_enabled needs tls_enabled".into());
+    }
+    Ok(())
+}
+
+/// Print every route and its backends, one backend per line.
+fn print_routes<T: registry::ServiceProvider>(
+    registry: &ServiceRegistry<T>,
+) -> Result<(), Box<dyn Error>> {
+    for route in registry.routes()? {
+        println!("{}{} {}", route.host, route.path, route.policy);
+        for backend in route.backends {
+            println!(
+                "    {} {} weight={} source={}",
+                backend.addr,
+                format!("{:?}", backend.status).to_lowercase(),
+                backend.weight,
+                backend.source
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The named sources of routes: every entry of `providers`, or just the
+/// one `provider` selects if that's empty. Blocking queries wait up to
+/// `wait` for changes.
+fn providers(
+    conf: &config::Config,
+    wait: Duration,
+) -> Result<Vec<(String, Provider)>, Box<dyn Error>> {
+    let entries: Vec<HashMap<String, String>> = conf.get("providers")?;
+    if entries.is_empty() {
+        let kind = conf.get_str("provider")?;
+        let provider = provider(conf, &kind, &HashMap::new(), wait)?;
+        return Ok(vec![(kind, provider)]);
+    }
+    let mut providers: Vec<(String, Provider)> = Vec::new();
+    for mut entry in entries {
+        let kind = entry
+            .remove("type")
+            .ok_or("Every entry of providers needs a type")?;
+        let name = entry.remove("name").unwrap_or_else(|| kind.clone());
+        if name == registry::STATIC_SOURCE || providers.iter().any(|(other, _)| *other == name) {
+            return Err(format!("The provider name {:?} is taken, give it another", name).into());
+        }
+        let provider =
+            provider(conf, &kind, &entry, wait).map_err(|e| format!("Provider {}: {}", name, e))?;
+        providers.push((name, provider));
+    }
+    Ok(providers)
+}
+
+// The settings an entry of `providers` can override, without their prefix.
+const CONSUL_KEYS: &[&str] = &[
+    "address",
+    "token",
+    "ca_file",
+    "cert_file",
+    "key_file",
+    "datacenter",
+    "namespace",
+    "partition",
+];
+const NOMAD_KEYS: &[&str] = &[
+    "address",
+    "token",
+    "namespace",
+    "region",
+    "ca_file",
+    "cert_file",
+    "key_file",
+];
+
+/// A service discovery backend of type `kind`, configured by the
+/// `consul_*` or `nomad_*` settings with `overrides` taking precedence.
+/// Overrides are keyed without the prefix, e.g. `datacenter`.
+fn provider(
+    conf: &config::Config,
+    kind: &str,
+    overrides: &HashMap<String, String>,
+    wait: Duration,
+) -> Result<Provider, Box<dyn Error>> {
+    let keys = match kind {
+        "consul" => CONSUL_KEYS,
+        "nomad" => NOMAD_KEYS,
+        other => return Err(format!("Unknown provider {:?}", other).into()),
+    };
+    if let Some(key) = overrides.keys().find(|key| !keys.contains(&key.as_str())) {
+        return Err(format!("Unknown {} setting {:?}", kind, key).into());
+    }
+    match kind {
+        "consul" => Ok(Provider::Consul(ConsulClient::new(
+            &consul_config(conf, overrides)?,
+            wait,
+        )?)),
+        _ => {
+            let get = |key: &str| setting(conf, "nomad", key, overrides);
+            let config = NomadConfig {
+                address: get("address")?,
+                token: get("token")?,
+                namespace: get("namespace")?,
+                region: get("region")?,
+                ca_file: get("ca_file")?,
+                cert_file: get("cert_file")?,
+                key_file: get("key_file")?,
+            };
+            Ok(Provider::Nomad(NomadClient::new(&config, wait)?))
+        }
+    }
+}
+
+/// The `<prefix>_<key>` setting, unless `overrides` has `key`.
+fn setting(
+    conf: &config::Config,
+    prefix: &str,
+    key: &str,
+    overrides: &HashMap<String, String>,
+) -> Result<String, config::ConfigError> {
+    match overrides.get(key) {
+        Some(value) => Ok(value.clone()),
+        None => conf.get_str(&format!("{}_{}", prefix, key)),
+    }
+}
+
+fn consul_config(
+    conf: &config::Config,
+    overrides: &HashMap<String, String>,
+) -> Result<ConsulConfig, Box<dyn Error>> {
+    let get = |key: &str| setting(conf, "consul", key, overrides);
+    Ok(ConsulConfig {
+        address: get("address")?,
+        token: get("token")?,
+        ca_file: get("ca_file")?,
+        cert_file: get("cert_file")?,
+        key_file: get("key_file")?,
+        datacenter: get("datacenter")?,
+        namespace: get("namespace")?,
+        partition: get("partition")?,
+    })
+}
+
+/// The log filter and format. ROBBY_LOG takes precedence over log_level, so
+/// that a single run can be made chattier without editing the config.
+fn log_settings(conf: &config::Config) -> Result<(Filter, logging::LogFormat), Box<dyn Error>> {
+    let filter = match std::env::var("ROBBY_LOG") {
+        Ok(filter) => filter,
+        Err(_) => conf.get_str("log_level")?,
+    };
+    Ok((filter.parse()?, conf.get_str("log_format")?.parse()?))
+}
+
+/// The number `key` is set to, which can't be negative.
+fn get_unsigned(conf: &config::Config, key: &str) -> Result<u64, Box<dyn Error>> {
+    let value = conf.get_int(key)?;
+    if value < 0 {
+        return Err(format!("{} can't be negative", key).into());
+    }
+    Ok(value as u64)
+}
+
+/// The duration `key` is set to, in seconds.
+fn get_secs(conf: &config::Config, key: &str) -> Result<Duration, Box<dyn Error>> {
+    Ok(Duration::from_secs(get_unsigned(conf, key)?))
+}
+
+fn registry_settings(conf: &config::Config) -> Result<RegistrySettings, Box<dyn Error>> {
+    let secs = |key: &str| get_secs(conf, key);
+    Ok(RegistrySettings {
+        include_warning: conf.get_bool("include_warning")?,
+        policy: conf.get_str("load_balancing")?.parse()?,
+        outlier: OutlierDetection {
+            consecutive_failures: conf.get_int("outlier_consecutive_failures")? as u32,
+            base_ejection: secs("outlier_base_ejection_secs")?,
+            max_ejection: secs("outlier_max_ejection_secs")?,
+            max_ejection_percent: conf.get_int("outlier_max_ejection_percent")? as usize,
+        },
+        health_check: HealthCheck {
+            path: conf.get_str("health_check_path")?,
+            interval: secs("health_check_interval_secs")?,
+            timeout: secs("health_check_timeout_secs")?,
+            status: parse_status(&conf.get_str("health_check_status")?)?,
+        },
+    })
 }
 
-fn refresh_service_map(registry: Arc<ServiceRegistry>) {
-    loop {
-        thread::sleep(Duration::from_secs(10));
-        let result = registry.update();
-        if let Err(e) = result {
-            eprintln!("Failed to update service map: {:?}", e);
+/// A proxy for `registry` configured by `conf`. When the config is reloaded,
+/// the `previous` proxy's metrics, ACME storage and, if its size is the
+/// same, connection pool are carried over.
+fn build_proxy<T>(
+    conf: &config::Config,
+    registry: Arc<ServiceRegistry<T>>,
+    previous: Option<&Proxy<T>>,
+) -> Result<Proxy<T>, Box<dyn Error>>
+where
+    T: registry::ServiceProvider,
+{
+    let max_idle = conf.get_int("max_idle_connections")? as usize;
+    let idle_timeout = get_secs(conf, "pool_idle_timeout_secs")?;
+    let mut proxy = Proxy::new(registry, max_idle);
+    proxy.pool = Arc::new(Pool::new(max_idle, idle_timeout));
+    if let Some(previous) = previous {
+        if previous.pool.max_idle() == max_idle && previous.pool.idle_timeout() == idle_timeout {
+            proxy.pool = previous.pool.clone();
         }
+        proxy.metrics = previous.metrics.clone();
+        proxy.acme = previous.acme.clone();
+    }
+    proxy.trusted_proxies = TrustedProxies::parse(&get_list(conf, "trusted_proxies")?)?;
+    let error_format = conf.get_str("error_format")?.parse()?;
+    let error_template_dir = conf.get_str("error_template_dir")?;
+    proxy.error_pages = if error_template_dir.is_empty() {
+        ErrorPages::new(error_format)
+    } else {
+        ErrorPages::load(error_format, Path::new(&error_template_dir))?
+    };
+    let retries = conf.get_int("retries")?;
+    if retries < 0 || retries > i64::from(proxy::MAX_RETRIES) {
+        return Err(format!("retries must be from 0 to {}", proxy::MAX_RETRIES).into());
     }
+    proxy.retries = retries as u32;
+    proxy.retry_backoff = Duration::from_millis(get_unsigned(conf, "retry_backoff_ms")?);
+    proxy.mark_down = get_secs(conf, "mark_down_secs")?;
+    let secs = |key: &str| get_secs(conf, key);
+    proxy.timeouts = Timeouts {
+        header_read: secs("client_header_timeout_secs")?,
+        connect: secs("connect_timeout_secs")?,
+        first_byte: secs("response_timeout_secs")?,
+        idle: secs("idle_timeout_secs")?,
+        lifetime: secs("max_connection_lifetime_secs")?,
+    };
+    let access_log = conf.get_str("access_log")?;
+    let access_log_format = conf.get_str("access_log_format")?.parse()?;
+    proxy.access_log = match access_log.as_str() {
+        "" => None,
+        "stdout" => Some(AccessLog::stdout(access_log_format)),
+        path => Some(AccessLog::file(access_log_format, Path::new(path))?),
+    };
+    Ok(proxy)
 }
 
-fn get_config() -> config::Config {
+/// The listeners `conf` asks for. The TLS listener uses `acceptor`, which is
+/// set up once at startup.
+fn listeners(
+    conf: &config::Config,
+    acceptor: &Option<TlsAcceptor>,
+) -> Result<Listeners, Box<dyn Error>> {
+    let bind_host = conf.get_str("bind_host")?;
+    let mut listeners = Listeners {
+        http: format!("{}:{}", bind_host, conf.get_str("bind_port")?),
+        proxy_protocol: conf.get_bool("proxy_protocol")?,
+        ..Listeners::default()
+    };
+    if let Some(acceptor) = acceptor {
+        let tls_address = format!("{}:{}", bind_host, conf.get_str("tls_bind_port")?);
+        listeners.tls = Some((tls_address, acceptor.clone()));
+    }
+    if conf.get_bool("passthrough_enabled")? {
+        let port = conf.get_str("passthrough_bind_port")?;
+        listeners.passthrough = Some(format!("{}:{}", bind_host, port));
+    }
+    if conf.get_bool("admin_enabled")? {
+        let host = conf.get_str("admin_bind_host")?;
+        let port = conf.get_str("admin_bind_port")?;
+        listeners.admin = Some(format!("{}:{}", host, port));
+    }
+    Ok(listeners)
+}
+
+// Settings that are only read at startup.
+const RESTART_KEYS: &[&str] = &[
+    "provider",
+    "providers",
+    "nomad_address",
+    "nomad_token",
+    "nomad_namespace",
+    "nomad_region",
+    "nomad_ca_file",
+    "nomad_cert_file",
+    "nomad_key_file",
+    "consul_address",
+    "consul_token",
+    "consul_ca_file",
+    "consul_cert_file",
+    "consul_key_file",
+    "consul_datacenter",
+    "consul_namespace",
+    "consul_partition",
+    "consul_wait",
+    "consul_fallback_interval",
+    "static_routes",
+    "tls_enabled",
+    "tls_cert_dir",
+    "tls_consul_prefix",
+    "acme_enabled",
+    "acme_directory",
+    "acme_email",
+    "acme_ca_file",
+    "acme_renew_days",
+    "acme_dir",
+    "acme_consul_prefix",
+];
+
+/// Load the config file again and apply it to `server`. Everything is
+/// checked, and new listener addresses are bound, before anything changes,
+/// so a bad config leaves robby as it was. Connections that are already
+/// open carry on with the old settings. `started` is the config robby
+/// started with, to warn about settings that need a restart.
+fn reload_config<T>(
+    server: &Arc<Server<T>>,
+    options: &Options,
+    started: &config::Config,
+    acceptor: &Option<TlsAcceptor>,
+) -> Result<(), Box<dyn Error>>
+where
+    T: 'static + registry::ServiceProvider + Send + Sync,
+{
+    let conf = get_config(options, std::env::vars())?;
+    let (filter, format) = log_settings(&conf)?;
+    let settings = registry_settings(&conf)?;
+    let current = server.proxy();
+    let proxy = build_proxy(&conf, current.registry.clone(), Some(&current))?;
+    let bound = server.bind(listeners(&conf, acceptor)?)?;
+    // This is the last step that can fail, and it changes nothing if it
+    // does.
+    current.registry.reconfigure(settings)?;
+
+    logging::set(filter, format);
+    server.set_proxy(Arc::new(proxy));
+    server.start(bound);
+    for key in restart_changes(&conf, started) {
+        warn!("{} changed, which takes effect when robby restarts", key);
+    }
+    Ok(())
+}
+
+/// The RESTART_KEYS that are set differently in `conf` than in `started`.
+/// Values are compared whole, so lists such as `providers` count too.
+fn restart_changes(conf: &config::Config, started: &config::Config) -> Vec<&'static str> {
+    RESTART_KEYS
+        .iter()
+        .filter(|key| conf.get::<config::Value>(key).ok() != started.get::<config::Value>(key).ok())
+        .cloned()
+        .collect()
+}
+
+/// Load certificates from the configured sources into a CertStore for the
+/// TLS listener. Certificates in Consul KV are watched for changes.
+fn start_tls(
+    conf: &config::Config,
+    consul_wait: Duration,
+    fallback: Duration,
+) -> Result<Arc<CertStore>, Box<dyn Error>> {
+    let store = Arc::new(CertStore::new());
+    let cert_dir = conf.get_str("tls_cert_dir")?;
+    if !cert_dir.is_empty() {
+        store.set_source("dir", tls::load_dir(Path::new(&cert_dir))?);
+    }
+    let prefix = conf.get_str("tls_consul_prefix")?;
+    if !prefix.is_empty() {
+        let client = ConsulClient::new(&consul_config(conf, &HashMap::new())?, consul_wait)?;
+        let watch_store = store.clone();
+        thread::spawn(move || tls::watch_consul(watch_store, client, &prefix, fallback));
+    }
+    Ok(store)
+}
+
+/// Start ordering certificates for every routed host into `store`. Returns
+/// the shared ACME storage, which the proxy answers challenges from.
+fn start_acme<T>(
+    conf: &config::Config,
+    store: Arc<CertStore>,
+    registry: Arc<ServiceRegistry<T>>,
+) -> Result<Arc<Storage>, Box<dyn Error>>
+where
+    T: 'static + registry::ServiceProvider + Send + Sync,
+{
+    let prefix = conf.get_str("acme_consul_prefix")?;
+    let storage = Arc::new(if prefix.is_empty() {
+        Storage::Dir(conf.get_str("acme_dir")?.into())
+    } else {
+        // Storage requests are quick, so they don't need a long timeout.
+        let client = ConsulClient::new(
+            &consul_config(conf, &HashMap::new())?,
+            Duration::from_secs(10),
+        )?;
+        Storage::Consul {
+            client,
+            prefix: prefix.trim_matches('/').to_string(),
+        }
+    });
+    let ca_file = conf.get_str("acme_ca_file")?;
+    let ca_file = if ca_file.is_empty() {
+        None
+    } else {
+        Some(Path::new(&ca_file))
+    };
+    let client = AcmeClient::new(
+        &conf.get_str("acme_directory")?,
+        &conf.get_str("acme_email")?,
+        ca_file,
+        &AcmeManager::account_key(&storage)?,
+    )?;
+    let manager = AcmeManager::new(
+        client,
+        storage.clone(),
+        store,
+        Duration::from_secs(get_unsigned(conf, "acme_renew_days")?.saturating_mul(86400)),
+    );
+    thread::spawn(move || manager.run(registry));
+    Ok(storage)
+}
+
+/// The defaults, overridden by the config file if there is one, then by
+/// ROBBY_<KEY> variables in `env`, the environment as name and value pairs,
+/// then by the command line. A file that can't be read or parsed is an
+/// error.
+fn get_config<E>(options: &Options, env: E) -> Result<config::Config, String>
+where
+    E: IntoIterator<Item = (String, String)>,
+{
     let mut conf = config::Config::default();
     conf.set_default("bind_host", "0.0.0.0")
         .unwrap()
         .set_default("bind_port", 9001)
+        .unwrap()
+        .set_default("provider", "consul")
+        .unwrap()
+        .set_default("nomad_address", "http://127.0.0.1:4646")
+        .unwrap()
+        .set_default("nomad_token", "")
+        .unwrap()
+        .set_default("nomad_namespace", "default")
+        .unwrap()
+        .set_default("nomad_region", "")
+        .unwrap()
+        .set_default("nomad_ca_file", "")
+        .unwrap()
+        .set_default("nomad_cert_file", "")
+        .unwrap()
+        .set_default("nomad_key_file", "")
+        .unwrap()
+        .set_default("consul_address", "http://127.0.0.1:8500")
+        .unwrap()
+        .set_default("consul_token", "")
+        .unwrap()
+        .set_default("consul_ca_file", "")
+        .unwrap()
+        .set_default("consul_cert_file", "")
+        .unwrap()
+        .set_default("consul_key_file", "")
+        .unwrap()
+        .set_default("consul_datacenter", "")
+        .unwrap()
+        .set_default("consul_namespace", "")
+        .unwrap()
+        .set_default("consul_partition", "")
+        .unwrap()
+        .set_default("consul_wait", 300)
+        .unwrap()
+        .set_default("consul_fallback_interval", 10)
+        .unwrap()
+        .set_default("static_routes", "")
+        .unwrap()
+        .set_default("providers", Vec::<String>::new())
+        .unwrap()
+        .set_default("include_warning", false)
+        .unwrap()
+        .set_default("load_balancing", "random")
+        .unwrap()
+        .set_default("max_idle_connections", 16)
+        .unwrap()
+        .set_default("pool_idle_timeout_secs", 60)
+        .unwrap()
+        .set_default("trusted_proxies", Vec::<String>::new())
+        .unwrap()
+        .set_default("proxy_protocol", false)
+        .unwrap()
+        .set_default("error_format", "html")
+        .unwrap()
+        .set_default("error_template_dir", "")
+        .unwrap()
+        .set_default("retries", 2)
+        .unwrap()
+        .set_default("retry_backoff_ms", 50)
+        .unwrap()
+        .set_default("mark_down_secs", 10)
+        .unwrap()
+        .set_default("outlier_consecutive_failures", 5)
+        .unwrap()
+        .set_default("outlier_base_ejection_secs", 30)
+        .unwrap()
+        .set_default("outlier_max_ejection_secs", 300)
+        .unwrap()
+        .set_default("outlier_max_ejection_percent", 50)
+        .unwrap()
+        .set_default("health_check_path", "")
+        .unwrap()
+        .set_default("health_check_interval_secs", 10)
+        .unwrap()
+        .set_default("health_check_timeout_secs", 2)
+        .unwrap()
+        .set_default("health_check_status", "200-399")
+        .unwrap()
+        .set_default("client_header_timeout_secs", 30)
+        .unwrap()
+        .set_default("connect_timeout_secs", 5)
+        .unwrap()
+        .set_default("response_timeout_secs", 60)
+        .unwrap()
+        .set_default("idle_timeout_secs", 300)
+        .unwrap()
+        .set_default("max_connection_lifetime_secs", 0)
+        .unwrap()
+        .set_default("log_level", "info")
+        .unwrap()
+        .set_default("log_format", "text")
+        .unwrap()
+        .set_default("access_log", "stdout")
+        .unwrap()
+        .set_default("access_log_format", "common")
+        .unwrap()
+        .set_default("tls_enabled", false)
+        .unwrap()
+        .set_default("tls_bind_port", 9443)
+        .unwrap()
+        .set_default("tls_cert_dir", "")
+        .unwrap()
+        .set_default("tls_consul_prefix", "")
+        .unwrap()
+        .set_default("passthrough_enabled", false)
+        .unwrap()
+        .set_default("passthrough_bind_port", 9444)
+        .unwrap()
+        .set_default("admin_enabled", false)
+        .unwrap()
+        .set_default("admin_bind_host", "127.0.0.1")
+        .unwrap()
+        .set_default("admin_bind_port", 9002)
+        .unwrap()
+        .set_default("acme_enabled", false)
+        .unwrap()
+        .set_default(
+            "acme_directory",
+            "https://acme-v02.api.letsencrypt.org/directory",
+        )
+        .unwrap()
+        .set_default("acme_email", "")
+        .unwrap()
+        .set_default("acme_ca_file", "")
+        .unwrap()
+        .set_default("acme_renew_days", 30)
+        .unwrap()
+        .set_default("acme_dir", "/var/lib/robby/acme")
+        .unwrap()
+        .set_default("acme_consul_prefix", "")
         .unwrap();
 
-    conf.merge(config::File::with_name("/etc/robby"))
-        .map_err(|e| eprintln!("Using default config: {}", e))
-        .ok();
+    match options
+        .config_files()
+        .into_iter()
+        .find(|file| file.exists())
+    {
+        Some(file) => {
+            conf.merge(config::File::from(file.as_path()))
+                .map_err(|e| format!("Failed to load {}: {}", file.display(), e))?;
+        }
+        None if options.config.is_some() => {
+            return Err(format!(
+                "No config file at {}",
+                options.config_files()[0].display()
+            ))
+        }
+        None => eprintln!("Using default config: no /etc/robby config file"),
+    }
+    for (name, value) in env {
+        if let Some(key) = name.strip_prefix("ROBBY_") {
+            conf.set(&key.to_lowercase(), value)
+                .map_err(|e| format!("Failed to read {}: {}", name, e))?;
+        }
+    }
+    for (key, value) in options.overrides() {
+        conf.set(key, value).map_err(|e| e.to_string())?;
+    }
+    Ok(conf)
+}
 
-    conf
+/// A list from the config. Environment variables can't hold lists, so a
+/// string is split on commas instead.
+fn get_list(conf: &config::Config, key: &str) -> Result<Vec<String>, config::ConfigError> {
+    match conf.get_array(key) {
+        Ok(values) => values.into_iter().map(|value| value.into_str()).collect(),
+        Err(_) => Ok(conf
+            .get_str(key)?
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .collect()),
+    }
 }
 
-/// Proxy connection copies bytes back and forth between two TcpStreams.
+/// Proxy connection copies bytes back and forth between two streams.
 /// This returns a future which will resolve when either stream closes the
 /// connection.
-fn proxy_connection(
-    server_stream: TcpStream,
-    client_stream: TcpStream,
-) -> impl Future<Item = (), Error = ()> {
+fn proxy_connection<S, C>(server_stream: S, client_stream: C) -> impl Future<Item = (), Error = ()>
+where
+    S: AsyncRead + AsyncWrite,
+    C: AsyncRead + AsyncWrite,
+{
     let (sreader, swriter) = server_stream.split();
     let (creader, cwriter) = client_stream.split();
 
@@ -82,17 +804,17 @@ fn proxy_connection(
 
     let handle_from_server = from_server_bytes_copied
         .map(|count| {
-            println!("wrote {} bytes from server to client.", count.0);
-            println!("Server closed the connection. Disconnecting from client.");
+            debug!("wrote {} bytes from server to client.", count.0);
+            debug!("Server closed the connection. Disconnecting from client.");
         })
-        .map_err(|err| eprintln!("IO error {:?}", err));
+        .map_err(|err| debug!("IO error {:?}", err));
 
     let handle_from_client = from_client_bytes_copied
         .map(|count| {
-            println!("wrote {} bytes from client to server.", count.0);
-            println!("Client closed the connection. Disconnecting from server.");
+            debug!("wrote {} bytes from client to server.", count.0);
+            debug!("Client closed the connection. Disconnecting from server.");
         })
-        .map_err(|err| eprintln!("IO error {:?}", err));
+        .map_err(|err| debug!("IO error {:?}", err));
 
     // Wait for one to complete then drop the other.
     handle_from_server
@@ -100,22 +822,40 @@ fn proxy_connection(
         .then(|_res| future::ok(()))
 }
 
-fn extract_host(header: &str) -> Result<&str, ()> {
-    lazy_static! {
-        static ref RE: Regex = RegexBuilder::new(r"Host: ([^\r]*)[\r]?")
-            .case_insensitive(true)
-            .build()
-            .unwrap();
-    }
-    let caps = RE.captures(header);
-    if let None = caps {
-        eprintln!("Failed to find 'Host' header.");
-        eprintln!("Header: {}", header);
-        return Err(());
-    }
-    let caps = caps.unwrap();
-    let host = caps.get(1).unwrap().as_str();
-    Ok(host)
+/// Returns the host a request is for: its Host header, lowercased and
+/// without a port.
+fn extract_host(header: &str) -> Result<String, ()> {
+    let host = match extract_header(header, "Host") {
+        Some(host) if !host.is_empty() => host,
+        _ => {
+            debug!("Failed to find 'Host' header.");
+            debug!("Header: {}", header);
+            return Err(());
+        }
+    };
+    let name = if host.starts_with('[') {
+        // An IPv6 address, whose colons aren't a port.
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => return Err(()),
+        }
+    } else {
+        host.split(':').next().unwrap_or("")
+    };
+    Ok(name.to_ascii_lowercase())
+}
+
+/// Returns the value of the first header named `name`, if there is one.
+fn extract_header<'a>(header: &'a str, name: &str) -> Option<&'a str> {
+    header.split("\r\n").skip(1).find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let field = parts.next()?;
+        if field.eq_ignore_ascii_case(name) {
+            Some(parts.next()?.trim())
+        } else {
+            None
+        }
+    })
 }
 
 fn extract_uri(header: &str) -> Result<&str, ()> {
@@ -126,8 +866,8 @@ fn extract_uri(header: &str) -> Result<&str, ()> {
     let reqline = lines.nth(0).unwrap();
     let caps = RE.captures(reqline);
     if let None = caps {
-        eprintln!("Failed to extract request URI.");
-        eprintln!("Header: {}", header);
+        debug!("Failed to extract request URI.");
+        debug!("Header: {}", header);
         return Err(());
     }
     let caps = caps.unwrap();
@@ -135,103 +875,306 @@ fn extract_uri(header: &str) -> Result<&str, ()> {
     Ok(host)
 }
 
-fn run_server<T>(server_address: &str, registry: Arc<ServiceRegistry<T>>) -> Result<(), String>
-where
-    T: 'static + registry::ServiceProvider + Send + Sync,
-{
+fn bind(server_address: &str) -> Result<TcpListener, String> {
     let addr = server_address
         .parse()
         .map_err(|e| format!("Can't parse address {}. {}", server_address, e))?;
-    let listener =
-        TcpListener::bind(&addr).map_err(|e| format!("Failed to bind address {}. {}", addr, e))?;
+    TcpListener::bind(&addr).map_err(|e| format!("Failed to bind address {}. {}", addr, e))
+}
 
-    println!("Robby listening on {}", &server_address);
-    let server = listener
+/// The addresses robby serves on. Only the plain HTTP listener is required.
+#[derive(Default)]
+struct Listeners {
+    http: String,
+    // Terminate TLS on this address with the acceptor's certificates.
+    tls: Option<(String, TlsAcceptor)>,
+    // Pass TLS connections on this address through to the backend chosen by
+    // their SNI server name.
+    passthrough: Option<String>,
+    // Serve metrics and the admin API on this address.
+    admin: Option<String>,
+    // Whether every connection starts with a PROXY header from a load
+    // balancer in front of robby.
+    proxy_protocol: bool,
+}
+
+/// Work out where a client connection came from. If `proxy_protocol` is set,
+/// the connection's PROXY header is read first, within `timeout`, and its
+/// addresses are used.
+fn client_info(
+    client_sock: TcpStream,
+    client_addr: SocketAddr,
+    tls: bool,
+    proxy_protocol: bool,
+    timeout: Duration,
+) -> impl Future<Item = (TcpStream, ClientInfo), Error = ()> {
+    let info = client_sock.local_addr().map(|local| ClientInfo {
+        addr: client_addr,
+        local,
+        tls,
+    });
+    future::result(info)
+        .and_then(move |info| {
+            if !proxy_protocol {
+                return future::Either::A(future::ok((client_sock, info)));
+            }
+            let header = with_timeout(
+                read_proxy_header(client_sock),
+                timeout,
+                "Reading the PROXY header",
+            );
+            future::Either::B(header.map(move |(client_sock, addresses)| {
+                // LOCAL and UNKNOWN headers mean the connection is the load
+                // balancer's own, e.g. a health check.
+                let info = match addresses {
+                    Some((addr, local)) => ClientInfo {
+                        addr,
+                        local,
+                        ..info
+                    },
+                    None => info,
+                };
+                (client_sock, info)
+            }))
+        })
+        .map_err(move |e| warn!("Failed to read PROXY header from {}: {}", client_addr, e))
+}
+
+/// Accept connections on `listener`, handing each one to `handle`.
+fn accept<F, C>(listener: TcpListener, mut handle: F) -> impl Future<Item = (), Error = ()>
+where
+    F: FnMut(TcpStream, SocketAddr) -> C,
+    C: Future<Item = (), Error = ()> + Send + 'static,
+{
+    listener
         .incoming()
-        .map_err(|e| eprintln!("accept failed = {:?}", e))
+        .map_err(|e| error!("accept failed = {:?}", e))
         .for_each(move |client_sock| {
-            println!("Connection from {}", client_sock.peer_addr().unwrap());
-
-            // Each proxy connection needs a reference to the registry.
-            let registry = registry.clone();
-
-            // Read the http header and determine the target host by the Host header.
-            // Don't exceed 16384 (16k) bytes.
-            let headerbuf = vec![0; 16384];
-            let con = read_http_header(client_sock, headerbuf)
-                .map_err(|e| eprintln!("Read error: {:?}", e))
-                .and_then(move |(client_sock, mut buffer, totalbytes, split)| {
-                    buffer.truncate(totalbytes);
-                    let header = &buffer[..split];
-                    let parsed_header = from_utf8(&header);
-                    if let Err(e) = parsed_header {
-                        eprintln!("Failed to decode utf-8: {:?}", e);
-                        return Err(());
-                    }
-                    let parsed_header = parsed_header.ok().unwrap();
-
-                    let host = extract_host(&parsed_header)?;
-                    // In the future, match on Host header *and* URI path.
-                    //let _uri = extract_uri(&parsed_header)?;
-
-                    // Lookup this host in the service registry.
-                    let addr = registry.lookup(host).map_err(|e| {
-                        match e {
-                            GetHostError::StrErr(estr) => {
-                                eprintln!("Error: {:?}", estr);
-                            }
-                            GetHostError::PoisonErr(estr) => {
-                                // This should happen if the lock is poisoned,
-                                // meaning we can't continue.
-                                eprintln!("Failed to acquire lock: {:?}", estr);
-                                panic!();
-                            }
-                        }
-                    })?;
-                    println!("Have mapping {} -> {}", host, addr);
-
-                    // Connect to the address from the service map, copy the bytes
-                    // we already read, and then proxy the remainder of the connection.
-                    let server_sock = TcpStream::connect(&addr);
-                    let server_con = server_sock
-                        .map_err(|e| eprintln!("Failed to connect: {:?}", e))
-                        .and_then(|server_stream| {
-                            write_all(server_stream, buffer)
-                                .map_err(|e| eprintln!("Error: {:?}", e))
-                        })
-                        .and_then(|(server_stream, _buf)| {
-                            proxy_connection(server_stream, client_sock)
-                        })
-                        .map_err(|e| eprintln!("Error: {:?}", e));
-                    tokio::spawn(server_con);
-                    Ok(())
-                })
-                .map_err(|e| eprintln!("Read Error: {:?}", e));
+            let client_addr = client_sock.peer_addr().unwrap();
+            let con = handle(client_sock, client_addr);
 
             // We spawn con and return an empty future, even though we could return con.
             // The reason is that futures returned in for_each blocks are each resolved
-            // before the next iteration begins. The future held in con resolves after the
-            // header has been read and the downstream connection has been made.
-            // If we return con, then each connection from a client will read headers and
-            // connect to the target serially rather than concurrently like we want.
+            // before the next iteration begins. The future held in con resolves after
+            // the client closes the connection.
+            // If we return con, then each connection from a client will be served
+            // serially rather than concurrently like we want.
             tokio::spawn(con);
             future::ok(())
         })
-        .map_err(|e| eprintln!("Error talking to client: {:?}", e));
+        .map_err(|e| error!("Error talking to client: {:?}", e))
+}
+
+/// One of the accept loops a Server runs.
+enum Listener {
+    Http,
+    Tls(TlsAcceptor),
+    Passthrough,
+    Admin,
+}
+
+impl Listener {
+    fn name(&self) -> &'static str {
+        match self {
+            Listener::Http => "http",
+            Listener::Tls(_) => "tls",
+            Listener::Passthrough => "passthrough",
+            Listener::Admin => "admin",
+        }
+    }
+}
 
+/// Listeners bound by Server::bind, ready to be started.
+struct Bound {
+    listeners: Vec<(Listener, String, TcpListener)>,
+    // The running listeners to keep, because their address hasn't changed.
+    keep: Vec<&'static str>,
+    proxy_protocol: bool,
+}
+
+/// Server runs robby's listeners and hands their connections to the current
+/// proxy. The proxy and listeners can be replaced while it runs, and
+/// connections that were already accepted carry on with the proxy they
+/// started with.
+struct Server<T: registry::ServiceProvider> {
+    proxy: RwLock<Arc<Proxy<T>>>,
+    proxy_protocol: AtomicBool,
+    // The address of each running listener and the sender that stops it.
+    running: Mutex<HashMap<&'static str, (String, oneshot::Sender<()>)>>,
+    executor: TaskExecutor,
+}
+
+impl<T> Server<T>
+where
+    T: 'static + registry::ServiceProvider + Send + Sync,
+{
+    fn proxy(&self) -> Arc<Proxy<T>> {
+        self.proxy.read().unwrap().clone()
+    }
+
+    fn set_proxy(&self, proxy: Arc<Proxy<T>>) {
+        *self.proxy.write().unwrap() = proxy;
+    }
+
+    /// Bind the addresses in `listeners` that aren't being listened on
+    /// already. Nothing changes until the result is passed to `start`.
+    fn bind(&self, listeners: Listeners) -> Result<Bound, String> {
+        let mut wanted = vec![(Listener::Http, listeners.http)];
+        if let Some((address, acceptor)) = listeners.tls {
+            wanted.push((Listener::Tls(acceptor), address));
+        }
+        if let Some(address) = listeners.passthrough {
+            wanted.push((Listener::Passthrough, address));
+        }
+        if let Some(address) = listeners.admin {
+            wanted.push((Listener::Admin, address));
+        }
+        let running = self.running.lock().unwrap();
+        let mut bound = Bound {
+            listeners: Vec::new(),
+            keep: Vec::new(),
+            proxy_protocol: listeners.proxy_protocol,
+        };
+        for (listener, address) in wanted {
+            match running.get(listener.name()) {
+                Some((running, _)) if *running == address => bound.keep.push(listener.name()),
+                _ => {
+                    let tcp_listener = bind(&address)?;
+                    bound.listeners.push((listener, address, tcp_listener));
+                }
+            }
+        }
+        Ok(bound)
+    }
+
+    /// Stop the running listeners that `bound` doesn't keep, and start the
+    /// ones it bound.
+    fn start(self: &Arc<Self>, bound: Bound) {
+        self.proxy_protocol
+            .store(bound.proxy_protocol, Ordering::SeqCst);
+        let mut running = self.running.lock().unwrap();
+        let stopped: Vec<&'static str> = running
+            .keys()
+            .filter(|name| !bound.keep.contains(name))
+            .cloned()
+            .collect();
+        for name in stopped {
+            if let Some((address, stop)) = running.remove(name) {
+                let _ = stop.send(());
+                info!("Robby stopped listening on {}", address);
+            }
+        }
+        for (listener, address, tcp_listener) in bound.listeners {
+            match listener {
+                Listener::Http => info!("Robby listening on {}", address),
+                Listener::Tls(_) => info!("Robby listening for TLS on {}", address),
+                Listener::Passthrough => info!("Robby passing TLS through on {}", address),
+                Listener::Admin => info!("Robby admin listening on {}", address),
+            }
+            let name = listener.name();
+            let (stop, stopped) = oneshot::channel();
+            // Dropping the accept loop closes the listener. Connections it
+            // accepted were spawned separately, so they aren't affected.
+            self.executor.spawn(
+                self.serve(listener, tcp_listener)
+                    .select(stopped.then(|_| Ok(())))
+                    .then(|_| Ok(())),
+            );
+            running.insert(name, (address, stop));
+        }
+    }
+
+    fn serve(
+        self: &Arc<Self>,
+        listener: Listener,
+        tcp_listener: TcpListener,
+    ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+        let server = self.clone();
+        match listener {
+            Listener::Http => Box::new(accept(tcp_listener, move |client_sock, client_addr| {
+                let proxy = server.proxy();
+                let proxy_protocol = server.proxy_protocol.load(Ordering::SeqCst);
+                let timeout = proxy.timeouts.header_read;
+                // Every request on the connection is routed on its own Host
+                // header and URI.
+                client_info(client_sock, client_addr, false, proxy_protocol, timeout)
+                    .and_then(move |(client_sock, info)| serve_connection(proxy, client_sock, info))
+            })),
+            Listener::Tls(acceptor) => {
+                Box::new(accept(tcp_listener, move |client_sock, client_addr| {
+                    let proxy = server.proxy();
+                    let proxy_protocol = server.proxy_protocol.load(Ordering::SeqCst);
+                    let acceptor = acceptor.clone();
+                    // A client that never finishes its handshake is cut off
+                    // like one that never finishes its request header.
+                    let timeout = proxy.timeouts.header_read;
+                    client_info(client_sock, client_addr, true, proxy_protocol, timeout).and_then(
+                        move |(client_sock, info)| {
+                            with_timeout(acceptor.accept(client_sock), timeout, "TLS handshake")
+                                .map_err(move |e| {
+                                    debug!("TLS handshake with {} failed: {}", client_addr, e)
+                                })
+                                .and_then(move |tls_sock| serve_connection(proxy, tls_sock, info))
+                        },
+                    )
+                }))
+            }
+            Listener::Passthrough => {
+                Box::new(accept(tcp_listener, move |client_sock, client_addr| {
+                    let proxy = server.proxy();
+                    let proxy_protocol = server.proxy_protocol.load(Ordering::SeqCst);
+                    let timeout = proxy.timeouts.header_read;
+                    client_info(client_sock, client_addr, true, proxy_protocol, timeout).and_then(
+                        move |(client_sock, info)| serve_passthrough(proxy, client_sock, info),
+                    )
+                }))
+            }
+            Listener::Admin => Box::new(accept(tcp_listener, move |client_sock, _| {
+                serve_admin(server.proxy(), client_sock)
+            })),
+        }
+    }
+}
+
+/// Start serving requests on `listeners` with `proxy`. The runtime runs
+/// until every listener is stopped and every connection is closed.
+fn start_server<T>(
+    listeners: Listeners,
+    proxy: Arc<Proxy<T>>,
+) -> Result<(Runtime, Arc<Server<T>>), String>
+where
+    T: 'static + registry::ServiceProvider + Send + Sync,
+{
     // We need to add a panic_handler that kills the process when a worker panics.
     // There's no valid excuse to continue after a panic, since we don't know what
     // state the program is in. Panics are not exceptional conditions or errors,
     // they are panics. I'm really not sure why tokio catches and ignores them by
     // default.
-    let mut runtime = Builder::new()
+    let runtime = Builder::new()
         .panic_handler(|e| {
-            eprintln!("FATAL ERROR: {:?}", e);
+            error!("FATAL ERROR: {:?}", e);
             std::process::exit(1);
         })
         .build()
         .expect("failed to start new Runtime");
-    runtime.spawn(server);
+    let server = Arc::new(Server {
+        proxy: RwLock::new(proxy),
+        proxy_protocol: AtomicBool::new(false),
+        running: Mutex::new(HashMap::new()),
+        executor: runtime.executor(),
+    });
+    let bound = server.bind(listeners)?;
+    server.start(bound);
+    Ok((runtime, server))
+}
+
+/// Serve requests on `listeners` with `proxy`.
+#[cfg(test)]
+fn run_server<T>(listeners: Listeners, proxy: Arc<Proxy<T>>) -> Result<(), String>
+where
+    T: 'static + registry::ServiceProvider + Send + Sync,
+{
+    let (runtime, _server) = start_server(listeners, proxy)?;
     runtime.shutdown_on_idle().wait().unwrap();
     Ok(())
 }
@@ -0,0 +1,613 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{Read, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use tokio::{io, prelude::*};
+
+use crate::registry::RegistryStats;
+
+// Upper bounds of the request duration histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Counts for each of BUCKETS, not cumulative.
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| value <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct RouteStats {
+    bytes_in: u64,
+    bytes_out: u64,
+    duration: Histogram,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    // Forwarded requests by route, backend and status.
+    requests: HashMap<(String, String, u16), u64>,
+    routes: HashMap<String, RouteStats>,
+    connect_errors: HashMap<SocketAddr, u64>,
+    // Responses robby made up itself, by status.
+    error_responses: HashMap<u16, u64>,
+}
+
+/// Metrics collects counters for the Prometheus `/metrics` endpoint.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+    client_connections: AtomicUsize,
+}
+
+impl Metrics {
+    fn record_request(
+        &self,
+        route: &str,
+        backend: Option<SocketAddr>,
+        status: u16,
+        bytes: (u64, u64),
+        duration: Duration,
+    ) {
+        let backend = backend.map_or(String::new(), |addr| addr.to_string());
+        let mut counters = self.counters.lock().unwrap();
+        *counters
+            .requests
+            .entry((route.to_string(), backend, status))
+            .or_insert(0) += 1;
+        let stats = counters.routes.entry(route.to_string()).or_default();
+        stats.bytes_in += bytes.0;
+        stats.bytes_out += bytes.1;
+        stats.duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn record_connect_error(&self, backend: SocketAddr) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.connect_errors.entry(backend).or_insert(0) += 1;
+    }
+
+    pub fn record_error_response(&self, status: u16) {
+        let mut counters = self.counters.lock().unwrap();
+        *counters.error_responses.entry(status).or_insert(0) += 1;
+    }
+
+    /// Count a client connection until the returned guard is dropped.
+    pub fn client_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.client_connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Everything in the Prometheus text format, along with `registry`.
+    pub fn render(&self, registry: &RegistryStats) -> String {
+        let mut out = String::new();
+        let counters = self.counters.lock().unwrap();
+
+        header(
+            &mut out,
+            "robby_requests_total",
+            "counter",
+            "Requests forwarded to backends.",
+        );
+        let mut requests: Vec<_> = counters.requests.iter().collect();
+        requests.sort();
+        for ((route, backend, status), count) in requests {
+            let _ = writeln!(
+                out,
+                "robby_requests_total{{route=\"{}\",backend=\"{}\",status=\"{}\"}} {}",
+                escape(route),
+                backend,
+                status,
+                count
+            );
+        }
+
+        let mut routes: Vec<_> = counters.routes.iter().collect();
+        routes.sort_by(|a, b| a.0.cmp(b.0));
+        header(
+            &mut out,
+            "robby_request_bytes_total",
+            "counter",
+            "Bytes received from clients.",
+        );
+        for (route, stats) in &routes {
+            let _ = writeln!(
+                out,
+                "robby_request_bytes_total{{route=\"{}\"}} {}",
+                escape(route),
+                stats.bytes_in
+            );
+        }
+        header(
+            &mut out,
+            "robby_response_bytes_total",
+            "counter",
+            "Bytes sent to clients.",
+        );
+        for (route, stats) in &routes {
+            let _ = writeln!(
+                out,
+                "robby_response_bytes_total{{route=\"{}\"}} {}",
+                escape(route),
+                stats.bytes_out
+            );
+        }
+        header(
+            &mut out,
+            "robby_request_duration_seconds",
+            "histogram",
+            "Time from receiving a request header to sending the whole response.",
+        );
+        for (route, stats) in &routes {
+            let route = escape(route);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(stats.duration.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "robby_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "robby_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+                route, stats.duration.count
+            );
+            let _ = writeln!(
+                out,
+                "robby_request_duration_seconds_sum{{route=\"{}\"}} {}",
+                route, stats.duration.sum
+            );
+            let _ = writeln!(
+                out,
+                "robby_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route, stats.duration.count
+            );
+        }
+
+        header(
+            &mut out,
+            "robby_backend_connect_errors_total",
+            "counter",
+            "Failed connection attempts to backends.",
+        );
+        let mut connect_errors: Vec<_> = counters.connect_errors.iter().collect();
+        connect_errors.sort();
+        for (backend, count) in connect_errors {
+            let _ = writeln!(
+                out,
+                "robby_backend_connect_errors_total{{backend=\"{}\"}} {}",
+                backend, count
+            );
+        }
+
+        header(
+            &mut out,
+            "robby_error_responses_total",
+            "counter",
+            "Error responses sent by robby itself.",
+        );
+        let mut error_responses: Vec<_> = counters.error_responses.iter().collect();
+        error_responses.sort();
+        for (status, count) in error_responses {
+            let _ = writeln!(
+                out,
+                "robby_error_responses_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+        drop(counters);
+
+        header(
+            &mut out,
+            "robby_client_connections",
+            "gauge",
+            "Open client connections.",
+        );
+        let _ = writeln!(
+            out,
+            "robby_client_connections {}",
+            self.client_connections.load(Ordering::SeqCst)
+        );
+
+        header(
+            &mut out,
+            "robby_backend_active_connections",
+            "gauge",
+            "Connections to each backend that are in use.",
+        );
+        for backend in &registry.backends {
+            let _ = writeln!(
+                out,
+                "robby_backend_active_connections{{backend=\"{}\"}} {}",
+                escape(&backend.addr),
+                backend.active
+            );
+        }
+        header(
+            &mut out,
+            "robby_backend_health_check_passing",
+            "gauge",
+            "Whether each checked backend passed its last health check.",
+        );
+        for backend in &registry.backends {
+            if let Some(passing) = backend.health_check {
+                let _ = writeln!(
+                    out,
+                    "robby_backend_health_check_passing{{backend=\"{}\"}} {}",
+                    escape(&backend.addr),
+                    passing as u8
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "robby_registry_routes",
+            "gauge",
+            "Routes in the routing table.",
+        );
+        let _ = writeln!(out, "robby_registry_routes {}", registry.routes);
+        header(
+            &mut out,
+            "robby_registry_backends",
+            "gauge",
+            "Distinct backends in the routing table.",
+        );
+        let _ = writeln!(out, "robby_registry_backends {}", registry.backends.len());
+        if let Some(update) = &registry.last_update {
+            let at = update.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+            header(
+                &mut out,
+                "robby_registry_last_update_timestamp_seconds",
+                "gauge",
+                "When the routing table was last updated.",
+            );
+            let _ = writeln!(
+                out,
+                "robby_registry_last_update_timestamp_seconds {}",
+                at.as_secs_f64()
+            );
+            header(
+                &mut out,
+                "robby_registry_last_update_duration_seconds",
+                "gauge",
+                "How long the last routing table update took.",
+            );
+            let _ = writeln!(
+                out,
+                "robby_registry_last_update_duration_seconds {}",
+                update.duration.as_secs_f64()
+            );
+            header(
+                &mut out,
+                "robby_registry_last_update_success",
+                "gauge",
+                "Whether the last routing table update succeeded.",
+            );
+            let _ = writeln!(
+                out,
+                "robby_registry_last_update_success {}",
+                update.error.is_none() as u8
+            );
+        }
+
+        header(
+            &mut out,
+            "robby_source_up",
+            "gauge",
+            "Whether the last query to each source of routes succeeded.",
+        );
+        for source in &registry.sources {
+            let _ = writeln!(
+                out,
+                "robby_source_up{{source=\"{}\"}} {}",
+                escape(&source.name),
+                source.error.is_none() as u8
+            );
+        }
+        header(
+            &mut out,
+            "robby_source_routes",
+            "gauge",
+            "Routes with a backend from each source.",
+        );
+        for source in &registry.sources {
+            let _ = writeln!(
+                out,
+                "robby_source_routes{{source=\"{}\"}} {}",
+                escape(&source.name),
+                source.routes
+            );
+        }
+        header(
+            &mut out,
+            "robby_source_last_success_timestamp_seconds",
+            "gauge",
+            "When a query to each source last succeeded.",
+        );
+        for source in &registry.sources {
+            if let Some(at) = source.last_success {
+                let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+                let _ = writeln!(
+                    out,
+                    "robby_source_last_success_timestamp_seconds{{source=\"{}\"}} {}",
+                    escape(&source.name),
+                    at.as_secs_f64()
+                );
+            }
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Keeps a client connection counted while it's open.
+pub struct ConnectionGuard(Arc<Metrics>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.client_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The bytes read from and written to a stream so far.
+#[derive(Debug, Default)]
+pub struct ByteCounts {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl ByteCounts {
+    /// The bytes read and written.
+    pub fn get(&self) -> (u64, u64) {
+        (
+            self.read.load(Ordering::SeqCst),
+            self.written.load(Ordering::SeqCst),
+        )
+    }
+}
+
+/// A stream that counts the bytes that pass through it.
+pub struct Counted<S> {
+    stream: S,
+    counts: Arc<ByteCounts>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(stream: S) -> Counted<S> {
+        Counted {
+            stream,
+            counts: Arc::new(ByteCounts::default()),
+        }
+    }
+
+    pub fn counts(&self) -> Arc<ByteCounts> {
+        self.counts.clone()
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        self.counts.read.fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.stream.write(buf)?;
+        self.counts.written.fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Counted<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Counted<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+/// Measures a single request on a client connection whose bytes are
+/// counted by `counts`.
+pub struct RequestMeter {
+    // The route the request was sent to, if it was routed.
+    route: Option<String>,
+    started: Instant,
+    counts: Arc<ByteCounts>,
+    // The connection's counts when the request started.
+    before: (u64, u64),
+    backend: Mutex<Option<SocketAddr>>,
+    // How long it took to get the response header from the backend.
+    backend_duration: Mutex<Option<Duration>>,
+}
+
+/// What a RequestMeter measured.
+pub struct Measurement {
+    pub backend: Option<SocketAddr>,
+    // Bytes received from and sent to the client.
+    pub bytes: (u64, u64),
+    pub duration: Duration,
+    pub backend_duration: Option<Duration>,
+}
+
+impl RequestMeter {
+    /// Start measuring a request to `route`. `before` is what the
+    /// connection had read and written before the request began.
+    pub fn new(route: Option<String>, counts: Arc<ByteCounts>, before: (u64, u64)) -> RequestMeter {
+        RequestMeter {
+            route,
+            started: Instant::now(),
+            counts,
+            before,
+            backend: Mutex::new(None),
+            backend_duration: Mutex::new(None),
+        }
+    }
+
+    /// Note the backend the request was sent to.
+    pub fn connected(&self, backend: SocketAddr) {
+        *self.backend.lock().unwrap() = Some(backend);
+    }
+
+    /// Note that the backend's response header arrived.
+    pub fn response_started(&self) {
+        *self.backend_duration.lock().unwrap() = Some(self.started.elapsed());
+    }
+
+    /// Record the request with the status it ended with. Requests that
+    /// weren't routed aren't counted.
+    pub fn finish(&self, metrics: &Metrics, status: u16) -> Measurement {
+        let (read, written) = self.counts.get();
+        let measurement = Measurement {
+            backend: *self.backend.lock().unwrap(),
+            bytes: (read - self.before.0, written - self.before.1),
+            duration: self.started.elapsed(),
+            backend_duration: *self.backend_duration.lock().unwrap(),
+        };
+        if let Some(route) = &self.route {
+            metrics.record_request(
+                route,
+                measurement.backend,
+                status,
+                measurement.bytes,
+                measurement.duration,
+            );
+        }
+        measurement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::{BackendStats, SourceStatus, UpdateStatus};
+    use std::{io::Cursor, time::SystemTime};
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(Metrics::default());
+        let mut stream = Counted::new(Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()));
+        let counts = stream.counts();
+        let meter = RequestMeter::new(Some("a.com/".to_string()), counts.clone(), (0, 0));
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).unwrap();
+        let backend = "127.0.0.1:8080".parse().unwrap();
+        meter.connected(backend);
+        assert_eq!(meter.finish(&metrics, 200).bytes, (18, 0));
+        // Requests that weren't routed aren't counted.
+        RequestMeter::new(None, counts, (0, 0)).finish(&metrics, 404);
+        metrics.record_connect_error(backend);
+        metrics.record_error_response(502);
+        let _guard = metrics.client_connection();
+
+        let registry = RegistryStats {
+            routes: 1,
+            backends: vec![BackendStats {
+                addr: "127.0.0.1:8080".to_string(),
+                active: 2,
+                health_check: Some(false),
+            }],
+            last_update: Some(UpdateStatus {
+                at: SystemTime::now(),
+                duration: Duration::from_millis(5),
+                error: None,
+            }),
+            sources: vec![
+                SourceStatus {
+                    name: "dc1".to_string(),
+                    routes: 1,
+                    last_success: Some(SystemTime::now()),
+                    error: None,
+                },
+                SourceStatus {
+                    name: "dc2".to_string(),
+                    routes: 0,
+                    last_success: None,
+                    error: Some("connection refused".to_string()),
+                },
+            ],
+        };
+        let text = metrics.render(&registry);
+        for line in &[
+            "robby_requests_total{route=\"a.com/\",backend=\"127.0.0.1:8080\",status=\"200\"} 1",
+            "robby_request_bytes_total{route=\"a.com/\"} 18",
+            "robby_response_bytes_total{route=\"a.com/\"} 0",
+            "robby_request_duration_seconds_bucket{route=\"a.com/\",le=\"+Inf\"} 1",
+            "robby_request_duration_seconds_count{route=\"a.com/\"} 1",
+            "robby_backend_connect_errors_total{backend=\"127.0.0.1:8080\"} 1",
+            "robby_error_responses_total{status=\"502\"} 1",
+            "robby_client_connections 1",
+            "robby_backend_active_connections{backend=\"127.0.0.1:8080\"} 2",
+            "robby_backend_health_check_passing{backend=\"127.0.0.1:8080\"} 0",
+            "robby_registry_routes 1",
+            "robby_registry_backends 1",
+            "robby_registry_last_update_success 1",
+            "robby_source_up{source=\"dc1\"} 1",
+            "robby_source_up{source=\"dc2\"} 0",
+            "robby_source_routes{source=\"dc1\"} 1",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+        drop(_guard);
+        assert!(metrics
+            .render(&registry)
+            .contains("robby_client_connections 0"));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.001);
+        histogram.observe(0.3);
+        histogram.observe(100.0);
+        assert_eq!(histogram.buckets[0], 1);
+        assert_eq!(histogram.buckets[6], 1);
+        assert_eq!(histogram.buckets.iter().sum::<u64>(), 2);
+        assert_eq!(histogram.count, 3);
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, time::Duration};
+
+use reqwest::{header::HeaderMap, Client, Response};
+use serde::Deserialize;
+
+use crate::{
+    consul::api_client,
+    registry::{HealthStatus, Indexed, ServiceNode, ServiceProvider},
+};
+
+/// How to reach Nomad. Empty strings leave a setting out.
+#[derive(Debug, Clone, Default)]
+pub struct NomadConfig {
+    // The HTTP API, e.g. `http://127.0.0.1:4646`.
+    pub address: String,
+    // The ACL token sent with every request.
+    pub token: String,
+    // The namespace to read services from, or `*` for all of them.
+    pub namespace: String,
+    pub region: String,
+    // A PEM file of CA certificates to trust, and a client certificate and
+    // key, for an https address.
+    pub ca_file: String,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// NomadClient reads services registered with Nomad's own service
+/// discovery, using blocking queries like ConsulClient.
+pub struct NomadClient {
+    address: String,
+    wait: Duration,
+    client: Client,
+    // The namespace and region parameters sent with every request.
+    scope: Vec<(&'static str, String)>,
+}
+
+impl NomadClient {
+    pub fn new(config: &NomadConfig, wait: Duration) -> Result<NomadClient, String> {
+        // Nomad adds up to wait/16 of jitter to a blocking query too.
+        let client = api_client(
+            wait + wait / 16 + Duration::from_secs(5),
+            ("X-Nomad-Token", &config.token),
+            &config.ca_file,
+            &config.cert_file,
+            &config.key_file,
+        )
+        .map_err(|e| format!("Failed to build nomad client: {}", e))?;
+        let scope = [("namespace", &config.namespace), ("region", &config.region)]
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| (*name, value.to_string()))
+            .collect();
+        Ok(NomadClient {
+            address: config.address.trim_end_matches('/').to_string(),
+            wait,
+            client,
+            scope,
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn get(&self, endpoint: &str, index: u64) -> Result<Response, String> {
+        let url = format!("{}/v1/{}", self.address, endpoint);
+        let mut request = self.client.get(&url).query(&self.scope);
+        if index > 0 {
+            request = request.query(&[
+                ("index", index.to_string()),
+                ("wait", format!("{}s", self.wait.as_secs())),
+            ]);
+        }
+        let response = request
+            .send()
+            .map_err(|e| format!("Request to {} failed: {}", url, e))?;
+        if !response.status().is_success() {
+            return Err(format!("Request to {} failed: {}", url, response.status()));
+        }
+        Ok(response)
+    }
+}
+
+fn nomad_index(headers: &HeaderMap) -> Result<u64, String> {
+    headers
+        .get("X-Nomad-Index")
+        .ok_or_else(|| "Nomad response is missing X-Nomad-Index".to_string())?
+        .to_str()
+        .map_err(|e| format!("Bad X-Nomad-Index: {}", e))?
+        .parse()
+        .map_err(|e| format!("Bad X-Nomad-Index: {}", e))
+}
+
+// These mirror the parts of /v1/services and /v1/service/<name> responses
+// that we care about.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NamespaceServices {
+    services: Vec<ServiceStub>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ServiceStub {
+    service_name: String,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Registration {
+    address: String,
+    port: u16,
+    tags: Option<Vec<String>>,
+}
+
+/// The services in every namespace of a /v1/services response, with their
+/// tags. A service in more than one namespace gets the tags of all of them.
+fn services_by_name(namespaces: Vec<NamespaceServices>) -> HashMap<String, Vec<String>> {
+    let mut services: HashMap<String, Vec<String>> = HashMap::new();
+    for service in namespaces.into_iter().flat_map(|ns| ns.services) {
+        let tags = services.entry(service.service_name).or_default();
+        for tag in service.tags.unwrap_or_default() {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+    }
+    services
+}
+
+impl From<Registration> for ServiceNode {
+    fn from(registration: Registration) -> ServiceNode {
+        ServiceNode {
+            address: registration.address,
+            port: registration.port,
+            tags: registration.tags.unwrap_or_default(),
+            // Nomad only lists the registrations of running allocations and
+            // doesn't report check results, so robby's own health checks
+            // are the way to take a wedged instance out.
+            status: HealthStatus::Passing,
+        }
+    }
+}
+
+impl ServiceProvider for NomadClient {
+    fn services(&self, index: u64) -> Result<Indexed<HashMap<String, Vec<String>>>, String> {
+        let mut response = self.get("services", index)?;
+        let index = nomad_index(response.headers())?;
+        let namespaces: Vec<NamespaceServices> = response
+            .json()
+            .map_err(|e| format!("Error parsing nomad response: {}", e))?;
+        Ok(Indexed {
+            index,
+            value: services_by_name(namespaces),
+        })
+    }
+
+    fn get_nodes(&self, service: &str, index: u64) -> Result<Indexed<Vec<ServiceNode>>, String> {
+        let mut response = self.get(&format!("service/{}", service), index)?;
+        let index = nomad_index(response.headers())?;
+        let registrations: Vec<Registration> = response
+            .json()
+            .map_err(|e| format!("Error parsing nomad response: {}", e))?;
+        Ok(Indexed {
+            index,
+            value: registrations.into_iter().map(ServiceNode::from).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        sync::{mpsc, Mutex},
+        thread,
+    };
+
+    #[test]
+    fn test_services_by_name() {
+        let namespaces: Vec<NamespaceServices> = serde_json::from_str(
+            r#"[
+                {"Namespace": "default", "Services": [
+                    {"ServiceName": "web", "Tags": ["urlprefix-web.com/"]},
+                    {"ServiceName": "db", "Tags": null}
+                ]},
+                {"Namespace": "prod", "Services": [
+                    {"ServiceName": "web", "Tags": ["urlprefix-web.com/", "prod"]}
+                ]}
+            ]"#,
+        )
+        .unwrap();
+        let services = services_by_name(namespaces);
+        assert_eq!(services.len(), 2);
+        assert_eq!(services["web"], vec!["urlprefix-web.com/", "prod"]);
+        assert!(services["db"].is_empty());
+    }
+
+    #[test]
+    fn test_get_nodes() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let (sender, requests) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        let server = rouille::Server::new(format!("127.0.0.1:{}", port), move |request| {
+            let token = request.header("X-Nomad-Token").unwrap_or("").to_string();
+            sender
+                .lock()
+                .unwrap()
+                .send((request.raw_url().to_string(), token))
+                .unwrap();
+            rouille::Response::text(
+                r#"[{"ID": "_nomad-task-1", "ServiceName": "web", "Namespace": "default",
+                     "Address": "10.0.0.5", "Port": 23456, "Tags": ["urlprefix-web.com/"]}]"#,
+            )
+            .with_additional_header("X-Nomad-Index", "12")
+        })
+        .unwrap();
+        thread::spawn(move || server.run());
+
+        let config = NomadConfig {
+            address: format!("http://127.0.0.1:{}", port),
+            token: "secret".to_string(),
+            namespace: "default".to_string(),
+            ..NomadConfig::default()
+        };
+        let client = NomadClient::new(&config, Duration::from_secs(2)).unwrap();
+        let nodes = client.get_nodes("web", 10).unwrap();
+        assert_eq!(nodes.index, 12);
+        assert_eq!(nodes.value[0].address, "10.0.0.5");
+        assert_eq!(nodes.value[0].port, 23456);
+        assert_eq!(nodes.value[0].status, HealthStatus::Passing);
+        assert_eq!(
+            requests.recv().unwrap(),
+            (
+                "/v1/service/web?namespace=default&index=10&wait=2s".to_string(),
+                "secret".to_string()
+            )
+        );
+    }
+}
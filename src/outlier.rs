@@ -0,0 +1,144 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Settings for passive outlier detection. A backend that fails
+/// `consecutive_failures` requests in a row is ejected from load balancing
+/// for `base_ejection`, doubled for each time it was ejected before, up to
+/// `max_ejection`. At most `max_ejection_percent` of a route's backends are
+/// ejected at once.
+#[derive(Debug, Clone)]
+pub struct OutlierDetection {
+    pub consecutive_failures: u32,
+    pub base_ejection: Duration,
+    pub max_ejection: Duration,
+    pub max_ejection_percent: usize,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> OutlierDetection {
+        OutlierDetection {
+            consecutive_failures: 5,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+            max_ejection_percent: 50,
+        }
+    }
+}
+
+impl OutlierDetection {
+    /// How many of `total` backends may be ejected at once.
+    pub fn max_ejected(&self, total: usize) -> usize {
+        total * self.max_ejection_percent / 100
+    }
+
+    fn ejection_time(&self, ejections: u32) -> Duration {
+        let factor = 2u32.saturating_pow(ejections.saturating_sub(1));
+        self.base_ejection
+            .checked_mul(factor)
+            .map_or(self.max_ejection, |time| time.min(self.max_ejection))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    consecutive_failures: u32,
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+/// The failure history of one backend.
+#[derive(Debug, Default)]
+pub struct OutlierState {
+    counts: Mutex<Counts>,
+}
+
+impl OutlierState {
+    /// Count a failed request. Returns how long the backend is ejected for
+    /// if this failure ejected it.
+    pub fn failure(&self, detection: &OutlierDetection) -> Option<Duration> {
+        let mut counts = self.counts.lock().unwrap();
+        let now = Instant::now();
+        if counts.ejected_until.is_some_and(|until| now < until) {
+            // Requests that were already under way when the backend was
+            // ejected don't extend the ejection.
+            return None;
+        }
+        counts.consecutive_failures += 1;
+        if counts.consecutive_failures < detection.consecutive_failures {
+            return None;
+        }
+        counts.consecutive_failures = 0;
+        counts.ejections += 1;
+        let time = detection.ejection_time(counts.ejections);
+        counts.ejected_until = Some(now + time);
+        Some(time)
+    }
+
+    /// Count a successful request. Once a backend has gone `max_ejection`
+    /// without being ejected, the next ejection starts from `base_ejection`
+    /// again.
+    pub fn success(&self, detection: &OutlierDetection) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.consecutive_failures = 0;
+        if let Some(until) = counts.ejected_until {
+            if Instant::now() > until + detection.max_ejection {
+                counts.ejections = 0;
+                counts.ejected_until = None;
+            }
+        }
+    }
+
+    /// The end of the backend's current ejection, if it's ejected.
+    pub fn ejected_until(&self) -> Option<Instant> {
+        self.counts
+            .lock()
+            .unwrap()
+            .ejected_until
+            .filter(|until| Instant::now() < *until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ejection() {
+        let detection = OutlierDetection {
+            consecutive_failures: 3,
+            base_ejection: Duration::from_secs(10),
+            max_ejection: Duration::from_secs(30),
+            max_ejection_percent: 50,
+        };
+        let state = OutlierState::default();
+
+        // A success resets the count of consecutive failures.
+        assert_eq!(state.failure(&detection), None);
+        assert_eq!(state.failure(&detection), None);
+        state.success(&detection);
+        assert_eq!(state.failure(&detection), None);
+        assert_eq!(state.failure(&detection), None);
+        assert_eq!(state.ejected_until(), None);
+        assert_eq!(state.failure(&detection), Some(Duration::from_secs(10)));
+        assert!(state.ejected_until().is_some());
+
+        // Failures while ejected aren't counted.
+        for _ in 0..5 {
+            assert_eq!(state.failure(&detection), None);
+        }
+
+        // Each ejection is twice as long as the last, up to the maximum.
+        state.counts.lock().unwrap().ejected_until = Some(Instant::now());
+        for _ in 0..2 {
+            assert_eq!(state.failure(&detection), None);
+        }
+        assert_eq!(state.failure(&detection), Some(Duration::from_secs(20)));
+        assert_eq!(detection.ejection_time(3), Duration::from_secs(30));
+        assert_eq!(detection.ejection_time(100), Duration::from_secs(30));
+
+        assert_eq!(detection.max_ejected(1), 0);
+        assert_eq!(detection.max_ejected(5), 2);
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tokio::net::TcpStream;
+
+/// Pool holds idle keep-alive connections to backends so that later
+/// requests can reuse them instead of connecting again.
+pub struct Pool {
+    // Each connection with when it was returned to the pool.
+    idle: Mutex<HashMap<SocketAddr, Vec<(TcpStream, Instant)>>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl Pool {
+    /// `max_idle` is the most idle connections kept for any one backend,
+    /// and connections that sit idle for longer than `idle_timeout` are
+    /// closed instead of reused.
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Pool {
+        Pool {
+            idle: Mutex::new(HashMap::new()),
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    pub fn max_idle(&self) -> usize {
+        self.max_idle
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Take the most recently used idle connection to `addr`, if there is one.
+    pub fn checkout(&self, addr: &SocketAddr) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.get_mut(addr)?;
+        // The oldest connections are first, so the expired ones are too.
+        let idle_timeout = self.idle_timeout;
+        streams.retain(|(_, since)| since.elapsed() < idle_timeout);
+        let stream = streams.pop();
+        if streams.is_empty() {
+            idle.remove(addr);
+        }
+        stream.map(|(stream, _)| stream)
+    }
+
+    /// Return a connection to `addr` that is ready for another request.
+    pub fn checkin(&self, addr: SocketAddr, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let streams = idle.entry(addr).or_default();
+        if streams.len() < self.max_idle {
+            streams.push((stream, Instant::now()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+    use tokio::prelude::Future;
+
+    fn connect(addr: &SocketAddr) -> TcpStream {
+        TcpStream::connect(addr).wait().unwrap()
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let pool = Pool::new(2, Duration::from_millis(50));
+        pool.checkin(addr, connect(&addr));
+        assert!(pool.checkout(&addr).is_some());
+        assert!(pool.checkout(&addr).is_none());
+
+        pool.checkin(addr, connect(&addr));
+        thread::sleep(Duration::from_millis(100));
+        assert!(pool.checkout(&addr).is_none());
+    }
+}
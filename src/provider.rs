@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+
+use crate::{
+    consul::ConsulClient,
+    nomad::NomadClient,
+    registry::{Indexed, ServiceNode, ServiceProvider},
+};
+
+/// A service discovery backend, chosen with the `provider` setting or an
+/// entry of `providers`.
+pub enum Provider {
+    Consul(ConsulClient),
+    Nomad(NomadClient),
+}
+
+impl Provider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Consul(_) => "consul",
+            Provider::Nomad(_) => "nomad",
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        match self {
+            Provider::Consul(client) => client.address(),
+            Provider::Nomad(client) => client.address(),
+        }
+    }
+}
+
+impl ServiceProvider for Provider {
+    fn services(&self, index: u64) -> Result<Indexed<HashMap<String, Vec<String>>>, String> {
+        match self {
+            Provider::Consul(client) => client.services(index),
+            Provider::Nomad(client) => client.services(index),
+        }
+    }
+
+    fn get_nodes(&self, service: &str, index: u64) -> Result<Indexed<Vec<ServiceNode>>, String> {
+        match self {
+            Provider::Consul(client) => client.get_nodes(service, index),
+            Provider::Nomad(client) => client.get_nodes(service, index),
+        }
+    }
+}
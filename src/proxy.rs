@@ -0,0 +1,1114 @@
+use std::{
+    net::SocketAddr,
+    str::from_utf8,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use futures::future::{self, Either, Loop};
+use tokio::{
+    io::{self, shutdown, write_all},
+    net::TcpStream,
+    prelude::*,
+    timer::Delay,
+};
+
+use tokio_threadpool::blocking;
+
+use crate::{
+    access_log::{AccessLog, Entry},
+    acme::{challenge_token, Storage},
+    balancer::RequestContext,
+    copy_body::{copy_body, is_write_error},
+    error_page::ErrorPages,
+    extract_header, extract_host, extract_uri,
+    forwarded::{rewrite_header, Forwarding, TrustedProxies},
+    http::{self, BodyLength, RequestInfo, ResponseInfo},
+    metrics::{ByteCounts, Counted, Metrics, RequestMeter},
+    pool::Pool,
+    proxy_connection, proxy_protocol,
+    read_client_hello::{read_client_hello, server_name},
+    read_http_header::read_http_header,
+    registry::{GetHostError, ServiceProvider, ServiceRegistry, Target},
+    timeout::{with_lifetime, with_timeout, Activity, Idle, Timeouts},
+};
+
+// Don't let a request or response header exceed 16384 (16k) bytes.
+const MAX_HEADER: usize = 16384;
+
+// ClientHellos are normally well under 2k, even with post-quantum key shares.
+const MAX_CLIENT_HELLO: usize = 16384;
+
+// Read at most this much of a request that gets an error response.
+const MAX_DRAIN: usize = 65536;
+
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+/// The most times a request can be retried on another backend.
+pub const MAX_RETRIES: u32 = 10;
+
+// The longest wait before a retry, however many came before it.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Proxy holds everything a client connection needs to route and forward
+/// its requests.
+pub struct Proxy<T: ServiceProvider> {
+    pub registry: Arc<ServiceRegistry<T>>,
+    // Shared with the proxies that replace this one when the config is
+    // reloaded, so that idle connections survive a reload.
+    pub pool: Arc<Pool>,
+    // Where to find ACME HTTP-01 challenge responses, if ACME is enabled.
+    pub acme: Option<Arc<Storage>>,
+    // Clients whose X-Forwarded-* and Forwarded headers are passed on.
+    pub trusted_proxies: TrustedProxies,
+    // The responses sent when a request can't be forwarded.
+    pub error_pages: ErrorPages,
+    // How many other instances to try when a backend can't be reached, how
+    // long to wait before the first retry, and how long to avoid a backend
+    // that couldn't be reached.
+    pub retries: u32,
+    pub retry_backoff: Duration,
+    pub mark_down: Duration,
+    // The timeouts for routes that don't set their own.
+    pub timeouts: Timeouts,
+    pub metrics: Arc<Metrics>,
+    // Where to log each request, if anywhere.
+    pub access_log: Option<AccessLog>,
+}
+
+impl<T: ServiceProvider> Proxy<T> {
+    /// A proxy for `registry` that keeps up to `max_idle` idle connections
+    /// to each backend.
+    pub fn new(registry: Arc<ServiceRegistry<T>>, max_idle: usize) -> Proxy<T> {
+        Proxy {
+            registry,
+            pool: Arc::new(Pool::new(max_idle, Duration::from_secs(60))),
+            acme: None,
+            trusted_proxies: TrustedProxies::default(),
+            error_pages: ErrorPages::default(),
+            retries: 2,
+            retry_backoff: Duration::from_millis(50),
+            mark_down: Duration::from_secs(10),
+            timeouts: Timeouts::default(),
+            metrics: Arc::new(Metrics::default()),
+            access_log: None,
+        }
+    }
+}
+
+/// Where a client connection came from and how it reached robby. When
+/// the connection starts with a PROXY header, the addresses are the ones
+/// from the header.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientInfo {
+    pub addr: SocketAddr,
+    // The address the client connected to.
+    pub local: SocketAddr,
+    pub tls: bool,
+}
+
+// Each step of a client connection either serves another request with the
+// client stream and whatever was read past the end of the last request, or
+// finishes the connection.
+type Step<S> = Box<dyn Future<Item = Loop<(), (S, Vec<u8>)>, Error = ()> + Send>;
+
+type IoFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
+
+// A failed exchange with a backend, along with the client if nothing has
+// been sent to it yet that would keep it from getting an error response.
+type Failed<S> = (io::Error, Option<S>);
+
+type ExchangeFuture<T, S> = Box<dyn Future<Item = T, Error = Failed<S>> + Send>;
+
+// An error on the client's side of an exchange, which says nothing about
+// the backend's health.
+#[derive(Debug)]
+struct ClientError(io::Error);
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+fn client_error(e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), ClientError(e))
+}
+
+fn is_client_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<ClientError>())
+}
+
+// A response header read from upstream after sending a request body: the
+// client, the upstream, the bytes read from upstream, the header's length,
+// and whatever the client sent past the end of the request body.
+type BodySent<S> = (S, TcpStream, Vec<u8>, usize, Vec<u8>);
+
+/// Serve every request on a client connection, routing each one on its own
+/// Host header and URI. Resolves when the client closes the connection, or
+/// when a request or response means the connection can't be reused.
+pub fn serve_connection<S, T>(
+    proxy: Arc<Proxy<T>>,
+    client: S,
+    info: ClientInfo,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    let lifetime = proxy.timeouts.lifetime;
+    let open = proxy.metrics.client_connection();
+    let client = Counted::new(client);
+    let counts = client.counts();
+    let connection = future::loop_fn((client, Vec::new()), move |(client, leftover)| {
+        serve_request(proxy.clone(), client, info, leftover, counts.clone())
+    });
+    with_lifetime(connection, lifetime).then(move |result| {
+        drop(open);
+        result
+    })
+}
+
+fn serve_request<S, T>(
+    proxy: Arc<Proxy<T>>,
+    client: S,
+    info: ClientInfo,
+    mut buffer: Vec<u8>,
+    counts: Arc<ByteCounts>,
+) -> Step<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    // Whatever was read past the end of the last request belongs to this
+    // one.
+    let (read, written) = counts.get();
+    let before = (read - buffer.len() as u64, written);
+    let pos = buffer.len();
+    buffer.resize(MAX_HEADER, 0);
+    let mut read = read_http_header(client, buffer, pos);
+    if proxy.timeouts.header_read > Duration::from_secs(0) {
+        read = read.with_deadline(Instant::now() + proxy.timeouts.header_read);
+    }
+    let request = read
+        .map_err(|e| {
+            // Clients close idle keep-alive connections all the time.
+            if e.kind() != io::ErrorKind::UnexpectedEof {
+                debug!("Read error: {:?}", e);
+            }
+        })
+        .and_then(move |(client, mut buffer, totalbytes, split)| -> Step<S> {
+            if split == 0 && totalbytes == 0 {
+                // An idle keep-alive connection is closed without a word.
+                return Box::new(future::ok(Loop::Break(())));
+            }
+            buffer.truncate(totalbytes);
+            let unrouted = |head| Origin {
+                original: head,
+                info,
+                meter: RequestMeter::new(None, counts.clone(), before),
+            };
+            if split == 0 {
+                let status = if totalbytes == MAX_HEADER {
+                    debug!("Request header from {} exceeded max length", info.addr);
+                    431
+                } else {
+                    debug!("Timed out reading request header from {}", info.addr);
+                    408
+                };
+                let origin = Arc::new(unrouted(buffer));
+                return logged_error_response(proxy, client, origin, status);
+            }
+            let leftover = buffer.split_off(split);
+            let head = buffer;
+            if let Some(token) = acme_challenge(&proxy, &head) {
+                return answer_challenge(&proxy, client, &token, leftover);
+            }
+            match route_request(&proxy, &head, info.addr) {
+                Ok((request, target)) => {
+                    let forwarded = forwarded_head(&proxy, &head, info);
+                    let route = Some(target.route.clone());
+                    let origin = Origin {
+                        original: head,
+                        info,
+                        meter: RequestMeter::new(route, counts, before),
+                    };
+                    forward_request(proxy, client, forwarded, leftover, request, target, origin)
+                }
+                Err(status) => {
+                    let origin = Arc::new(unrouted(head));
+                    logged_error_response(proxy, client, origin, status)
+                }
+            }
+        });
+    Box::new(request)
+}
+
+/// Returns the token if the request in `head` is for an ACME HTTP-01
+/// challenge and ACME is enabled.
+fn acme_challenge<T: ServiceProvider>(proxy: &Proxy<T>, head: &[u8]) -> Option<String> {
+    proxy.acme.as_ref()?;
+    let uri = from_utf8(head).ok()?.split_whitespace().nth(1)?;
+    challenge_token(uri).map(|token| token.to_string())
+}
+
+/// Answer an ACME HTTP-01 challenge from the shared ACME storage. The
+/// instance that ordered the certificate may not be the one the ACME server
+/// reaches.
+fn answer_challenge<S, T>(proxy: &Proxy<T>, client: S, token: &str, leftover: Vec<u8>) -> Step<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: ServiceProvider,
+{
+    let storage = proxy.acme.clone().expect("ACME is enabled");
+    let token = token.to_string();
+    // Reading the storage may mean a request to Consul, so don't hold up
+    // the reactor thread with it.
+    let lookup = future::poll_fn(move || blocking(|| storage.challenge(&token)))
+        .map_err(|e| error!("Failed to look up ACME challenge: {:?}", e));
+    let step = lookup
+        .and_then(|found| {
+            let response = match found {
+                Ok(Some(key_authorization)) => simple_response("200 OK", &key_authorization),
+                Ok(None) => simple_response("404 Not Found", b"Not Found"),
+                Err(e) => {
+                    error!("Failed to look up ACME challenge: {}", e);
+                    simple_response("404 Not Found", b"Not Found")
+                }
+            };
+            write_all(client, response).map_err(|e| debug!("Error: {:?}", e))
+        })
+        .map(|(client, _)| Loop::Continue((client, leftover)));
+    Box::new(step)
+}
+
+fn simple_response(status: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n",
+        status,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Send the client an error response with `status`, then close the
+/// connection.
+fn error_response<S, T>(proxy: &Proxy<T>, client: S, status: u16) -> Step<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: ServiceProvider,
+{
+    proxy.metrics.record_error_response(status);
+    let response = proxy.error_pages.response(status);
+    Box::new(
+        write_all(client, response)
+            .and_then(|(client, _)| drain(client))
+            .and_then(shutdown)
+            .map_err(|e| debug!("Error: {:?}", e))
+            .map(|_| Loop::Break(())),
+    )
+}
+
+/// Send the client an error response for the request from `origin`, then
+/// log the request.
+fn logged_error_response<S, T>(
+    proxy: Arc<Proxy<T>>,
+    client: S,
+    origin: Arc<Origin>,
+    status: u16,
+) -> Step<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    let step = error_response(&proxy, client, status).then(move |result| {
+        finish_request(&proxy, &origin, status);
+        result
+    });
+    Box::new(step)
+}
+
+/// Record a finished request in the metrics and the access log.
+fn finish_request<T: ServiceProvider>(proxy: &Proxy<T>, origin: &Origin, status: u16) {
+    let measurement = origin.meter.finish(&proxy.metrics, status);
+    if let Some(access_log) = &proxy.access_log {
+        access_log.log(&Entry {
+            client: origin.info.addr,
+            head: &origin.original,
+            backend: measurement.backend,
+            status,
+            bytes: measurement.bytes,
+            time: Local::now(),
+            duration: measurement.duration,
+            backend_duration: measurement.backend_duration,
+        });
+    }
+}
+
+/// Read and throw away whatever the client has already sent, up to
+/// MAX_DRAIN bytes. Closing a socket with unread data resets the
+/// connection, and the client may never see the response written to it.
+fn drain<S: AsyncRead>(client: S) -> impl Future<Item = S, Error = io::Error> {
+    let mut client = Some(client);
+    let mut drained = 0;
+    let mut buf = [0; 4096];
+    future::poll_fn(move || loop {
+        let stream = client.as_mut().expect("poll drain after it's done");
+        match stream.poll_read(&mut buf)? {
+            Async::Ready(n) if n > 0 && drained < MAX_DRAIN => drained += n,
+            _ => return Ok(Async::Ready(client.take().unwrap())),
+        }
+    })
+}
+
+/// The status to send a client when the exchange with its backend failed
+/// with `e`.
+fn gateway_status(e: &io::Error) -> u16 {
+    if e.kind() == io::ErrorKind::TimedOut {
+        504
+    } else {
+        502
+    }
+}
+
+/// Put a PROXY header in front of `head` if `target` wants one.
+fn with_proxy_header(target: &Target, info: ClientInfo, head: Vec<u8>) -> Vec<u8> {
+    match target.proxy_protocol {
+        Some(version) => {
+            let mut header = proxy_protocol::header(version, info.addr, info.local);
+            header.extend(head);
+            header
+        }
+        None => head,
+    }
+}
+
+/// Add the forwarding headers for `info` to the request header `head`.
+fn forwarded_head<T: ServiceProvider>(proxy: &Proxy<T>, head: &[u8], info: ClientInfo) -> Vec<u8> {
+    // route_request already checked that the header is valid utf-8.
+    let head = from_utf8(head).expect("header is utf-8");
+    let forwarding = Forwarding {
+        client: info.addr.ip(),
+        host: extract_header(head, "Host").unwrap_or(""),
+        proto: if info.tls { "https" } else { "http" },
+        port: info.local.port(),
+        trusted: proxy.trusted_proxies.contains(info.addr.ip()),
+    };
+    rewrite_header(head, &forwarding)
+}
+
+/// Find the backend for the request in `head`. Fails with the status to
+/// send the client if there isn't one.
+fn route_request<T>(
+    proxy: &Proxy<T>,
+    head: &[u8],
+    client_addr: SocketAddr,
+) -> Result<(RequestInfo, Target), u16>
+where
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    let parsed_header = from_utf8(head).map_err(|e| {
+        debug!("Failed to decode utf-8: {:?}", e);
+        400u16
+    })?;
+    let request = http::parse_request(head).map_err(|e| {
+        debug!("Error: {}", e);
+        debug!("Header: {}", parsed_header);
+        400u16
+    })?;
+
+    let host = extract_host(parsed_header).map_err(|_| 400u16)?;
+    let host = host.as_str();
+    let uri = extract_uri(parsed_header).map_err(|_| 400u16)?;
+
+    // Lookup this host and path in the service registry.
+    let context = RequestContext {
+        client: client_addr.ip(),
+        header: parsed_header,
+        exclude: &[],
+    };
+    let target = proxy.registry.lookup(host, uri, &context).map_err(|e| {
+        match e {
+            GetHostError::StrErr(estr) => {
+                debug!("Error: {:?}", estr);
+            }
+            GetHostError::PoisonErr(estr) => {
+                // This should happen if the lock is poisoned,
+                // meaning we can't continue.
+                error!("Failed to acquire lock: {:?}", estr);
+                panic!();
+            }
+        }
+        404u16
+    })?;
+    debug!("Routed {}{} to {}", host, uri, target.addr);
+    Ok((request, target))
+}
+
+/// Errors that mean a pooled connection was closed by the backend while it
+/// sat idle, so the request can safely be sent again on a new connection.
+fn is_stale(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Read a response header from `upstream`, failing if it takes longer than
+/// `timeout`. `leftover` holds bytes that were already read from it.
+/// Resolves to the stream, the bytes read, and the length of the header.
+fn read_response_head(
+    upstream: TcpStream,
+    mut leftover: Vec<u8>,
+    timeout: Duration,
+) -> IoFuture<(TcpStream, Vec<u8>, usize)> {
+    let pos = leftover.len();
+    leftover.resize(MAX_HEADER, 0);
+    let read = with_timeout(
+        read_http_header(upstream, leftover, pos),
+        timeout,
+        "Reading the response header",
+    );
+    Box::new(read.and_then(|(upstream, mut buffer, totalbytes, split)| {
+        if split == 0 {
+            let e = "HTTP response header exceeded max length";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        buffer.truncate(totalbytes);
+        Ok((upstream, buffer, split))
+    }))
+}
+
+fn connect_and_send(
+    addr: SocketAddr,
+    head: Vec<u8>,
+    timeouts: Timeouts,
+) -> IoFuture<(TcpStream, Vec<u8>, usize)> {
+    Box::new(
+        with_timeout(TcpStream::connect(&addr), timeouts.connect, "Connecting")
+            .and_then(|upstream| write_all(upstream, head))
+            .and_then(move |(upstream, _)| {
+                read_response_head(upstream, Vec::new(), timeouts.first_byte)
+            }),
+    )
+}
+
+/// Copy a body with copy_body, failing if neither side moves any data for
+/// `idle`.
+fn copy_body_idle<R, W>(
+    reader: R,
+    writer: W,
+    length: BodyLength,
+    buf: Vec<u8>,
+    idle: Duration,
+) -> IoFuture<(R, W, Vec<u8>)>
+where
+    R: AsyncRead + Send + 'static,
+    W: AsyncWrite + Send + 'static,
+{
+    let activity = Activity::new();
+    let reader = Idle::new(reader, idle, activity.clone());
+    let writer = Idle::new(writer, idle, activity);
+    Box::new(
+        future::result(copy_body(reader, writer, length, buf))
+            .flatten()
+            .map(|(reader, writer, extra)| (reader.into_inner(), writer.into_inner(), extra)),
+    )
+}
+
+/// Where a request came from: what it takes to route it again when its
+/// backend can't be reached, and to measure and log it once it's done.
+struct Origin {
+    // The request header as the client sent it.
+    original: Vec<u8>,
+    info: ClientInfo,
+    meter: RequestMeter,
+}
+
+// A connection to a backend, whether it came from the pool, and the backend
+// it's to.
+type Upstream = (TcpStream, bool, Target);
+
+type ConnectStep = IoFuture<Loop<Upstream, (Target, Vec<SocketAddr>)>>;
+
+/// Connect to `target`. If it can't be reached, it's marked down and the
+/// request is routed to another instance, up to `proxy.retries` times with
+/// exponential backoff. An idle connection is taken from the pool instead
+/// if `use_pool` is set.
+fn connect_upstream<T>(
+    proxy: Arc<Proxy<T>>,
+    target: Target,
+    origin: Arc<Origin>,
+    use_pool: bool,
+) -> IoFuture<Upstream>
+where
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    Box::new(future::loop_fn(
+        (target, Vec::new()),
+        move |(target, mut tried): (Target, Vec<SocketAddr>)| -> ConnectStep {
+            if use_pool {
+                if let Some(upstream) = proxy.pool.checkout(&target.addr) {
+                    origin.meter.connected(target.addr);
+                    return Box::new(future::ok(Loop::Break((upstream, true, target))));
+                }
+            }
+            let proxy = proxy.clone();
+            let origin = origin.clone();
+            let timeout = proxy.timeouts.for_route(&target.timeouts).connect;
+            let connect = with_timeout(TcpStream::connect(&target.addr), timeout, "Connecting");
+            let step = connect.then(move |result| -> ConnectStep {
+                let e = match result {
+                    Ok(upstream) => {
+                        origin.meter.connected(target.addr);
+                        return Box::new(future::ok(Loop::Break((upstream, false, target))));
+                    }
+                    Err(e) => e,
+                };
+                warn!("Failed to connect to {}: {}", target.addr, e);
+                proxy.metrics.record_connect_error(target.addr);
+                target.mark_down(proxy.mark_down);
+                proxy.registry.record_result(&target, false);
+                tried.push(target.addr);
+                drop(target);
+                let attempt = tried.len() as u32;
+                if attempt > proxy.retries {
+                    return Box::new(future::err(e));
+                }
+                let backoff = proxy
+                    .retry_backoff
+                    .saturating_mul(2u32.saturating_pow(attempt - 1))
+                    .min(MAX_RETRY_BACKOFF);
+                let next = Delay::new(Instant::now() + backoff).then(move |_| {
+                    match reroute(&proxy, &origin, &tried) {
+                        Some(target) => Ok(Loop::Continue((target, tried))),
+                        None => Err(e),
+                    }
+                });
+                Box::new(next)
+            });
+            Box::new(step)
+        },
+    ))
+}
+
+/// Route the request from `origin` again, to any backend but those in
+/// `tried`.
+fn reroute<T>(proxy: &Proxy<T>, origin: &Origin, tried: &[SocketAddr]) -> Option<Target>
+where
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    let header = from_utf8(&origin.original).ok()?;
+    let host = extract_host(header).ok()?;
+    let uri = extract_uri(header).ok()?;
+    let context = RequestContext {
+        client: origin.info.addr.ip(),
+        header,
+        exclude: tried,
+    };
+    let target = proxy.registry.lookup(&host, uri, &context).ok()?;
+    info!("Retrying {}{} on {}", host, uri, target.addr);
+    Some(target)
+}
+
+/// Whether a request with `method` can be sent twice without harm. The
+/// backend may have acted on a request before its connection broke.
+fn is_safe(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE")
+}
+
+/// Send a request without a body and read the response header. If a pooled
+/// connection turns out to have been closed, a request with a safe method
+/// is sent again on a new connection.
+fn send_request(
+    upstream: TcpStream,
+    pooled: bool,
+    addr: SocketAddr,
+    head: Vec<u8>,
+    method: &str,
+    timeouts: Timeouts,
+) -> IoFuture<(TcpStream, Vec<u8>, usize)> {
+    let retry_head = if pooled && is_safe(method) {
+        Some(head.clone())
+    } else {
+        None
+    };
+    Box::new(
+        write_all(upstream, head)
+            .and_then(move |(upstream, _)| {
+                read_response_head(upstream, Vec::new(), timeouts.first_byte)
+            })
+            .or_else(move |e| match retry_head {
+                Some(head) if is_stale(&e) => Either::B(connect_and_send(addr, head, timeouts)),
+                _ => Either::A(future::err(e)),
+            }),
+    )
+}
+
+/// Send a request and its body, then read the response header. The body is
+/// streamed from the client, so it can't be retried. If the backend doesn't
+/// send a response header, the client is handed back with the error.
+fn send_request_body<S>(
+    upstream: TcpStream,
+    client: S,
+    head: Vec<u8>,
+    leftover: Vec<u8>,
+    request: &RequestInfo,
+    timeouts: Timeouts,
+) -> ExchangeFuture<BodySent<S>, S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    // Clients that send Expect: 100-continue wait for the go-ahead before
+    // sending the body. Give it to them now, since we can't read the
+    // backend's interim response until the body has been sent. Clients
+    // ignore a second 100 Continue.
+    let client = if request.expect_continue && leftover.is_empty() {
+        Either::A(
+            write_all(client, CONTINUE)
+                .map(|(client, _)| client)
+                .map_err(client_error),
+        )
+    } else {
+        Either::B(future::ok(client))
+    };
+    let body = request.body;
+    Box::new(
+        write_all(upstream, head)
+            .join(client)
+            .and_then(move |((upstream, _), client)| {
+                copy_body_idle(client, upstream, body, leftover, timeouts.idle).map_err(|e| {
+                    if is_write_error(&e) {
+                        e
+                    } else {
+                        client_error(e)
+                    }
+                })
+            })
+            .map_err(|e| (e, None))
+            .and_then(move |(client, upstream, client_leftover)| {
+                read_response_head(upstream, Vec::new(), timeouts.first_byte).then(move |result| {
+                    match result {
+                        Ok((upstream, buffer, split)) => {
+                            Ok((client, upstream, buffer, split, client_leftover))
+                        }
+                        Err(e) => Err((e, Some(client))),
+                    }
+                })
+            }),
+    )
+}
+
+/// Forward interim 1xx responses to the client until the final response
+/// header arrives. A client can still be sent an error response after
+/// interim responses, so it's handed back if the final one doesn't arrive.
+fn read_final_response<S>(
+    client: S,
+    upstream: TcpStream,
+    buffer: Vec<u8>,
+    split: usize,
+    method: String,
+    timeout: Duration,
+) -> ExchangeFuture<(S, TcpStream, Vec<u8>, usize, ResponseInfo), S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    Box::new(future::loop_fn(
+        (client, upstream, buffer, split),
+        move |(client, upstream, mut buffer, split)| {
+            let response = match http::parse_response(&buffer[..split], &method) {
+                Ok(response) => response,
+                Err(e) => {
+                    let e = io::Error::new(io::ErrorKind::InvalidData, e);
+                    return Either::A(future::err((e, Some(client))));
+                }
+            };
+            if response.status >= 200 || response.status == 101 {
+                return Either::A(future::ok(Loop::Break((
+                    client, upstream, buffer, split, response,
+                ))));
+            }
+            let rest = buffer.split_off(split);
+            Either::B(
+                write_all(client, buffer)
+                    .map_err(|e| (client_error(e), None))
+                    .and_then(move |(client, _)| {
+                        read_response_head(upstream, rest, timeout).then(move |result| match result
+                        {
+                            Ok((upstream, buffer, split)) => {
+                                Ok(Loop::Continue((client, upstream, buffer, split)))
+                            }
+                            Err(e) => Err((e, Some(client))),
+                        })
+                    }),
+            )
+        },
+    ))
+}
+
+fn forward_request<S, T>(
+    proxy: Arc<Proxy<T>>,
+    client: S,
+    head: Vec<u8>,
+    leftover: Vec<u8>,
+    request: RequestInfo,
+    target: Target,
+    origin: Origin,
+) -> Step<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    if request.upgrade {
+        return tunnel(
+            proxy,
+            client,
+            head,
+            leftover,
+            request.method,
+            target,
+            origin,
+        );
+    }
+
+    let origin = Arc::new(origin);
+    let info = origin.info;
+    // A connection that started with a PROXY header belongs to one client,
+    // so it can't be shared.
+    let use_pool = target.proxy_protocol.is_none();
+    let method = request.method.clone();
+    let outlier_proxy = proxy.clone();
+    let exchange_origin = origin.clone();
+    let exchange = connect_upstream(proxy.clone(), target, origin.clone(), use_pool)
+        .then(move |result| match result {
+            Ok(upstream) => Ok((upstream, client)),
+            Err(e) => Err((e, Some(client))),
+        })
+        .and_then(move |((upstream, pooled, target), client)| {
+            let head = with_proxy_header(&target, info, head);
+            let addr = target.addr;
+            let timeouts = outlier_proxy.timeouts.for_route(&target.timeouts);
+            let sent = if request.body == BodyLength::Length(0) {
+                Either::A(
+                    send_request(upstream, pooled, addr, head, &request.method, timeouts).then(
+                        move |result| match result {
+                            Ok((upstream, buffer, split)) => {
+                                Ok((client, upstream, buffer, split, leftover))
+                            }
+                            Err(e) => Err((e, Some(client))),
+                        },
+                    ),
+                )
+            } else {
+                Either::B(send_request_body(
+                    upstream, client, head, leftover, &request, timeouts,
+                ))
+            };
+            sent.and_then(move |(client, upstream, buffer, split, client_leftover)| {
+                read_final_response(client, upstream, buffer, split, method, timeouts.first_byte)
+                    .map(move |response| (response, client_leftover))
+            })
+            .then(move |result| {
+                // A response is counted once its body has been copied, so
+                // a backend that breaks off its bodies doesn't get credit
+                // for the headers.
+                match &result {
+                    Ok(_) => exchange_origin.meter.response_started(),
+                    Err((e, _)) if is_client_error(e) => (),
+                    Err(_) => outlier_proxy.registry.record_result(&target, false),
+                }
+                result.map(move |(response, client_leftover)| {
+                    (response, client_leftover, target, request)
+                })
+            })
+        });
+
+    let step = exchange.then(move |result| -> Step<S> {
+        let (response, client_leftover, target, request) = match result {
+            Ok(exchange) => exchange,
+            Err((e, client)) => {
+                warn!("Error talking to backend: {:?}", e);
+                return match client {
+                    Some(client) => {
+                        logged_error_response(proxy, client, origin, gateway_status(&e))
+                    }
+                    None => Box::new(future::err(())),
+                };
+            }
+        };
+        let (client, upstream, mut buffer, split, response) = response;
+        let idle = proxy.timeouts.for_route(&target.timeouts).idle;
+        if response.status == 101 {
+            // The backend switched protocols, so whatever follows
+            // isn't HTTP any more.
+            proxy.registry.record_result(&target, true);
+            finish_request(&proxy, &origin, 101);
+            let step = write_all(client, buffer)
+                .join(write_all(upstream, client_leftover))
+                .map_err(|e| debug!("Error: {:?}", e))
+                .and_then(move |((client, _), (upstream, _))| {
+                    let activity = Activity::new();
+                    proxy_connection(
+                        Idle::new(upstream, idle, activity.clone()),
+                        Idle::new(client, idle, activity),
+                    )
+                })
+                .then(move |result| {
+                    drop(target);
+                    result.map(|_| Loop::Break(()))
+                });
+            return Box::new(step);
+        }
+
+        let rest = buffer.split_off(split);
+        let body = response.body;
+        let (finish_proxy, status) = (proxy.clone(), response.status);
+        let step = write_all(client, buffer)
+            .map_err(client_error)
+            .and_then(move |(client, _)| {
+                copy_body_idle(upstream, client, body, rest, idle).map_err(|e| {
+                    if is_write_error(&e) {
+                        client_error(e)
+                    } else {
+                        e
+                    }
+                })
+            })
+            .then(move |result| {
+                // A response that was cut short still counts, with the bytes
+                // that made it.
+                finish_request(&finish_proxy, &origin, status);
+                result
+            })
+            .then(move |result| {
+                let succeeded = match &result {
+                    Ok(_) => true,
+                    Err(e) => is_client_error(e),
+                };
+                proxy
+                    .registry
+                    .record_result(&target, succeeded && status < 500);
+                let (upstream, client, extra) = result.map_err(|e| debug!("Error: {:?}", e))?;
+                if use_pool && response.keep_alive && extra.is_empty() {
+                    proxy.pool.checkin(target.addr, upstream);
+                }
+                drop(target);
+                if request.keep_alive && body != BodyLength::UntilClose {
+                    Ok(Loop::Continue((client, client_leftover)))
+                } else {
+                    Ok(Loop::Break(()))
+                }
+            });
+        Box::new(step)
+    });
+    Box::new(step)
+}
+
+/// Connect the client straight to the backend for the rest of the
+/// connection. This is used for protocol upgrades such as websockets. The
+/// request is logged once the backend's response header has been passed
+/// on, whether or not it agreed to the upgrade.
+fn tunnel<S, T>(
+    proxy: Arc<Proxy<T>>,
+    client: S,
+    head: Vec<u8>,
+    leftover: Vec<u8>,
+    method: String,
+    target: Target,
+    origin: Origin,
+) -> Step<S>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    let origin = Arc::new(origin);
+    let info = origin.info;
+    let step = connect_upstream(proxy.clone(), target, origin.clone(), false).then(
+        move |result| -> Step<S> {
+            let (upstream, _, target) = match result {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    warn!("Failed to connect: {:?}", e);
+                    return logged_error_response(proxy, client, origin, gateway_status(&e));
+                }
+            };
+            let mut head = with_proxy_header(&target, info, head);
+            head.extend(leftover);
+            let timeouts = proxy.timeouts.for_route(&target.timeouts);
+            let response = write_all(upstream, head)
+                .and_then(move |(upstream, _)| {
+                    read_response_head(upstream, Vec::new(), timeouts.first_byte)
+                })
+                .and_then(move |(upstream, buffer, split)| {
+                    let response = http::parse_response(&buffer[..split], &method)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    Ok((upstream, buffer, response.status))
+                });
+            Box::new(response.then(move |result| -> Step<S> {
+                let (upstream, buffer, status) = match result {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("Error talking to backend: {:?}", e);
+                        proxy.registry.record_result(&target, false);
+                        return logged_error_response(proxy, client, origin, gateway_status(&e));
+                    }
+                };
+                origin.meter.response_started();
+                proxy.registry.record_result(&target, status < 500);
+                let step = write_all(client, buffer)
+                    .then(move |result| {
+                        finish_request(&proxy, &origin, status);
+                        result
+                    })
+                    .map_err(|e| debug!("Error: {:?}", e))
+                    .and_then(move |(client, _)| {
+                        let activity = Activity::new();
+                        proxy_connection(
+                            Idle::new(upstream, timeouts.idle, activity.clone()),
+                            Idle::new(client, timeouts.idle, activity),
+                        )
+                    })
+                    .then(move |result| {
+                        drop(target);
+                        result.map(|_| Loop::Break(()))
+                    });
+                Box::new(step)
+            }))
+        },
+    );
+    Box::new(step)
+}
+
+/// Connect to `target`, send it `head`, then copy bytes both ways between
+/// the client and the backend until one of them closes the connection.
+fn splice<S>(
+    client: S,
+    head: Vec<u8>,
+    target: Target,
+    timeouts: Timeouts,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    with_timeout(
+        TcpStream::connect(&target.addr),
+        timeouts.connect,
+        "Connecting",
+    )
+    .map_err(|e| warn!("Failed to connect: {:?}", e))
+    .and_then(move |server_stream| {
+        forward_stream(client, server_stream, head, target, timeouts.idle)
+    })
+}
+
+/// Send `head` to the backend on `server_stream`, then copy bytes both ways
+/// until one side closes the connection or neither moves any data for
+/// `idle`.
+fn forward_stream<S>(
+    client: S,
+    server_stream: TcpStream,
+    head: Vec<u8>,
+    target: Target,
+    idle: Duration,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    write_all(server_stream, head)
+        .map_err(|e| debug!("Error: {:?}", e))
+        .and_then(move |(server_stream, _buf)| {
+            let activity = Activity::new();
+            proxy_connection(
+                Idle::new(server_stream, idle, activity.clone()),
+                Idle::new(client, idle, activity),
+            )
+        })
+        .then(move |result| {
+            drop(target);
+            result
+        })
+}
+
+/// Serve a TLS connection without terminating it. The backend is chosen by
+/// the server name in the client's ClientHello, and the encrypted stream is
+/// passed through to it untouched.
+pub fn serve_passthrough<S, T>(
+    proxy: Arc<Proxy<T>>,
+    client: S,
+    info: ClientInfo,
+) -> impl Future<Item = (), Error = ()> + Send
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    let lifetime = proxy.timeouts.lifetime;
+    let open = proxy.metrics.client_connection();
+    let hello = with_timeout(
+        read_client_hello(client, vec![0; MAX_CLIENT_HELLO]),
+        proxy.timeouts.header_read,
+        "Reading the ClientHello",
+    );
+    let connection = hello
+        .map_err(|e| debug!("Read error: {:?}", e))
+        .and_then(move |(client, mut hello, n)| {
+            hello.truncate(n);
+            let target = route_server_name(&proxy, &hello, info.addr)?;
+            let hello = with_proxy_header(&target, info, hello);
+            let timeouts = proxy.timeouts.for_route(&target.timeouts);
+            Ok(splice(client, hello, target, timeouts))
+        })
+        .flatten();
+    with_lifetime(connection, lifetime).then(move |result| {
+        drop(open);
+        result
+    })
+}
+
+fn route_server_name<T>(
+    proxy: &Proxy<T>,
+    hello: &[u8],
+    client_addr: SocketAddr,
+) -> Result<Target, ()>
+where
+    T: 'static + ServiceProvider + Send + Sync,
+{
+    let host = match server_name(hello) {
+        Ok(Some(host)) => host.to_ascii_lowercase(),
+        Ok(None) => {
+            debug!("Client {} didn't send a server name.", client_addr);
+            return Err(());
+        }
+        Err(e) => {
+            debug!("Error: {}", e);
+            return Err(());
+        }
+    };
+    // There's no request to look at, so only routes for the whole host match.
+    let context = RequestContext {
+        client: client_addr.ip(),
+        header: "",
+        exclude: &[],
+    };
+    let target = proxy
+        .registry
+        .lookup(&host, "/", &context)
+        .map_err(|e| match e {
+            GetHostError::StrErr(estr) => {
+                debug!("Error: {:?}", estr);
+            }
+            GetHostError::PoisonErr(estr) => {
+                error!("Failed to acquire lock: {:?}", estr);
+                panic!();
+            }
+        })?;
+    debug!("Routed {} to {}", host, target.addr);
+    Ok(target)
+}
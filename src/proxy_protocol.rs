@@ -0,0 +1,308 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::{from_utf8, FromStr},
+};
+
+use futures::future::{self, Either, Loop};
+use tokio::{
+    io::{self, read_exact},
+    prelude::*,
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// A v1 header is at most 107 bytes, including the CRLF.
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_PROXY: u8 = 0x21;
+const V2_LOCAL: u8 = 0x20;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// The version of the PROXY protocol to send to a backend.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Version, String> {
+        match s {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            _ => Err(format!("Unknown PROXY protocol version {:?}", s)),
+        }
+    }
+}
+
+/// The source and destination of a proxied connection.
+pub type Addresses = (SocketAddr, SocketAddr);
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Both addresses of a header must be the same family, so if only one of
+// them is IPv6, the other is sent as an IPv4-mapped address.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> Addresses {
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_v6(source), to_v6(destination))
+    }
+}
+
+/// Build the PROXY header that tells a backend the connection it is about
+/// to receive came from `source` to `destination`.
+pub fn header(version: Version, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let (source, destination) = same_family(source, destination);
+    match version {
+        Version::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source.is_ipv4() { "TCP4" } else { "TCP6" },
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        )
+        .into_bytes(),
+        Version::V2 => {
+            let mut addresses = Vec::with_capacity(36);
+            let family = match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    addresses.extend_from_slice(&src.octets());
+                    addresses.extend_from_slice(&dst.octets());
+                    V2_TCP4
+                }
+                (src, dst) => {
+                    let octets = |ip| match ip {
+                        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+                        IpAddr::V6(ip) => ip.octets(),
+                    };
+                    addresses.extend_from_slice(&octets(src));
+                    addresses.extend_from_slice(&octets(dst));
+                    V2_TCP6
+                }
+            };
+            addresses.extend_from_slice(&source.port().to_be_bytes());
+            addresses.extend_from_slice(&destination.port().to_be_bytes());
+
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(V2_PROXY);
+            header.push(family);
+            header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+            header.extend(addresses);
+            header
+        }
+    }
+}
+
+/// Parse a v1 header, including its CRLF. Returns None for `PROXY UNKNOWN`,
+/// which means the connection's own addresses should be used.
+fn parse_v1(line: &[u8]) -> Result<Option<Addresses>, String> {
+    let line = from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| "Malformed PROXY header".to_string())?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.get(1) {
+        Some(&"UNKNOWN") => return Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => (),
+        _ => return Err(format!("Malformed PROXY header {:?}", line)),
+    }
+    let address = |ip: &str, port: &str| -> Result<SocketAddr, String> {
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|e| format!("Bad address {:?}: {}", ip, e))?;
+        let port = port
+            .parse()
+            .map_err(|e| format!("Bad port {:?}: {}", port, e))?;
+        Ok(SocketAddr::new(ip, port))
+    };
+    Ok(Some((
+        address(fields[2], fields[4])?,
+        address(fields[3], fields[5])?,
+    )))
+}
+
+/// Parse the body of a v2 header given its command and family bytes.
+/// Returns None for LOCAL connections, such as health checks from the
+/// proxy itself, and for address families other than TCP.
+fn parse_v2(command: u8, family: u8, body: &[u8]) -> Result<Option<Addresses>, String> {
+    match command {
+        V2_PROXY => (),
+        V2_LOCAL => return Ok(None),
+        _ => return Err(format!("Unknown PROXY v2 command {:#x}", command)),
+    }
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match family {
+        V2_TCP4 if body.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    body[at],
+                    body[at + 1],
+                    body[at + 2],
+                    body[at + 3],
+                ))
+            };
+            Ok(Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            )))
+        }
+        V2_TCP6 if body.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&body[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Ok(Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            )))
+        }
+        V2_TCP4 | V2_TCP6 => Err("PROXY v2 addresses are truncated".to_string()),
+        _ => Ok(None),
+    }
+}
+
+type ReadHeader<A> = Box<dyn Future<Item = (A, Option<Addresses>), Error = io::Error> + Send>;
+
+/// Read a v1 or v2 PROXY header from the start of `stream`. Nothing past
+/// the end of the header is read, so the stream can be handed on as it is,
+/// e.g. to a TLS handshake. Resolves to the stream and the addresses from
+/// the header, or None if the header doesn't give any.
+pub fn read_proxy_header<A>(stream: A) -> ReadHeader<A>
+where
+    A: AsyncRead + Send + 'static,
+{
+    // The shortest v1 header, "PROXY UNKNOWN\r\n", is longer than the v2
+    // signature.
+    let header = read_exact(stream, vec![0; V2_SIGNATURE.len()]).and_then(
+        |(stream, start)| -> ReadHeader<A> {
+            if start == V2_SIGNATURE {
+                Box::new(read_v2(stream))
+            } else if start.starts_with(V1_PREFIX) {
+                Box::new(read_v1(stream, start))
+            } else {
+                Box::new(future::err(invalid(
+                    "connection doesn't start with a PROXY header",
+                )))
+            }
+        },
+    );
+    Box::new(header)
+}
+
+fn read_v1<A>(
+    stream: A,
+    start: Vec<u8>,
+) -> impl Future<Item = (A, Option<Addresses>), Error = io::Error>
+where
+    A: AsyncRead,
+{
+    // Read a byte at a time so that nothing past the CRLF is consumed.
+    future::loop_fn((stream, start), |(stream, mut line)| {
+        if line.ends_with(b"\r\n") {
+            let addresses = parse_v1(&line).map_err(|e| invalid(&e));
+            return Either::A(future::result(addresses.map(|a| Loop::Break((stream, a)))));
+        }
+        if line.len() >= V1_MAX {
+            return Either::A(future::err(invalid("PROXY header exceeded max length")));
+        }
+        Either::B(read_exact(stream, [0; 1]).map(move |(stream, byte)| {
+            line.push(byte[0]);
+            Loop::Continue((stream, line))
+        }))
+    })
+}
+
+fn read_v2<A>(stream: A) -> impl Future<Item = (A, Option<Addresses>), Error = io::Error>
+where
+    A: AsyncRead,
+{
+    read_exact(stream, [0; 4])
+        .and_then(|(stream, fields)| {
+            let length = u16::from_be_bytes([fields[2], fields[3]]) as usize;
+            read_exact(stream, vec![0; length]).map(move |(stream, body)| (stream, fields, body))
+        })
+        .and_then(|(stream, fields, body)| {
+            let addresses = parse_v2(fields[0], fields[1], &body).map_err(|e| invalid(&e))?;
+            Ok((stream, addresses))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn addresses(source: &str, destination: &str) -> Addresses {
+        (source.parse().unwrap(), destination.parse().unwrap())
+    }
+
+    fn read(data: &[u8]) -> Result<(Option<Addresses>, Vec<u8>), io::Error> {
+        let (stream, addresses) = read_proxy_header(Cursor::new(data.to_vec())).wait()?;
+        let rest = stream.get_ref()[stream.position() as usize..].to_vec();
+        Ok((addresses, rest))
+    }
+
+    #[test]
+    fn test_header() {
+        let (source, destination) = addresses("1.2.3.4:5678", "10.0.0.1:443");
+        assert_eq!(
+            header(Version::V1, source, destination),
+            b"PROXY TCP4 1.2.3.4 10.0.0.1 5678 443\r\n".to_vec()
+        );
+        let v2 = header(Version::V2, source, destination);
+        assert_eq!(&v2[..12], V2_SIGNATURE);
+        assert_eq!(&v2[12..16], &[0x21, 0x11, 0, 12]);
+        assert_eq!(&v2[16..], &[1, 2, 3, 4, 10, 0, 0, 1, 0x16, 0x2e, 1, 0xbb]);
+
+        let (source, destination) = addresses("1.2.3.4:5678", "[2001:db8::1]:443");
+        assert_eq!(
+            header(Version::V1, source, destination),
+            b"PROXY TCP6 ::ffff:1.2.3.4 2001:db8::1 5678 443\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_read_proxy_header() {
+        let expected = addresses("1.2.3.4:5678", "10.0.0.1:443");
+        for version in &[Version::V1, Version::V2] {
+            let mut data = header(*version, expected.0, expected.1);
+            data.extend_from_slice(b"GET / HTTP/1.1\r\n");
+            let (found, rest) = read(&data).unwrap();
+            assert_eq!(found, Some(expected));
+            assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+        }
+
+        let v6 = addresses("[2001:db8::1]:1000", "[2001:db8::2]:80");
+        let (found, _) = read(&header(Version::V2, v6.0, v6.1)).unwrap();
+        assert_eq!(found, Some(v6));
+
+        assert_eq!(
+            read(b"PROXY UNKNOWN\r\nGET").unwrap(),
+            (None, b"GET".to_vec())
+        );
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[V2_LOCAL, 0, 0, 0]);
+        assert_eq!(read(&local).unwrap().0, None);
+
+        let err = |data: &[u8]| read(data).unwrap_err().kind();
+        assert_eq!(err(b"GET / HTTP/1.1\r\n\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(err(b"PROXY TCP4 1.2.3.4\r\n"), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err(b"PROXY TCP4 1.2.3.4 10.0.0.1 5678 443"),
+            io::ErrorKind::UnexpectedEof
+        );
+        let long = format!("PROXY {}\r\n", "a".repeat(200));
+        assert_eq!(err(long.as_bytes()), io::ErrorKind::InvalidData);
+    }
+}
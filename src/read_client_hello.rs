@@ -0,0 +1,303 @@
+use std::mem;
+
+use futures::*;
+use tokio::{io, prelude::*};
+
+const RECORD_HEADER: usize = 5;
+const CONTENT_HANDSHAKE: u8 = 22;
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+pub struct ReadClientHello<A, T> {
+    state: State<A, T>,
+}
+
+enum State<A, T> {
+    Reading { stream: A, buf: T, pos: usize },
+    Empty,
+}
+
+fn eof() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "early eof")
+}
+
+fn too_big() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "TLS ClientHello exceeded max length",
+    )
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reassembles the first handshake message from the TLS records at the start
+/// of `buf`. Returns None if more records are needed. A ClientHello is
+/// usually sent in one record, but it may be split across several.
+fn handshake_message(buf: &[u8]) -> Result<Option<Vec<u8>>, io::Error> {
+    let mut message = Vec::new();
+    let mut pos = 0;
+    while buf.len() >= pos + RECORD_HEADER {
+        if buf[pos] != CONTENT_HANDSHAKE {
+            return Err(invalid("connection doesn't start with a TLS handshake"));
+        }
+        let length = (buf[pos + 3] as usize) << 8 | buf[pos + 4] as usize;
+        let start = pos + RECORD_HEADER;
+        if buf.len() < start + length {
+            return Ok(None);
+        }
+        message.extend_from_slice(&buf[start..start + length]);
+        pos = start + length;
+
+        if message.len() >= 4 {
+            if message[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(invalid("first TLS handshake message isn't a ClientHello"));
+            }
+            let length =
+                (message[1] as usize) << 16 | (message[2] as usize) << 8 | message[3] as usize;
+            if message.len() >= 4 + length {
+                message.truncate(4 + length);
+                return Ok(Some(message));
+            }
+        }
+    }
+    Ok(None)
+}
+
+impl<A, T> Future for ReadClientHello<A, T>
+where
+    A: AsyncRead,
+    T: AsMut<[u8]>,
+{
+    type Item = (A, T, usize);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, io::Error> {
+        match self.state {
+            State::Reading {
+                ref mut stream,
+                ref mut buf,
+                ref mut pos,
+            } => {
+                let buf = buf.as_mut();
+                loop {
+                    if *pos == buf.len() {
+                        return Err(too_big());
+                    }
+                    let n = try_ready!({ stream.poll_read(&mut buf[*pos..]) });
+                    *pos += n;
+
+                    if handshake_message(&buf[..*pos])?.is_some() {
+                        break;
+                    }
+
+                    if n == 0 {
+                        return Err(eof());
+                    }
+                }
+            }
+            State::Empty => panic!("poll a ReadClientHello after it's done"),
+        }
+
+        match mem::replace(&mut self.state, State::Empty) {
+            State::Reading { stream, buf, pos } => Ok(Async::Ready((stream, buf, pos))),
+            State::Empty => panic!(),
+        }
+    }
+}
+
+/// Read from `stream` into `buf` until the client's TLS ClientHello has been
+/// read. The future resolves to the stream, the buffer, and the number of
+/// bytes in the buffer. Nothing is decrypted or consumed; all of the bytes
+/// read must be forwarded to the backend.
+pub fn read_client_hello<A, T>(stream: A, buf: T) -> ReadClientHello<A, T>
+where
+    A: AsyncRead,
+    T: AsMut<[u8]>,
+{
+    ReadClientHello {
+        state: State::Reading {
+            stream,
+            buf,
+            pos: 0,
+        },
+    }
+}
+
+/// A cursor over a ClientHello that fails on truncated input.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ()> {
+        if self.buf.len() < n {
+            return Err(());
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ()> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ()> {
+        let bytes = self.take(2)?;
+        Ok(u16::from(bytes[0]) << 8 | u16::from(bytes[1]))
+    }
+
+    // Take a vector whose length is given by a 1 or 2 byte prefix.
+    fn vec8(&mut self) -> Result<Reader<'a>, ()> {
+        let length = self.u8()? as usize;
+        Ok(Reader {
+            buf: self.take(length)?,
+        })
+    }
+
+    fn vec16(&mut self) -> Result<Reader<'a>, ()> {
+        let length = self.u16()? as usize;
+        Ok(Reader {
+            buf: self.take(length)?,
+        })
+    }
+}
+
+fn find_server_name(hello: &[u8]) -> Result<Option<&[u8]>, ()> {
+    // Skip the handshake header, client version and random.
+    let mut hello = Reader { buf: hello };
+    hello.take(4 + 2 + 32)?;
+    hello.vec8()?; // session id
+    hello.vec16()?; // cipher suites
+    hello.vec8()?; // compression methods
+    if hello.buf.is_empty() {
+        // No extensions at all.
+        return Ok(None);
+    }
+    let mut extensions = hello.vec16()?;
+    while !extensions.buf.is_empty() {
+        let extension_type = extensions.u16()?;
+        let mut extension = extensions.vec16()?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = extension.vec16()?;
+        while !names.buf.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                return Ok(Some(name.buf));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the server name from the SNI extension of the ClientHello at the
+/// start of `buf`, or None if the client didn't send one.
+pub fn server_name(buf: &[u8]) -> Result<Option<String>, String> {
+    let hello = handshake_message(buf)
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| "Incomplete ClientHello".to_string())?;
+    let name = find_server_name(&hello).map_err(|_| "Malformed ClientHello".to_string())?;
+    match name {
+        Some(name) => String::from_utf8(name.to_vec())
+            .map(Some)
+            .map_err(|_| "Server name isn't valid utf-8".to_string()),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // Build a ClientHello with the given extensions, split into records of
+    // at most `record_size` bytes.
+    pub fn client_hello(extensions: &[u8], record_size: usize) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        body.extend_from_slice(&[0]); // session id
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]); // cipher suites
+        body.extend_from_slice(&[1, 0]); // compression methods
+        body.extend_from_slice(&[(extensions.len() >> 8) as u8, extensions.len() as u8]);
+        body.extend_from_slice(extensions);
+
+        let mut message = vec![HANDSHAKE_CLIENT_HELLO, 0];
+        message.extend_from_slice(&[(body.len() >> 8) as u8, body.len() as u8]);
+        message.extend(body);
+
+        let mut records = Vec::new();
+        for fragment in message.chunks(record_size) {
+            records.extend_from_slice(&[CONTENT_HANDSHAKE, 3, 1]);
+            records.extend_from_slice(&[(fragment.len() >> 8) as u8, fragment.len() as u8]);
+            records.extend_from_slice(fragment);
+        }
+        records
+    }
+
+    pub fn sni(name: &str) -> Vec<u8> {
+        let name = name.as_bytes();
+        let list_len = name.len() + 3;
+        let mut extension = vec![0, 0];
+        extension.extend_from_slice(&[((list_len + 2) >> 8) as u8, (list_len + 2) as u8]);
+        extension.extend_from_slice(&[(list_len >> 8) as u8, list_len as u8]);
+        extension.extend_from_slice(&[
+            NAME_TYPE_HOST_NAME,
+            (name.len() >> 8) as u8,
+            name.len() as u8,
+        ]);
+        extension.extend_from_slice(name);
+        extension
+    }
+
+    fn read(data: Vec<u8>) -> Result<(Vec<u8>, usize), io::Error> {
+        read_client_hello(Cursor::new(data), vec![0; 1024])
+            .wait()
+            .map(|(_, buf, n)| (buf, n))
+    }
+
+    #[test]
+    fn test_server_name() {
+        // Put another extension first to make sure it's skipped.
+        let mut extensions = vec![0, 10, 0, 2, 0, 0];
+        extensions.extend(sni("example.com"));
+        let hello = client_hello(&extensions, 16384);
+        assert_eq!(server_name(&hello), Ok(Some("example.com".to_string())));
+
+        let hello = client_hello(&sni("foo.example.com"), 20);
+        assert_eq!(server_name(&hello), Ok(Some("foo.example.com".to_string())));
+
+        let hello = client_hello(&[], 16384);
+        assert_eq!(server_name(&hello), Ok(None));
+
+        let hello = client_hello(&sni("example.com"), 16384);
+        assert!(server_name(&hello[..hello.len() - 1]).is_err());
+        assert!(server_name(b"GET / HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_read_client_hello() {
+        let hello = client_hello(&sni("example.com"), 30);
+        let mut data = hello.clone();
+        data.extend_from_slice(b"more");
+        let (buf, n) = read(data).unwrap();
+        assert!(n >= hello.len());
+        assert_eq!(&buf[..hello.len()], &hello[..]);
+
+        assert_eq!(
+            read(hello[..hello.len() - 1].to_vec()).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            read(b"GET / HTTP/1.1\r\n\r\n".to_vec()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let big = client_hello(&sni(&"a".repeat(2000)), 16384);
+        assert_eq!(read(big).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,11 +1,12 @@
-use std::mem;
+use std::{mem, time::Instant};
 
 use futures::*;
 use regex::bytes::Regex;
-use tokio::{io, prelude::*};
+use tokio::{io, prelude::*, timer::Delay};
 
 pub struct ReadHttpHeader<A, T> {
     state: State<A, T>,
+    deadline: Option<Delay>,
 }
 
 enum State<A, T> {
@@ -22,11 +23,13 @@ fn eof() -> io::Error {
     io::Error::new(io::ErrorKind::UnexpectedEof, "early eof")
 }
 
-fn too_big() -> io::Error {
-    io::Error::new(
-        io::ErrorKind::InvalidData,
-        "HTTP header exceeded max length",
-    )
+/// Returns the offset just past the blank line that ends the header, if
+/// `buf` contains one.
+fn header_end(buf: &[u8]) -> Option<usize> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\r\n\r\n").unwrap();
+    }
+    RE.find(buf).map(|end_match| end_match.end())
 }
 
 impl<A, T> Future for ReadHttpHeader<A, T>
@@ -46,13 +49,23 @@ where
                 ref mut split,
             } => {
                 let buf = buf.as_mut();
-                while *pos < buf.len() {
-                    let n = try_ready!({ stream.poll_read(&mut buf[*pos..]) });
+                while *split == 0 && *pos < buf.len() {
+                    let n = match stream.poll_read(&mut buf[*pos..])? {
+                        Async::Ready(n) => n,
+                        Async::NotReady => {
+                            let expired = match self.deadline {
+                                Some(ref mut deadline) => {
+                                    deadline.poll().map_err(io::Error::other)?.is_ready()
+                                }
+                                None => false,
+                            };
+                            if expired {
+                                break;
+                            }
+                            return Ok(Async::NotReady);
+                        }
+                    };
 
-                    //let re = Regex::new(r"\r\n\r\n").unwrap();
-                    lazy_static! {
-                        static ref RE: Regex = Regex::new(r"\r\n\r\n").unwrap();
-                    }
                     // We want to backup enough to find the end sequence in case
                     // part of the end sequence came in previously.
                     let mut backup = *pos;
@@ -64,10 +77,8 @@ where
 
                     *pos += n;
 
-                    let caps = RE.captures(&buf[backup..*pos]);
-                    if let Some(captures) = caps {
-                        let end_match = captures.get(0).unwrap();
-                        *split = backup + end_match.end();
+                    if let Some(end) = header_end(&buf[backup..*pos]) {
+                        *split = backup + end;
                         break;
                     }
 
@@ -75,9 +86,6 @@ where
                         return Err(eof());
                     }
                 }
-                if *pos == buf.len() {
-                    return Err(too_big());
-                }
             }
             State::Empty => panic!("poll a ReadHttpHeader after it's done"),
         }
@@ -94,17 +102,35 @@ where
     }
 }
 
-pub fn read_http_header<A, T>(stream: A, buf: T) -> ReadHttpHeader<A, T>
+/// Read from `stream` into `buf` until a complete HTTP header has been read.
+/// The first `pos` bytes of `buf` are data that was already read, e.g. the
+/// start of a pipelined request that arrived with the previous one. The
+/// future resolves to the stream, the buffer, the number of bytes in the
+/// buffer, and the length of the header. If the buffer fills up before the
+/// end of the header, the length of the header is 0. The same goes for a
+/// deadline set with `with_deadline` passing, but then the buffer isn't
+/// full.
+pub fn read_http_header<A, T>(stream: A, mut buf: T, pos: usize) -> ReadHttpHeader<A, T>
 where
     A: AsyncRead,
     T: AsMut<[u8]>,
 {
+    let split = header_end(&buf.as_mut()[..pos]).unwrap_or(0);
     ReadHttpHeader {
         state: State::Reading {
-            stream: stream,
-            buf: buf,
-            pos: 0,
-            split: 0,
+            stream,
+            buf,
+            pos,
+            split,
         },
+        deadline: None,
+    }
+}
+
+impl<A, T> ReadHttpHeader<A, T> {
+    /// Stop waiting for the rest of the header at `deadline`.
+    pub fn with_deadline(mut self, deadline: Instant) -> ReadHttpHeader<A, T> {
+        self.deadline = Some(Delay::new(deadline));
+        self
     }
 }
@@ -1,13 +1,255 @@
-use std::{collections::HashMap, net::SocketAddr, sync::RwLock};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
-use rand::{seq::SliceRandom, thread_rng};
+use crate::{
+    balancer::{LoadBalancer, Policy, RequestContext},
+    consul::ConsulClient,
+    health::{Clients, HealthCheck, HealthState},
+    outlier::{OutlierDetection, OutlierState},
+    proxy_protocol::Version,
+    timeout::RouteTimeouts,
+};
 
-use knusbaum_consul;
+/// The aggregate state of an instance's health checks. An instance is as
+/// healthy as its worst check.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HealthStatus {
+    Passing,
+    Warning,
+    Critical,
+}
+
+/// A single instance of a service, as reported by a ServiceProvider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceNode {
+    pub address: String,
+    pub port: u16,
+    pub tags: Vec<String>,
+    pub status: HealthStatus,
+}
 
 #[derive(Debug)]
 pub struct AddressPort {
     pub address: String,
     pub port: u16,
+    // The name of the source that reported it.
+    pub source: String,
+    pub status: HealthStatus,
+    // From the `weight=` tag option. Only used by weighted load balancing.
+    pub weight: u32,
+    // From the `proxyproto=` tag option. Connections to this instance start
+    // with a PROXY header in this version.
+    pub proxy_protocol: Option<Version>,
+    // From the `health=` tag options, or the default health check.
+    pub health_check: Option<HealthCheck>,
+    // From the `connect_timeout=`, `response_timeout=` and `idle_timeout=`
+    // tag options.
+    pub timeouts: RouteTimeouts,
+    pub state: Arc<BackendState>,
+}
+
+impl AddressPort {
+    pub fn active_connections(&self) -> usize {
+        self.state.active.load(Ordering::SeqCst)
+    }
+
+    /// Whether this backend was recently marked down with Target::mark_down.
+    pub fn is_down(&self) -> bool {
+        match *self.state.down_until.lock().unwrap() {
+            Some(until) => Instant::now() < until,
+            None => false,
+        }
+    }
+
+    /// The socket address of this backend.
+    pub fn addr(&self) -> Result<SocketAddr, GetHostError> {
+        format!("{}:{}", self.address, self.port)
+            .parse()
+            .map_err(|e| GetHostError::StrErr(format!("Failed to parse address: {:?}", e)))
+    }
+
+    /// Count a new connection to this backend. The connection is counted
+    /// until the returned Target is dropped.
+    pub fn connect(&self) -> Result<Target, GetHostError> {
+        let addr = self.addr()?;
+        self.state.active.fetch_add(1, Ordering::SeqCst);
+        Ok(Target {
+            addr,
+            proxy_protocol: self.proxy_protocol,
+            timeouts: self.timeouts,
+            route: String::new(),
+            state: self.state.clone(),
+        })
+    }
+}
+
+/// BackendState is shared by every AddressPort for the same address and
+/// port, and survives routing table rebuilds for as long as any route or
+/// connection refers to it.
+#[derive(Debug, Default)]
+pub struct BackendState {
+    active: AtomicUsize,
+    // Until when the backend is skipped after a failure.
+    down_until: Mutex<Option<Instant>>,
+    outlier: OutlierState,
+    health: HealthState,
+}
+
+/// The backend chosen for a request.
+pub struct Target {
+    pub addr: SocketAddr,
+    pub proxy_protocol: Option<Version>,
+    pub timeouts: RouteTimeouts,
+    // The host and path of the route it was chosen for, e.g.
+    // `example.com/api`.
+    pub route: String,
+    state: Arc<BackendState>,
+}
+
+impl Target {
+    /// Skip this backend for `duration` when there are others to choose.
+    pub fn mark_down(&self, duration: Duration) {
+        *self.state.down_until.lock().unwrap() = Some(Instant::now() + duration);
+    }
+}
+
+impl Drop for Target {
+    fn drop(&mut self) {
+        self.state.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// When the routing table was last changed, how long it took, and why it
+/// failed if it did.
+#[derive(Debug, Clone)]
+pub struct UpdateStatus {
+    pub at: SystemTime,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+/// The state of one backend, for metrics.
+#[derive(Debug)]
+pub struct BackendStats {
+    pub addr: String,
+    pub active: usize,
+    // Whether its last health check passed, if it has been checked.
+    pub health_check: Option<bool>,
+}
+
+/// One instance of a route, for the admin API.
+#[derive(Debug)]
+pub struct BackendInfo {
+    pub addr: String,
+    pub source: String,
+    pub status: HealthStatus,
+    pub weight: u32,
+    pub active: usize,
+    // The outcome of its last health check, if it has been checked.
+    pub health_check: Option<Result<(), String>>,
+    // Whether it's ejected by outlier detection, or was marked down after
+    // a failed connection.
+    pub ejected: bool,
+    pub marked_down: bool,
+}
+
+/// A route in the routing table, for the admin API.
+#[derive(Debug)]
+pub struct RouteInfo {
+    // The host the route was registered for, e.g. `example.com`,
+    // `*.example.com`, or empty for routes without a host.
+    pub host: String,
+    pub path: String,
+    pub policy: Policy,
+    pub backends: Vec<BackendInfo>,
+}
+
+/// The registry's defaults, which can be changed while it runs.
+#[derive(Debug, Clone)]
+pub struct RegistrySettings {
+    // Whether instances with checks in the warning state receive traffic.
+    // Instances with critical checks never do.
+    pub include_warning: bool,
+    // The load balancing policy for routes without an `lb=` tag option.
+    pub policy: Policy,
+    // When backends that keep failing are ejected from load balancing.
+    pub outlier: OutlierDetection,
+    // The health check for routes without `health=` tag options. It's
+    // disabled if its path is empty.
+    pub health_check: HealthCheck,
+}
+
+impl RegistrySettings {
+    fn is_routable(&self, status: HealthStatus) -> bool {
+        match status {
+            HealthStatus::Passing => true,
+            HealthStatus::Warning => self.include_warning,
+            HealthStatus::Critical => false,
+        }
+    }
+}
+
+/// A summary of the routing table, for metrics.
+#[derive(Debug)]
+pub struct RegistryStats {
+    pub routes: usize,
+    pub backends: Vec<BackendStats>,
+    pub last_update: Option<UpdateStatus>,
+    pub sources: Vec<SourceStatus>,
+}
+
+/// The name of the source for routes from the static route file.
+pub const STATIC_SOURCE: &str = "static";
+
+// How many health checks can run at once.
+const HEALTH_CHECK_THREADS: usize = 8;
+
+/// How the queries to one source of routes have been going, for the admin
+/// API and metrics.
+#[derive(Debug, Clone)]
+pub struct SourceStatus {
+    pub name: String,
+    // Routes in the routing table with a backend from this source.
+    pub routes: usize,
+    pub last_success: Option<SystemTime>,
+    // Why the last query for its services failed, if it did. The source's
+    // last good routes are kept until a query succeeds again.
+    pub error: Option<String>,
+}
+
+/// The outcome of the latest query to a source.
+#[derive(Debug, Clone, Default)]
+struct QueryStatus {
+    last_success: Option<SystemTime>,
+    error: Option<String>,
+}
+
+impl QueryStatus {
+    fn record(&mut self, result: Result<(), String>) {
+        match result {
+            Ok(()) => {
+                self.last_success = Some(SystemTime::now());
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+}
+
+/// A named provider the registry reads routes from, e.g. one Consul
+/// datacenter.
+struct Source<T> {
+    name: String,
+    client: T,
+    status: Mutex<QueryStatus>,
 }
 
 #[derive(Debug)]
@@ -16,139 +258,925 @@ pub enum GetHostError {
     StrErr(String),
 }
 
-// This trait is only here for use in tests right now.
+/// The result of a blocking query along with the index to pass to the next
+/// query so that it blocks until the result changes.
+pub struct Indexed<T> {
+    pub index: u64,
+    pub value: T,
+}
+
 // In the future, I would like to make this trait more generic to allow
 // for other ServiceProviders to be used with the proxy.
+//
+// An index of 0 asks for the current state immediately. Any other index
+// should block until the state is newer than that index or the provider's
+// wait time runs out.
 pub trait ServiceProvider {
-    fn services(&self) -> Result<HashMap<String, Vec<String>>, String>;
-    fn get_nodes(&self, service: String) -> Result<Vec<knusbaum_consul::ServiceNode>, String>;
+    fn services(&self, index: u64) -> Result<Indexed<HashMap<String, Vec<String>>>, String>;
+    fn get_nodes(&self, service: &str, index: u64) -> Result<Indexed<Vec<ServiceNode>>, String>;
 }
 
-impl ServiceProvider for knusbaum_consul::Client {
-    fn services(&self) -> Result<HashMap<String, Vec<String>>, String> {
-        self.catalog.services()
+/// Consul may reset its index, e.g. after a snapshot restore. When the index
+/// goes backwards the next query has to start over from 0.
+pub fn next_index(old: u64, new: u64) -> u64 {
+    if new < old {
+        0
+    } else {
+        new
     }
+}
 
-    fn get_nodes(&self, service: String) -> Result<Vec<knusbaum_consul::ServiceNode>, String> {
-        self.catalog.get_nodes(service)
+/// Check the health of the backend at `addr` and record the outcome in
+/// `state`, logging when it starts or stops passing.
+fn run_check(clients: &Clients, addr: SocketAddr, check: &HealthCheck, state: &BackendState) {
+    let outcome = check.probe(clients, addr);
+    let failure = outcome.as_ref().err().cloned();
+    let passed_before = state.health.record(outcome);
+    match failure {
+        Some(e) if passed_before != Some(false) => warn!("Health check of {} failed: {}", addr, e),
+        None if passed_before == Some(false) => {
+            info!("Health check of {} is passing again", addr)
+        }
+        _ => (),
     }
 }
 
-#[derive(Debug)]
-pub struct ServiceRegistry<T: ServiceProvider = knusbaum_consul::Client> {
-    services: RwLock<HashMap<String, Vec<AddressPort>>>,
-    client: T,
+// The policy and balancer of each route, keyed by host and path.
+type Balancers = HashMap<String, (Policy, Weak<dyn LoadBalancer>)>;
+
+/// A Route maps requests whose URI begins with `path` to a set of
+/// addresses. Routes for a host are kept ordered by descending path length
+/// so that the longest matching prefix wins.
+pub struct Route {
+    pub path: String,
+    pub addresses: Vec<AddressPort>,
+    pub policy: Policy,
+    balancer: Arc<dyn LoadBalancer>,
+}
+
+impl Route {
+    fn new(path: String, policy: &Policy) -> Route {
+        Route {
+            path,
+            addresses: Vec::new(),
+            policy: policy.clone(),
+            balancer: policy.balancer(),
+        }
+    }
+
+    fn set_policy(&mut self, policy: &Policy) {
+        if *policy != self.policy {
+            self.policy = policy.clone();
+            self.balancer = policy.balancer();
+        }
+    }
+}
+
+/// Find the route for a request to `host` with request URI `uri`, along
+/// with the host it's registered for. Exact host matches are tried first,
+/// then wildcard hosts from most to least specific, and finally routes that
+/// were registered without a host. A wildcard like `*.example.com` only
+/// matches subdomains, while `*example.com` matches the domain too.
+fn find_route<'a>(
+    services: &'a HashMap<String, Vec<Route>>,
+    host: &str,
+    uri: &str,
+) -> Option<(&'a str, &'a Route)> {
+    let parts: Vec<&str> = host.split('.').collect();
+    let wildcards = (0..parts.len()).flat_map(|i| {
+        let suffix = parts[i..].join(".");
+        let subdomains = if i > 0 {
+            Some(format!("*.{}", suffix))
+        } else {
+            None
+        };
+        subdomains.into_iter().chain(Some(format!("*{}", suffix)))
+    });
+    let hosts = std::iter::once(host.to_string())
+        .chain(wildcards)
+        .chain(std::iter::once(String::new()));
+    for try_host in hosts {
+        if let Some((route_host, routes)) = services.get_key_value(&try_host) {
+            if let Some(route) = routes.iter().find(|route| uri.starts_with(&route.path)) {
+                return Some((route_host, route));
+            }
+        }
+    }
+    None
+}
+
+fn route_info(host: &str, route: &Route) -> RouteInfo {
+    RouteInfo {
+        host: host.to_string(),
+        path: route.path.clone(),
+        policy: route.policy.clone(),
+        backends: route
+            .addresses
+            .iter()
+            .map(|address| BackendInfo {
+                addr: format!("{}:{}", address.address, address.port),
+                source: address.source.clone(),
+                status: address.status,
+                weight: address.weight,
+                active: address.active_connections(),
+                health_check: address.state.health.last_result(),
+                ejected: address.state.outlier.ejected_until().is_some(),
+                marked_down: address.is_down(),
+            })
+            .collect(),
+    }
+}
+
+/// A parsed fabio-style `urlprefix-host/path opt=value ...` tag.
+#[derive(Debug, PartialEq)]
+struct PrefixTag {
+    host: String,
+    path: String,
+    options: HashMap<String, String>,
+}
+
+pub struct ServiceRegistry<T: ServiceProvider = ConsulClient> {
+    services: RwLock<HashMap<String, Vec<Route>>>,
+    // The most recent nodes seen for each service of each source, in the
+    // order of `sources`. The routing table is rebuilt from these whenever
+    // a single service changes.
+    nodes: Mutex<Vec<HashMap<String, Vec<ServiceNode>>>>,
+    // State for every backend that is routed to or has open connections,
+    // keyed by "source address:port".
+    backends: Mutex<HashMap<String, Weak<BackendState>>>,
+    // Kept so a route keeps its place in e.g. a round robin when the
+    // routing table is rebuilt.
+    balancers: Mutex<Balancers>,
+    sources: Vec<Source<T>>,
+    // Nodes for the routes in the static route file, which take precedence
+    // over the providers'.
+    static_nodes: Mutex<Vec<ServiceNode>>,
+    // None unless a static route file is used.
+    static_status: Mutex<Option<QueryStatus>>,
+    // Replaced as a whole when the config is reloaded.
+    settings: RwLock<Arc<RegistrySettings>>,
+    last_update: Mutex<Option<UpdateStatus>>,
 }
 
 impl<T: ServiceProvider> ServiceRegistry<T> {
-    pub fn new() -> ServiceRegistry<knusbaum_consul::Client> {
+    /// A registry that merges the routes of every named provider in
+    /// `sources`. Routes with the same host and path from different sources
+    /// share their backends.
+    pub fn new(sources: Vec<(String, T)>, settings: RegistrySettings) -> ServiceRegistry<T> {
         ServiceRegistry {
             services: RwLock::new(HashMap::new()),
-            client: knusbaum_consul::Client::new("http://127.0.0.1:8500"),
+            nodes: Mutex::new(sources.iter().map(|_| HashMap::new()).collect()),
+            backends: Mutex::new(HashMap::new()),
+            balancers: Mutex::new(HashMap::new()),
+            sources: sources
+                .into_iter()
+                .map(|(name, client)| Source {
+                    name,
+                    client,
+                    status: Mutex::new(QueryStatus::default()),
+                })
+                .collect(),
+            static_nodes: Mutex::new(Vec::new()),
+            static_status: Mutex::new(None),
+            settings: RwLock::new(Arc::new(settings)),
+            last_update: Mutex::new(None),
         }
     }
 
-    pub fn update(&self) -> Result<(), String> {
-        let new_map = self.pull_consul_routes()?;
-        let mut locked = self.services.write().map_err(|e| format!("{:?}", e))?;
-        *locked = new_map;
+    fn settings(&self) -> Arc<RegistrySettings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Switch to new settings and rebuild the routing table from the nodes
+    /// already known, so that the defaults for routes change right away.
+    /// Backends keep their connection counts, ejections and health checks.
+    /// If the routes can't be rebuilt, the old settings are kept.
+    pub fn reconfigure(&self, settings: RegistrySettings) -> Result<(), String> {
+        let nodes = self.nodes.lock().map_err(|e| format!("{:?}", e))?;
+        let new_map = self.routes_from_nodes(&nodes, &settings)?;
+        // Lookups read the settings while holding the routing table, so the
+        // table is locked first.
+        let mut services = self.services.write().map_err(|e| format!("{:?}", e))?;
+        let mut current = self.settings.write().map_err(|e| format!("{:?}", e))?;
+        *current = Arc::new(settings);
+        *services = new_map;
         Ok(())
     }
 
-    pub fn lookup(&self, host: &str) -> Result<SocketAddr, GetHostError> {
-        let address = self.address_for_host(host);
-        if address.is_ok() {
-            return address;
+    /// Count the outcome of a request to `target` for outlier detection.
+    /// Connect failures, resets and 5xx responses are failures.
+    pub fn record_result(&self, target: &Target, success: bool) {
+        let outlier = &self.settings().outlier;
+        if success {
+            target.state.outlier.success(outlier);
+        } else if let Some(time) = target.state.outlier.failure(outlier) {
+            warn!(
+                "Ejecting {} for {:?} after {} consecutive failures",
+                target.addr, time, outlier.consecutive_failures
+            );
         }
+    }
 
-        // This is gross. Need to replace this with true globbing.
-        let parts: Vec<&str> = host.split(".").collect();
-        let mut partslice = &parts[..];
-        while partslice.len() > 0 {
-            let tryhost = format!("*{}", partslice.join("."));
-            let address = self.address_for_host(&tryhost);
-            if address.is_ok() {
-                return address;
+    /// Fetch every service from every source and replace the routing
+    /// table. A source that fails keeps the routes it had, and this only
+    /// fails if every source does.
+    pub fn update(&self) -> Result<(), String> {
+        self.timed_update(|| {
+            let pulled: Vec<_> = (0..self.sources.len())
+                .map(|source| self.pull_nodes(source))
+                .collect();
+            let mut nodes = self.nodes.lock().map_err(|e| format!("{:?}", e))?;
+            let mut errors = Vec::new();
+            for (source, result) in pulled.into_iter().enumerate() {
+                match result {
+                    Ok(source_nodes) => nodes[source] = source_nodes,
+                    Err(e) => errors.push(format!("{}: {}", self.sources[source].name, e)),
+                }
+            }
+            self.rebuild_routes(&nodes)?;
+            if !errors.is_empty() && errors.len() == self.sources.len() {
+                return Err(errors.join("; "));
             }
-            partslice = &partslice[1..];
+            for e in errors {
+                warn!("Keeping the old routes of {}", e);
+            }
+            Ok(())
+        })
+    }
+
+    /// Record the outcome of a query for the services of `source`. Queries
+    /// for a single service's nodes aren't recorded, since their watchers
+    /// run side by side and would make the status flip back and forth.
+    fn record_source(&self, source: usize, result: Result<(), String>) {
+        if let Ok(mut status) = self.sources[source].status.lock() {
+            status.record(result);
+        }
+    }
+
+    /// Record that the static route file failed to load, so its old routes
+    /// are still served.
+    pub fn static_routes_failed(&self, error: String) {
+        if let Ok(mut status) = self.static_status.lock() {
+            status
+                .get_or_insert_with(QueryStatus::default)
+                .record(Err(error));
         }
-        return Err(GetHostError::StrErr(format!(
-            "No address found for host {}",
-            host
-        )));
     }
 
-    fn address_for_host(&self, host: &str) -> Result<SocketAddr, GetHostError> {
+    /// The status of every source, followed by the static route file if
+    /// there is one.
+    pub fn sources(&self) -> Result<Vec<SourceStatus>, String> {
+        let services = self.services.read().map_err(|e| format!("{:?}", e))?;
+        let routes = |name: &str| {
+            services
+                .values()
+                .flatten()
+                .filter(|route| route.addresses.iter().any(|address| address.source == name))
+                .count()
+        };
+        let mut statuses = Vec::new();
+        for source in &self.sources {
+            let status = source
+                .status
+                .lock()
+                .map_err(|e| format!("{:?}", e))?
+                .clone();
+            statuses.push(SourceStatus {
+                name: source.name.clone(),
+                routes: routes(&source.name),
+                last_success: status.last_success,
+                error: status.error,
+            });
+        }
+        let static_status = self.static_status.lock().map_err(|e| format!("{:?}", e))?;
+        if let Some(status) = static_status.clone() {
+            statuses.push(SourceStatus {
+                name: STATIC_SOURCE.to_string(),
+                routes: routes(STATIC_SOURCE),
+                last_success: status.last_success,
+                error: status.error,
+            });
+        }
+        Ok(statuses)
+    }
+
+    /// Run `update` and remember when it ran, how long it took and whether
+    /// it worked.
+    fn timed_update<F>(&self, update: F) -> Result<(), String>
+    where
+        F: FnOnce() -> Result<(), String>,
+    {
+        let at = SystemTime::now();
+        let started = Instant::now();
+        let result = update();
+        let status = UpdateStatus {
+            at,
+            duration: started.elapsed(),
+            error: result.as_ref().err().cloned(),
+        };
+        if let Ok(mut last_update) = self.last_update.lock() {
+            *last_update = Some(status);
+        }
+        result
+    }
+
+    /// A summary of the routing table and its backends.
+    pub fn stats(&self) -> Result<RegistryStats, String> {
+        let services = self.services.read().map_err(|e| format!("{:?}", e))?;
+        let mut routes = 0;
+        let mut backends: HashMap<String, BackendStats> = HashMap::new();
+        for route in services.values().flatten() {
+            routes += 1;
+            for address in &route.addresses {
+                let addr = format!("{}:{}", address.address, address.port);
+                backends
+                    .entry(addr.clone())
+                    .or_insert_with(|| BackendStats {
+                        addr,
+                        active: address.active_connections(),
+                        health_check: address.state.health.passing(),
+                    });
+            }
+        }
+        let mut backends: Vec<BackendStats> = backends.into_values().collect();
+        backends.sort_by(|a, b| a.addr.cmp(&b.addr));
+        drop(services);
+        Ok(RegistryStats {
+            routes,
+            backends,
+            last_update: self.last_update()?,
+            sources: self.sources()?,
+        })
+    }
+
+    /// Replace the nodes for a single service of `source`. This does nothing
+    /// if `running` is false, so that a watcher for a service that has gone
+    /// away can't clobber the registry after the service is removed.
+    fn set_service_nodes(
+        &self,
+        source: usize,
+        service: &str,
+        service_nodes: Vec<ServiceNode>,
+        running: &AtomicBool,
+    ) -> Result<(), String> {
+        let mut nodes = self.nodes.lock().map_err(|e| format!("{:?}", e))?;
+        if !running.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        nodes[source].insert(service.to_string(), service_nodes);
+        self.timed_update(|| self.rebuild_routes(&nodes))
+    }
+
+    /// Replace the nodes for routes from the static route file.
+    pub fn set_static_nodes(&self, static_nodes: Vec<ServiceNode>) -> Result<(), String> {
+        let nodes = self.nodes.lock().map_err(|e| format!("{:?}", e))?;
+        *self.static_nodes.lock().map_err(|e| format!("{:?}", e))? = static_nodes;
+        if let Ok(mut status) = self.static_status.lock() {
+            status
+                .get_or_insert_with(QueryStatus::default)
+                .record(Ok(()));
+        }
+        self.timed_update(|| self.rebuild_routes(&nodes))
+    }
+
+    fn remove_service(&self, source: usize, service: &str) -> Result<(), String> {
+        let mut nodes = self.nodes.lock().map_err(|e| format!("{:?}", e))?;
+        nodes[source].remove(service);
+        self.timed_update(|| self.rebuild_routes(&nodes))
+    }
+
+    fn rebuild_routes(&self, nodes: &[HashMap<String, Vec<ServiceNode>>]) -> Result<(), String> {
+        let new_map = self.routes_from_nodes(nodes, &self.settings())?;
+        let mut locked = self.services.write().map_err(|e| format!("{:?}", e))?;
+        *locked = new_map;
+        Ok(())
+    }
+
+    /// Every host that has a route, including wildcard hosts like
+    /// `*example.com`. Routes registered without a host are left out.
+    pub fn hosts(&self) -> Result<Vec<String>, String> {
+        let services = self.services.read().map_err(|e| format!("{:?}", e))?;
+        let mut hosts: Vec<String> = services
+            .keys()
+            .filter(|host| !host.is_empty())
+            .cloned()
+            .collect();
+        hosts.sort();
+        Ok(hosts)
+    }
+
+    /// Find an address for a request to `host` with request URI `uri`.
+    /// Exact host matches are tried first, then wildcard hosts from most to
+    /// least specific, and finally routes that were registered without a
+    /// host (e.g. `urlprefix-/static`). The route's load balancer picks
+    /// among the healthy instances using `request`, skipping the instances
+    /// in `request.exclude` and those ejected by outlier detection.
+    pub fn lookup(
+        &self,
+        host: &str,
+        uri: &str,
+        request: &RequestContext,
+    ) -> Result<Target, GetHostError> {
         let services = self
             .services
             .read()
             .map_err(|e| GetHostError::PoisonErr(format!("{:?}", e)))?;
+        match find_route(&services, host, uri) {
+            Some((route_host, route)) => self.address_for_route(route_host, route, request),
+            None => Err(GetHostError::StrErr(format!(
+                "No address found for host {} path {}",
+                host, uri
+            ))),
+        }
+    }
 
-        match services.get(host) {
-            Some(addresses) => {
-                let mut rng = thread_rng();
-                match addresses.choose(&mut rng) {
-                    Some(address) => {
-                        return format!("{}:{}", address.address, address.port)
-                            .parse()
-                            .map_err(|e| {
-                                GetHostError::StrErr(format!("Failed to parse address: {:?}", e))
-                            });
-                    }
-                    None => {
-                        return Err(GetHostError::StrErr(format!(
-                            "No address found for host {}",
-                            host
-                        )));
-                    }
+    /// Explain where `lookup` would send a request to `host` with request
+    /// URI `uri`: the route that matches it, if any, and the backend that
+    /// would be chosen for a request without any headers.
+    pub fn explain(
+        &self,
+        host: &str,
+        uri: &str,
+    ) -> Result<Option<(RouteInfo, Option<SocketAddr>)>, String> {
+        let services = self.services.read().map_err(|e| format!("{:?}", e))?;
+        let (route_host, route) = match find_route(&services, host, uri) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let request = RequestContext {
+            client: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            header: "",
+            exclude: &[],
+        };
+        let backend = route
+            .balancer
+            .peek(&self.candidates(route, &request), &request)
+            .and_then(|address| address.addr().ok());
+        Ok(Some((route_info(route_host, route), backend)))
+    }
+
+    /// Every route in the routing table, ordered by host and then by
+    /// descending path length, the order they're matched in.
+    pub fn routes(&self) -> Result<Vec<RouteInfo>, String> {
+        let services = self.services.read().map_err(|e| format!("{:?}", e))?;
+        let mut hosts: Vec<&String> = services.keys().collect();
+        hosts.sort();
+        Ok(hosts
+            .into_iter()
+            .flat_map(|host| {
+                services[host]
+                    .iter()
+                    .map(move |route| route_info(host, route))
+            })
+            .collect())
+    }
+
+    /// When the routing table was last updated.
+    pub fn last_update(&self) -> Result<Option<UpdateStatus>, String> {
+        Ok(self
+            .last_update
+            .lock()
+            .map_err(|e| format!("{:?}", e))?
+            .clone())
+    }
+
+    /// Choose a backend of `route`, which is registered for `host`.
+    fn address_for_route(
+        &self,
+        host: &str,
+        route: &Route,
+        request: &RequestContext,
+    ) -> Result<Target, GetHostError> {
+        match route
+            .balancer
+            .choose(&self.candidates(route, request), request)
+        {
+            Some(address) => address.connect().map(|mut target| {
+                target.route = format!("{}{}", host, route.path);
+                target
+            }),
+            None => Err(GetHostError::StrErr(format!(
+                "No healthy address found for host {} path {}",
+                host, route.path
+            ))),
+        }
+    }
+
+    /// The backends of `route` that the balancer can choose from for
+    /// `request`.
+    fn candidates<'a>(&self, route: &'a Route, request: &RequestContext) -> Vec<&'a AddressPort> {
+        let settings = self.settings();
+        let routable = |address: &&AddressPort| {
+            settings.is_routable(address.status)
+                && address.state.health.is_passing()
+                && !address
+                    .addr()
+                    .is_ok_and(|addr| request.exclude.contains(&addr))
+        };
+        let candidates = self.without_ejected(route.addresses.iter().filter(routable).collect());
+        // Backends that were marked down are only used when there's nothing
+        // else left.
+        let mut healthy: Vec<&AddressPort> = candidates
+            .iter()
+            .copied()
+            .filter(|address| !address.is_down())
+            .collect();
+        if healthy.is_empty() {
+            healthy = candidates;
+        }
+        healthy
+    }
+
+    /// Removes the ejected backends from `addresses`. If more of them are
+    /// ejected than outlier detection allows at once, the ones whose
+    /// ejections end first are kept.
+    fn without_ejected<'a>(&self, addresses: Vec<&'a AddressPort>) -> Vec<&'a AddressPort> {
+        let mut ejected: Vec<(Instant, usize)> = addresses
+            .iter()
+            .enumerate()
+            .filter_map(|(i, address)| Some((address.state.outlier.ejected_until()?, i)))
+            .collect();
+        if ejected.is_empty() {
+            return addresses;
+        }
+        ejected.sort();
+        let kept = ejected
+            .len()
+            .saturating_sub(self.settings().outlier.max_ejected(addresses.len()));
+        let ejected: Vec<usize> = ejected[kept..].iter().map(|(_, i)| *i).collect();
+        addresses
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !ejected.contains(i))
+            .map(|(_, address)| address)
+            .collect()
+    }
+
+    /// Adds `address_port` to the route for `host` and `path`, creating the
+    /// route with `policy` if it doesn't exist yet.
+    fn add_address_port<'a>(
+        service_map: &'a mut HashMap<String, Vec<Route>>,
+        host: String,
+        path: String,
+        policy: &Policy,
+        address_port: AddressPort,
+    ) -> &'a mut Route {
+        let routes = service_map.entry(host).or_default();
+        let index = match routes.iter().position(|route| route.path == path) {
+            Some(index) => index,
+            None => {
+                // Keep the longest paths first so lookup can take the first match.
+                let index = routes
+                    .iter()
+                    .position(|route| route.path.len() < path.len())
+                    .unwrap_or(routes.len());
+                routes.insert(index, Route::new(path, policy));
+                index
+            }
+        };
+        let route = &mut routes[index];
+        route.addresses.push(address_port);
+        route
+    }
+
+    /// Splits a fabio-style `urlprefix-host/path opt=value ...` tag into its
+    /// host, path and options. A tag without a path routes every path on the
+    /// host.
+    fn extract_prefix(tag: &str) -> PrefixTag {
+        let mut fields = tag.split_whitespace();
+        let prefix = fields.next().unwrap_or("").trim_start_matches("urlprefix-");
+        let (host, path) = match prefix.find('/') {
+            Some(split) => (
+                prefix[..split].to_ascii_lowercase(),
+                prefix[split..].to_string(),
+            ),
+            None => (prefix.to_ascii_lowercase(), "/".to_string()),
+        };
+        let options = fields
+            .map(|option| {
+                let mut parts = option.splitn(2, '=');
+                let key = parts.next().unwrap_or("").to_string();
+                let value = parts.next().unwrap_or("").to_string();
+                (key, value)
+            })
+            .collect();
+        PrefixTag {
+            host,
+            path,
+            options,
+        }
+    }
+
+    /// Returns the shared state for the backend at `address` and `port`, as
+    /// reported by `source`. Sources don't share state, even for the same
+    /// address. Backends that are no longer referenced anywhere are
+    /// forgotten.
+    fn backend_state(
+        backends: &mut HashMap<String, Weak<BackendState>>,
+        source: &str,
+        address: &str,
+        port: u16,
+    ) -> Arc<BackendState> {
+        let key = format!("{} {}:{}", source, address, port);
+        if let Some(state) = backends.get(&key).and_then(Weak::upgrade) {
+            return state;
+        }
+        let state = Arc::new(BackendState::default());
+        backends.insert(key, Arc::downgrade(&state));
+        state
+    }
+
+    /// Give `route`, which is registered for `host`, the balancer it had
+    /// before the routing table was rebuilt if its policy is the same.
+    /// Balancers of routes that are gone are forgotten.
+    fn keep_balancer(balancers: &mut Balancers, host: &str, route: &mut Route) {
+        let key = format!("{}{}", host, route.path);
+        if let Some((policy, balancer)) = balancers.get(&key) {
+            if *policy == route.policy {
+                if let Some(balancer) = balancer.upgrade() {
+                    route.balancer = balancer;
+                    return;
                 }
             }
-            None => {
-                return Err(GetHostError::StrErr(format!(
-                    "No address found for host {}",
-                    host
-                )));
+        }
+        balancers.insert(key, (route.policy.clone(), Arc::downgrade(&route.balancer)));
+    }
+
+    /// Fetch every service of `source`.
+    fn pull_nodes(&self, source: usize) -> Result<HashMap<String, Vec<ServiceNode>>, String> {
+        let client = &self.sources[source].client;
+        let pulled = client.services(0).and_then(|services| {
+            let mut nodes = HashMap::new();
+            for service in services.value.keys() {
+                let service_nodes = client.get_nodes(service, 0)?;
+                nodes.insert(service.to_string(), service_nodes.value);
+            }
+            Ok(nodes)
+        });
+        self.record_source(source, pulled.as_ref().map(|_| ()).map_err(|e| e.clone()));
+        pulled
+    }
+
+    /// Add `node`, which was reported by `source`, to the route of each of
+    /// its `urlprefix-` tags.
+    fn add_node(
+        service_map: &mut HashMap<String, Vec<Route>>,
+        source: &str,
+        node: &ServiceNode,
+        settings: &RegistrySettings,
+        backends: &mut HashMap<String, Weak<BackendState>>,
+    ) {
+        for tag in node.tags.iter() {
+            if !tag.starts_with("urlprefix-") {
+                continue;
+            }
+            let prefix = Self::extract_prefix(tag);
+            let weight = match prefix.options.get("weight").map(|w| w.parse()) {
+                Some(Ok(weight)) => weight,
+                Some(Err(e)) => {
+                    warn!("Ignoring bad weight in tag {:?}: {}", tag, e);
+                    1
+                }
+                None => 1,
+            };
+            let proxy_protocol = match prefix.options.get("proxyproto").map(|v| v.parse()) {
+                Some(Ok(version)) => Some(version),
+                Some(Err(e)) => {
+                    warn!("Ignoring bad proxyproto in tag {:?}: {}", tag, e);
+                    None
+                }
+                None => None,
+            };
+            let health_check = match settings.health_check.with_options(&prefix.options) {
+                Ok(check) => check,
+                Err(e) => {
+                    warn!("Ignoring bad health check in tag {:?}: {}", tag, e);
+                    settings
+                        .health_check
+                        .with_options(&HashMap::new())
+                        .unwrap_or(None)
+                }
+            };
+            let timeouts = RouteTimeouts::from_options(&prefix.options).unwrap_or_else(|e| {
+                warn!("Ignoring bad timeouts in tag {:?}: {}", tag, e);
+                RouteTimeouts::default()
+            });
+            let route = Self::add_address_port(
+                service_map,
+                prefix.host,
+                prefix.path,
+                &settings.policy,
+                AddressPort {
+                    address: node.address.clone(),
+                    port: node.port,
+                    source: source.to_string(),
+                    status: node.status,
+                    weight,
+                    proxy_protocol,
+                    health_check,
+                    timeouts,
+                    state: Self::backend_state(backends, source, &node.address, node.port),
+                },
+            );
+            match prefix.options.get("lb").map(|lb| lb.parse()) {
+                Some(Ok(policy)) => route.set_policy(&policy),
+                Some(Err(e)) => warn!("Ignoring bad lb in tag {:?}: {}", tag, e),
+                None => (),
             }
         }
     }
 
-    fn add_address_port(
-        service_map: &mut HashMap<String, Vec<AddressPort>>,
-        service: String,
-        address_port: AddressPort,
+    fn routes_from_nodes(
+        &self,
+        nodes: &[HashMap<String, Vec<ServiceNode>>],
+        settings: &RegistrySettings,
+    ) -> Result<HashMap<String, Vec<Route>>, String> {
+        let static_nodes = self.static_nodes.lock().map_err(|e| format!("{:?}", e))?;
+        let mut backends = self.backends.lock().map_err(|e| format!("{:?}", e))?;
+        backends.retain(|_, state| state.strong_count() > 0);
+
+        let mut service_map: HashMap<String, Vec<Route>> = HashMap::new();
+        for (source, source_nodes) in self.sources.iter().zip(nodes) {
+            for node in source_nodes.values().flatten() {
+                Self::add_node(
+                    &mut service_map,
+                    &source.name,
+                    node,
+                    settings,
+                    &mut backends,
+                );
+            }
+        }
+        // A route from the static route file replaces a discovered route
+        // with the same host and path. Other discovered routes for the same
+        // host are kept.
+        let mut file_map: HashMap<String, Vec<Route>> = HashMap::new();
+        for node in static_nodes.iter() {
+            Self::add_node(&mut file_map, STATIC_SOURCE, node, settings, &mut backends);
+        }
+        for (host, routes) in file_map {
+            let host_routes = service_map.entry(host).or_default();
+            for route in routes {
+                host_routes.retain(|existing| existing.path != route.path);
+                let index = host_routes
+                    .iter()
+                    .position(|existing| existing.path.len() < route.path.len())
+                    .unwrap_or(host_routes.len());
+                host_routes.insert(index, route);
+            }
+        }
+        let mut balancers = self.balancers.lock().map_err(|e| format!("{:?}", e))?;
+        balancers.retain(|_, (_, balancer)| balancer.strong_count() > 0);
+        for (host, routes) in service_map.iter_mut() {
+            for route in routes {
+                Self::keep_balancer(&mut balancers, host, route);
+            }
+        }
+        Ok(service_map)
+    }
+}
+
+impl<T: 'static + ServiceProvider + Send + Sync> ServiceRegistry<T> {
+    /// Keep the registry up to date with every source. This never returns.
+    /// Each source's service list is watched with blocking queries on a
+    /// thread of its own, and each service gets a thread that watches that
+    /// service's nodes, so a change to one service is applied as soon as
+    /// its source reports it. When a query fails, the watcher waits
+    /// `fallback` before trying again, and the source's routes are left as
+    /// they were.
+    pub fn watch(registry: Arc<Self>, fallback: Duration) {
+        let watchers: Vec<_> = (0..registry.sources.len())
+            .map(|source| {
+                let registry = registry.clone();
+                thread::spawn(move || Self::watch_source(registry, source, fallback))
+            })
+            .collect();
+        for watcher in watchers {
+            let _ = watcher.join();
+        }
+    }
+
+    fn watch_source(registry: Arc<Self>, source: usize, fallback: Duration) {
+        let name = &registry.sources[source].name;
+        let mut index = 0;
+        let mut watchers: HashMap<String, Arc<AtomicBool>> = HashMap::new();
+        loop {
+            let services = match registry.sources[source].client.services(index) {
+                Ok(services) => services,
+                Err(e) => {
+                    error!("Failed to watch services of {}: {}", name, e);
+                    registry.record_source(source, Err(e));
+                    index = 0;
+                    thread::sleep(fallback);
+                    continue;
+                }
+            };
+            registry.record_source(source, Ok(()));
+            index = next_index(index, services.index);
+
+            watchers.retain(|service, running| {
+                if services.value.contains_key(service) {
+                    return true;
+                }
+                running.store(false, Ordering::SeqCst);
+                if let Err(e) = registry.remove_service(source, service) {
+                    error!("Failed to remove service {} of {}: {}", service, name, e);
+                }
+                false
+            });
+
+            for service in services.value.keys() {
+                if watchers.contains_key(service) {
+                    continue;
+                }
+                let running = Arc::new(AtomicBool::new(true));
+                watchers.insert(service.to_string(), running.clone());
+                let registry = registry.clone();
+                let service = service.to_string();
+                thread::spawn(move || registry.watch_service(source, &service, &running, fallback));
+            }
+        }
+    }
+
+    fn watch_service(
+        &self,
+        source: usize,
+        service: &str,
+        running: &AtomicBool,
+        fallback: Duration,
     ) {
-        if let Some(address_ports) = service_map.get_mut(&service) {
-            address_ports.push(address_port);
-        } else {
-            service_map.insert(service.to_string(), vec![address_port]);
-        }
-    }
-
-    fn extract_prefix(tag: &str) -> String {
-        tag.replace("urlprefix-", "").replace("/", "")
-    }
-
-    fn pull_consul_routes(&self) -> Result<HashMap<String, Vec<AddressPort>>, String> {
-        let services: HashMap<String, Vec<String>> = self.client.services()?;
-        let mut service_map: HashMap<String, Vec<AddressPort>> = HashMap::new();
-        for service in services.keys() {
-            let nodes = self.client.get_nodes(service.to_string())?;
-            for node in nodes.into_iter() {
-                for tag in node.ServiceTags.into_iter() {
-                    if tag.starts_with("urlprefix-") {
-                        Self::add_address_port(
-                            &mut service_map,
-                            Self::extract_prefix(&tag),
-                            AddressPort {
-                                address: node.ServiceAddress.clone(),
-                                port: node.ServicePort,
-                            },
-                        );
+        let name = &self.sources[source].name;
+        let mut index = 0;
+        while running.load(Ordering::SeqCst) {
+            match self.sources[source].client.get_nodes(service, index) {
+                Ok(nodes) => {
+                    index = next_index(index, nodes.index);
+                    if let Err(e) = self.set_service_nodes(source, service, nodes.value, running) {
+                        error!("Failed to update service {} of {}: {}", service, name, e);
                     }
                 }
+                Err(e) => {
+                    error!("Failed to watch service {} of {}: {}", service, name, e);
+                    index = 0;
+                    thread::sleep(fallback);
+                }
             }
         }
-        Ok(service_map)
+    }
+
+    /// Run the health checks of every routed backend. This never returns.
+    /// Checks run on HEALTH_CHECK_THREADS threads, so that a backend that is
+    /// slow to answer doesn't hold up the others.
+    pub fn check_health(registry: Arc<Self>) {
+        let clients = Arc::new(Clients::default());
+        let (due, receiver) = mpsc::channel::<(SocketAddr, HealthCheck, Arc<BackendState>)>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..HEALTH_CHECK_THREADS {
+            let clients = clients.clone();
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let next = receiver.lock().unwrap().recv();
+                match next {
+                    Ok((addr, check, state)) => run_check(&clients, addr, &check, &state),
+                    Err(_) => return,
+                }
+            });
+        }
+        loop {
+            for (addr, check, state) in registry.health_checks() {
+                if state.health.start_check(check.interval) {
+                    let _ = due.send((addr, check, state));
+                }
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// Every backend that has a health check, with its check. A backend
+    /// in several routes with different checks gets one of them, and one
+    /// reported by several sources is checked for each.
+    fn health_checks(&self) -> Vec<(SocketAddr, HealthCheck, Arc<BackendState>)> {
+        let services = match self.services.read() {
+            Ok(services) => services,
+            Err(e) => {
+                error!("Failed to read routes for health checks: {:?}", e);
+                return Vec::new();
+            }
+        };
+        let mut checks: HashMap<*const BackendState, (SocketAddr, HealthCheck, Arc<BackendState>)> =
+            HashMap::new();
+        for address in services
+            .values()
+            .flatten()
+            .flat_map(|route| &route.addresses)
+        {
+            if let (Some(check), Ok(addr)) = (&address.health_check, address.addr()) {
+                checks
+                    .entry(Arc::as_ptr(&address.state))
+                    .or_insert_with(|| (addr, check.clone(), address.state.clone()));
+            }
+        }
+        checks.into_values().collect()
     }
 }
 
@@ -159,41 +1187,103 @@ pub mod tests {
     pub struct TestConsul {
         hostname: String,
         target_port: u16,
+        // Makes every query fail, like an unreachable agent.
+        failing: AtomicBool,
+    }
+
+    impl TestConsul {
+        fn new(hostname: &str, target_port: u16) -> TestConsul {
+            TestConsul {
+                hostname: hostname.to_string(),
+                target_port,
+                failing: AtomicBool::new(false),
+            }
+        }
+
+        fn check(&self) -> Result<(), String> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err("Connection refused".to_string());
+            }
+            Ok(())
+        }
+    }
+
+    fn test_node(hostname: &str, target_port: u16) -> ServiceNode {
+        ServiceNode {
+            address: "127.0.0.1".to_string(),
+            port: target_port,
+            tags: vec![format!("urlprefix-{}/", hostname)],
+            status: HealthStatus::Passing,
+        }
     }
 
     impl ServiceProvider for TestConsul {
-        fn services(&self) -> Result<HashMap<String, Vec<String>>, String> {
+        fn services(&self, _index: u64) -> Result<Indexed<HashMap<String, Vec<String>>>, String> {
+            self.check()?;
             let mut m = HashMap::new();
             m.insert(
                 "test_service".to_string(),
                 vec![format!("urlprefix-{}/", self.hostname)],
             );
-            return Ok(m);
+            Ok(Indexed { index: 1, value: m })
         }
 
-        fn get_nodes(&self, service: String) -> Result<Vec<knusbaum_consul::ServiceNode>, String> {
+        fn get_nodes(
+            &self,
+            service: &str,
+            _index: u64,
+        ) -> Result<Indexed<Vec<ServiceNode>>, String> {
+            self.check()?;
             if service == "test_service" {
-                return Ok(vec![knusbaum_consul::ServiceNode {
-                    Address: "127.0.0.1".to_string(),
-                    Node: "0923e94c789a".to_string(),
-                    ServiceAddress: "127.0.0.1".to_string(),
-                    ServiceID: "test_service".to_string(),
-                    ServiceName: "test_service".to_string(),
-                    ServicePort: self.target_port,
-                    ServiceTags: vec![format!("urlprefix-{}/", self.hostname)],
-                }]);
+                return Ok(Indexed {
+                    index: 1,
+                    value: vec![test_node(&self.hostname, self.target_port)],
+                });
             }
-            return Err("No such service.".to_string());
+            Err("No such service.".to_string())
+        }
+    }
+
+    fn test_settings() -> RegistrySettings {
+        RegistrySettings {
+            include_warning: false,
+            policy: Policy::Random,
+            outlier: OutlierDetection::default(),
+            health_check: HealthCheck::default(),
         }
     }
 
     pub fn test_registry(hostname: &str, target_port: u16) -> ServiceRegistry<TestConsul> {
-        ServiceRegistry {
-            services: RwLock::new(HashMap::new()),
-            client: TestConsul {
-                hostname: hostname.to_string(),
-                target_port: target_port,
-            },
+        ServiceRegistry::new(
+            vec![("consul".to_string(), TestConsul::new(hostname, target_port))],
+            test_settings(),
+        )
+    }
+
+    /// A registry with a service for each of `tags`, each with one instance
+    /// on 127.0.0.1 at the given port.
+    pub fn registry_with_tags(tags: &[(&str, u16)]) -> ServiceRegistry<TestConsul> {
+        let nodes = tags
+            .iter()
+            .enumerate()
+            .map(|(i, (tag, port))| {
+                let mut node = test_node("", *port);
+                node.tags = vec![tag.to_string()];
+                (format!("service{}", i), vec![node])
+            })
+            .collect();
+        let registry = test_registry("", 0);
+        let nodes = vec![nodes];
+        registry.rebuild_routes(&nodes).unwrap();
+        *registry.nodes.lock().unwrap() = nodes;
+        registry
+    }
+
+    fn test_request() -> RequestContext<'static> {
+        RequestContext {
+            client: "10.0.0.1".parse().unwrap(),
+            header: "",
+            exclude: &[],
         }
     }
 
@@ -201,67 +1291,144 @@ pub mod tests {
     fn test_lookup() {
         let registry = test_registry("test-website.com", 8080);
         assert!(registry.update().is_ok());
-        let result = registry.lookup("test-website.com");
+        let result = registry.lookup("test-website.com", "/", &test_request());
         assert!(result.is_ok());
 
-        let addr = result.unwrap();
+        let addr = result.unwrap().addr;
         assert_eq!(addr, "127.0.0.1:8080".parse().unwrap());
     }
 
     #[test]
     fn test_pull_consul_routes() {
         let registry = test_registry("test-website.com", 8080);
-        let result = registry.pull_consul_routes();
+        let result = registry.pull_nodes(0);
         assert!(result.is_ok());
-        let result = result.unwrap();
+        let result = registry
+            .routes_from_nodes(&[result.unwrap()], &registry.settings())
+            .unwrap();
 
-        let addrs = result.get("test-website.com");
-        assert!(addrs.is_some());
-        let addrs = addrs.unwrap();
+        let routes = result.get("test-website.com");
+        assert!(routes.is_some());
+        let routes = routes.unwrap();
 
+        assert!(routes.len() == 1);
+        assert!(routes[0].path == "/");
+        let addrs = &routes[0].addresses;
         assert!(addrs.len() == 1);
         assert!(addrs[0].address == "127.0.0.1");
         assert!(addrs[0].port == 8080);
     }
 
-    fn check_matches(host: &str, service_prefix: &str) {
-        let addrs = vec![AddressPort {
-            address: "127.0.0.1".to_string(),
-            port: 8080,
-        }];
-        let mut map = HashMap::new();
-        map.insert(service_prefix.to_string(), addrs);
-        let registry = ServiceRegistry {
-            services: RwLock::new(map),
-            client: TestConsul {
-                hostname: "".to_string(),
-                target_port: 0,
-            },
+    #[test]
+    fn test_next_index() {
+        assert_eq!(next_index(0, 5), 5);
+        assert_eq!(next_index(5, 7), 7);
+        assert_eq!(next_index(7, 7), 7);
+        assert_eq!(next_index(7, 3), 0);
+    }
+
+    #[test]
+    fn test_set_service_nodes() {
+        let registry = test_registry("test-website.com", 8080);
+        assert!(registry.update().is_ok());
+
+        let running = AtomicBool::new(true);
+        let nodes = vec![test_node("test-website.com", 8081)];
+        assert!(registry
+            .set_service_nodes(0, "test_service", nodes, &running)
+            .is_ok());
+        let result = registry.lookup("test-website.com", "/", &test_request());
+        assert_eq!(result.unwrap().addr, "127.0.0.1:8081".parse().unwrap());
+
+        // A watcher that has been stopped must not change the registry.
+        running.store(false, Ordering::SeqCst);
+        let nodes = vec![test_node("test-website.com", 8082)];
+        assert!(registry
+            .set_service_nodes(0, "test_service", nodes, &running)
+            .is_ok());
+        let result = registry.lookup("test-website.com", "/", &test_request());
+        assert_eq!(result.unwrap().addr, "127.0.0.1:8081".parse().unwrap());
+
+        assert!(registry.remove_service(0, "test_service").is_ok());
+        assert!(registry
+            .lookup("test-website.com", "/", &test_request())
+            .is_err());
+    }
+
+    #[test]
+    fn test_health_filtering() {
+        let registry = test_registry("test-website.com", 8080);
+        let running = AtomicBool::new(true);
+        let mut critical = test_node("test-website.com", 8081);
+        critical.status = HealthStatus::Critical;
+        let mut warning = test_node("test-website.com", 8082);
+        warning.status = HealthStatus::Warning;
+
+        let nodes = vec![critical.clone(), warning.clone()];
+        assert!(registry
+            .set_service_nodes(0, "test_service", nodes, &running)
+            .is_ok());
+        assert!(registry
+            .lookup("test-website.com", "/", &test_request())
+            .is_err());
+
+        let settings = RegistrySettings {
+            include_warning: true,
+            ..test_settings()
         };
+        assert!(registry.reconfigure(settings).is_ok());
+        let result = registry.lookup("test-website.com", "/", &test_request());
+        assert_eq!(result.unwrap().addr, "127.0.0.1:8082".parse().unwrap());
+
+        let nodes = vec![critical];
+        assert!(registry
+            .set_service_nodes(0, "test_service", nodes, &running)
+            .is_ok());
+        assert!(registry
+            .lookup("test-website.com", "/", &test_request())
+            .is_err());
+    }
+
+    pub fn registry_with_routes(routes: &[(&str, &str, u16)]) -> ServiceRegistry<TestConsul> {
+        let mut map = HashMap::new();
+        for (host, path, port) in routes {
+            ServiceRegistry::<TestConsul>::add_address_port(
+                &mut map,
+                host.to_string(),
+                path.to_string(),
+                &Policy::Random,
+                AddressPort {
+                    address: "127.0.0.1".to_string(),
+                    port: *port,
+                    source: "consul".to_string(),
+                    status: HealthStatus::Passing,
+                    weight: 1,
+                    proxy_protocol: None,
+                    health_check: None,
+                    timeouts: RouteTimeouts::default(),
+                    state: Arc::new(BackendState::default()),
+                },
+            );
+        }
+        let registry = test_registry("", 0);
+        *registry.services.write().unwrap() = map;
+        registry
+    }
+
+    fn check_matches(host: &str, service_prefix: &str) {
+        let registry = registry_with_routes(&[(service_prefix, "/", 8080)]);
 
         // Test
-        let target = registry.lookup(host);
+        let target = registry.lookup(host, "/", &test_request());
         assert!(target.is_ok());
-        assert_eq!(target.ok().unwrap(), "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(target.ok().unwrap().addr, "127.0.0.1:8080".parse().unwrap());
     }
 
     fn check_no_match(host: &str, service_prefix: &str) {
-        let addrs = vec![AddressPort {
-            address: "127.0.0.1".to_string(),
-            port: 8080,
-        }];
-        let mut map = HashMap::new();
-        map.insert(service_prefix.to_string(), addrs);
-        let registry = ServiceRegistry {
-            services: RwLock::new(map),
-            client: TestConsul {
-                hostname: "".to_string(),
-                target_port: 0,
-            },
-        };
+        let registry = registry_with_routes(&[(service_prefix, "/", 8080)]);
 
         // Test
-        let target = registry.lookup(host);
+        let target = registry.lookup(host, "/", &test_request());
         assert!(target.is_err());
     }
 
@@ -272,19 +1439,459 @@ pub mod tests {
         check_matches("bar.foo.com", "*foo.com");
         check_matches("baz.bar.foo.com", "*foo.com");
         check_no_match("foo.com.biz", "*foo.com");
+        check_matches("bar.foo.com", "*.foo.com");
+        check_matches("baz.bar.foo.com", "*.foo.com");
+        check_no_match("foo.com", "*.foo.com");
+        check_no_match("barfoo.com", "*.foo.com");
 
         check_matches("foo.com", "foo.com");
         check_no_match("bar.foo.com", "foo.com");
         check_no_match("foo.com.biz", "foo.com");
     }
 
-    fn check_extract_prefix(prefix: &str, expect: &str) {
-        assert_eq!(&<ServiceRegistry>::extract_prefix(prefix), expect);
+    fn check_path_routes(registry: &ServiceRegistry<TestConsul>, host: &str, uri: &str, port: u16) {
+        let target = registry.lookup(host, uri, &test_request());
+        assert!(target.is_ok());
+        assert_eq!(
+            target.ok().unwrap().addr,
+            format!("127.0.0.1:{}", port).parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_path_matching() {
+        let registry = registry_with_routes(&[
+            ("foo.com", "/", 8080),
+            ("foo.com", "/api/v2", 8082),
+            ("foo.com", "/api", 8081),
+            ("*foo.com", "/static", 8083),
+            ("", "/shared", 8084),
+        ]);
+
+        check_path_routes(&registry, "foo.com", "/", 8080);
+        check_path_routes(&registry, "foo.com", "/index.html", 8080);
+        check_path_routes(&registry, "foo.com", "/api", 8081);
+        check_path_routes(&registry, "foo.com", "/api/v1/users", 8081);
+        check_path_routes(&registry, "foo.com", "/api/v2/users", 8082);
+
+        // A wildcard host is only used when the exact host has no matching path.
+        check_path_routes(&registry, "foo.com", "/static/app.js", 8080);
+        check_path_routes(&registry, "bar.foo.com", "/static/app.js", 8083);
+        assert!(registry
+            .lookup("bar.foo.com", "/", &test_request())
+            .is_err());
+
+        // Routes without a host match any host.
+        check_path_routes(&registry, "bar.foo.com", "/shared/logo.png", 8084);
+        check_path_routes(&registry, "other.com", "/shared/logo.png", 8084);
+        assert!(registry.lookup("other.com", "/", &test_request()).is_err());
+    }
+
+    #[test]
+    fn test_static_nodes() {
+        let registry = registry_with_tags(&[
+            ("urlprefix-foo.com/", 8080),
+            ("urlprefix-foo.com/api", 8081),
+        ]);
+        let mut file_node = test_node("", 9080);
+        file_node.tags = vec!["urlprefix-foo.com/".to_string()];
+        let mut other_node = test_node("", 9082);
+        other_node.tags = vec!["urlprefix-foo.com/api/v2".to_string()];
+        registry
+            .set_static_nodes(vec![file_node, other_node])
+            .unwrap();
+
+        // The file's route replaces the discovered one with the same path,
+        // and the discovered /api route still works.
+        check_path_routes(&registry, "foo.com", "/", 9080);
+        check_path_routes(&registry, "foo.com", "/api/v1", 8081);
+        check_path_routes(&registry, "foo.com", "/api/v2/users", 9082);
+        let routes = registry.routes().unwrap();
+        assert_eq!(routes.len(), 3);
+        assert_eq!(routes[0].backends.len(), 1);
+
+        registry.set_static_nodes(Vec::new()).unwrap();
+        check_path_routes(&registry, "foo.com", "/", 8080);
+        check_path_routes(&registry, "foo.com", "/api/v2/users", 8081);
+    }
+
+    #[test]
+    fn test_sources() {
+        let registry = ServiceRegistry::new(
+            vec![
+                ("dc1".to_string(), TestConsul::new("a.com", 8080)),
+                ("dc2".to_string(), TestConsul::new("a.com", 8080)),
+            ],
+            test_settings(),
+        );
+        registry.update().unwrap();
+        let routes = registry.routes().unwrap();
+        assert_eq!(routes.len(), 1);
+        let sources: Vec<&str> = routes[0]
+            .backends
+            .iter()
+            .map(|b| b.source.as_str())
+            .collect();
+        assert_eq!(sources, vec!["dc1", "dc2"]);
+        // The same address from two sources is two backends.
+        let services = registry.services.read().unwrap();
+        let addresses = &services["a.com"][0].addresses;
+        assert!(!Arc::ptr_eq(&addresses[0].state, &addresses[1].state));
+        drop(services);
+
+        // A failing source keeps its routes and reports why.
+        registry.sources[1]
+            .client
+            .failing
+            .store(true, Ordering::SeqCst);
+        registry.update().unwrap();
+        assert_eq!(registry.routes().unwrap()[0].backends.len(), 2);
+        let statuses = registry.sources().unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].error, None);
+        assert_eq!(statuses[1].routes, 1);
+        assert_eq!(statuses[1].error.as_deref(), Some("Connection refused"));
+        assert!(statuses[1].last_success.is_some());
+
+        registry.sources[0]
+            .client
+            .failing
+            .store(true, Ordering::SeqCst);
+        assert!(registry.update().is_err());
+        assert_eq!(registry.routes().unwrap()[0].backends.len(), 2);
+
+        registry.set_static_nodes(Vec::new()).unwrap();
+        registry.static_routes_failed("Bad file".to_string());
+        let statuses = registry.sources().unwrap();
+        assert_eq!(statuses[2].name, STATIC_SOURCE);
+        assert_eq!(statuses[2].routes, 0);
+        assert_eq!(statuses[2].error.as_deref(), Some("Bad file"));
+    }
+
+    fn check_extract_prefix(prefix: &str, host: &str, path: &str) {
+        let tag = <ServiceRegistry>::extract_prefix(prefix);
+        assert_eq!(tag.host, host);
+        assert_eq!(tag.path, path);
     }
 
     #[test]
     fn test_extract_prefix_host_match() {
-        check_extract_prefix("urlprefix-foo.com/", "foo.com");
-        check_extract_prefix("urlprefix-*.foo.com/", "*.foo.com");
+        check_extract_prefix("urlprefix-foo.com/", "foo.com", "/");
+        check_extract_prefix("urlprefix-*.foo.com/", "*.foo.com", "/");
+        check_extract_prefix("urlprefix-localhost:9001", "localhost:9001", "/");
+    }
+
+    #[test]
+    fn test_extract_prefix_path_match() {
+        check_extract_prefix("urlprefix-foo.com/api", "foo.com", "/api");
+        check_extract_prefix("urlprefix-foo.com/api/v2/", "foo.com", "/api/v2/");
+        check_extract_prefix("urlprefix-/static", "", "/static");
+    }
+
+    #[test]
+    fn test_extract_prefix_options() {
+        let tag = <ServiceRegistry>::extract_prefix("urlprefix-foo.com/api lb=p2c  weight=3");
+        assert_eq!(tag.host, "foo.com");
+        assert_eq!(tag.path, "/api");
+        assert_eq!(tag.options.len(), 2);
+        assert_eq!(tag.options["lb"], "p2c");
+        assert_eq!(tag.options["weight"], "3");
+        assert!(<ServiceRegistry>::extract_prefix("urlprefix-foo.com/")
+            .options
+            .is_empty());
+    }
+
+    #[test]
+    fn test_route_options() {
+        let registry = test_registry("test-website.com", 8080);
+        let mut node = test_node("test-website.com", 8080);
+        node.tags = vec![
+            "urlprefix-foo.com/ lb=round_robin weight=5".to_string(),
+            "urlprefix-foo.com/api proxyproto=v2".to_string(),
+            "urlprefix-foo.com/static lb=bogus weight=bogus proxyproto=v3".to_string(),
+        ];
+        let mut nodes = HashMap::new();
+        nodes.insert("test_service".to_string(), vec![node]);
+        let routes = registry
+            .routes_from_nodes(&[nodes], &registry.settings())
+            .unwrap();
+        let routes = &routes["foo.com"];
+
+        assert_eq!(routes[0].path, "/static");
+        assert_eq!(routes[0].policy, Policy::Random);
+        assert_eq!(routes[0].addresses[0].weight, 1);
+        assert_eq!(routes[0].addresses[0].proxy_protocol, None);
+        assert_eq!(routes[1].path, "/api");
+        assert_eq!(routes[1].policy, Policy::Random);
+        assert_eq!(routes[1].addresses[0].proxy_protocol, Some(Version::V2));
+        assert_eq!(routes[2].path, "/");
+        assert_eq!(routes[2].policy, Policy::RoundRobin);
+        assert_eq!(routes[2].addresses[0].weight, 5);
+
+        // Every route to the same instance shares its state.
+        assert!(Arc::ptr_eq(
+            &routes[0].addresses[0].state,
+            &routes[2].addresses[0].state
+        ));
+    }
+
+    #[test]
+    fn test_route_health_check() {
+        let registry = test_registry("test-website.com", 8080);
+        let mut node = test_node("test-website.com", 8080);
+        node.tags = vec![
+            "urlprefix-foo.com/".to_string(),
+            "urlprefix-foo.com/api health=/healthz health_interval=5".to_string(),
+            "urlprefix-foo.com/static health=/ health_timeout=soon".to_string(),
+        ];
+        let mut nodes = HashMap::new();
+        nodes.insert("test_service".to_string(), vec![node]);
+        let routes = registry
+            .routes_from_nodes(&[nodes], &registry.settings())
+            .unwrap();
+        let routes = &routes["foo.com"];
+
+        // A bad check falls back to the default, which is disabled.
+        assert_eq!(routes[0].path, "/static");
+        assert_eq!(routes[0].addresses[0].health_check, None);
+        assert_eq!(routes[1].path, "/api");
+        let check = routes[1].addresses[0].health_check.as_ref().unwrap();
+        assert_eq!(check.path, "/healthz");
+        assert_eq!(check.interval, Duration::from_secs(5));
+        assert_eq!(routes[2].path, "/");
+        assert_eq!(routes[2].addresses[0].health_check, None);
+    }
+
+    #[test]
+    fn test_route_timeouts() {
+        let registry = test_registry("test-website.com", 8080);
+        let mut node = test_node("test-website.com", 8080);
+        node.tags = vec![
+            "urlprefix-foo.com/ connect_timeout=1 response_timeout=2 idle_timeout=3".to_string(),
+            "urlprefix-foo.com/api".to_string(),
+            "urlprefix-foo.com/static connect_timeout=bogus".to_string(),
+        ];
+        let mut nodes = HashMap::new();
+        nodes.insert("test_service".to_string(), vec![node]);
+        let routes = registry
+            .routes_from_nodes(&[nodes], &registry.settings())
+            .unwrap();
+        let routes = &routes["foo.com"];
+
+        assert_eq!(routes[0].path, "/static");
+        assert_eq!(routes[0].addresses[0].timeouts, RouteTimeouts::default());
+        assert_eq!(routes[1].path, "/api");
+        assert_eq!(routes[1].addresses[0].timeouts, RouteTimeouts::default());
+        assert_eq!(routes[2].path, "/");
+        let timeouts = routes[2].addresses[0].timeouts;
+        assert_eq!(timeouts.connect, Some(Duration::from_secs(1)));
+        assert_eq!(timeouts.first_byte, Some(Duration::from_secs(2)));
+        assert_eq!(timeouts.idle, Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_connection_tracking() {
+        let registry = test_registry("test-website.com", 8080);
+        assert!(registry.update().is_ok());
+        let target = registry.lookup("test-website.com", "/", &test_request());
+        let target = target.unwrap();
+
+        // Connections are still counted after the routing table is rebuilt.
+        assert!(registry.update().is_ok());
+        let services = registry.services.read().unwrap();
+        let address = &services["test-website.com"][0].addresses[0];
+        assert_eq!(address.active_connections(), 1);
+        drop(target);
+        assert_eq!(address.active_connections(), 0);
+    }
+
+    #[test]
+    fn test_exclude_and_mark_down() {
+        let registry = registry_with_routes(&[("a.com", "/", 8080), ("a.com", "/", 8081)]);
+        let first: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let second: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+        let lookup = |exclude: &[SocketAddr]| {
+            let request = RequestContext {
+                exclude,
+                ..test_request()
+            };
+            registry
+                .lookup("a.com", "/", &request)
+                .map(|target| target.addr)
+        };
+
+        for _ in 0..10 {
+            assert_eq!(lookup(&[first]).unwrap(), second);
+        }
+        assert!(lookup(&[first, second]).is_err());
+
+        // A backend marked down is skipped while another one is left.
+        let target = registry.lookup("a.com", "/", &test_request()).unwrap();
+        target.mark_down(Duration::from_secs(60));
+        let down = target.addr;
+        for _ in 0..10 {
+            assert_ne!(lookup(&[]).unwrap(), down);
+        }
+        let up = if down == first { second } else { first };
+        assert_eq!(lookup(&[up]).unwrap(), down);
+    }
+
+    #[test]
+    fn test_balancer_kept() {
+        let tags = [
+            ("urlprefix-a.com/ lb=round_robin", 8080),
+            ("urlprefix-a.com/ lb=round_robin", 8081),
+        ];
+        let registry = registry_with_tags(&tags);
+        let lookup = || registry.lookup("a.com", "/", &test_request()).unwrap().addr;
+        let first = lookup();
+
+        // The round robin carries on where it was after a rebuild.
+        registry.set_static_nodes(Vec::new()).unwrap();
+        let second = lookup();
+        assert_ne!(first, second);
+        registry.set_static_nodes(Vec::new()).unwrap();
+        assert_eq!(lookup(), first);
+    }
+
+    #[test]
+    fn test_outlier_ejection() {
+        let registry = registry_with_routes(&[("a.com", "/", 8080), ("a.com", "/", 8081)]);
+        let lookup = || registry.lookup("a.com", "/", &test_request()).unwrap();
+        let fail = |target: &Target| {
+            for _ in 0..registry.settings().outlier.consecutive_failures {
+                registry.record_result(target, false);
+            }
+        };
+
+        let bad = lookup();
+        fail(&bad);
+        for _ in 0..10 {
+            assert_ne!(lookup().addr, bad.addr);
+        }
+
+        // Only half of the route can be ejected, so when the other backend
+        // is ejected too, the one ejected first is used again.
+        let other = lookup();
+        fail(&other);
+        for _ in 0..10 {
+            assert_eq!(lookup().addr, bad.addr);
+        }
+    }
+
+    #[test]
+    fn test_failed_health_check() {
+        let registry = registry_with_routes(&[("a.com", "/", 8080), ("a.com", "/", 8081)]);
+        let failing = registry.lookup("a.com", "/", &test_request()).unwrap();
+        failing.state.health.record(Err("down".to_string()));
+        for _ in 0..10 {
+            let target = registry.lookup("a.com", "/", &test_request()).unwrap();
+            assert_ne!(target.addr, failing.addr);
+        }
+        failing.state.health.record(Ok(()));
+        assert!((0..100)
+            .any(|_| registry.lookup("a.com", "/", &test_request()).unwrap().addr == failing.addr));
+    }
+
+    #[test]
+    fn test_explain() {
+        let registry = registry_with_routes(&[
+            ("a.com", "/", 8080),
+            ("a.com", "/api", 8081),
+            ("*b.com", "/", 8082),
+            ("", "/static", 8083),
+        ]);
+        let explain = |host: &str, uri: &str| {
+            let (route, backend) = registry.explain(host, uri).unwrap().unwrap();
+            (route.host, route.path, backend.map(|addr| addr.port()))
+        };
+        assert_eq!(
+            explain("a.com", "/api/users"),
+            ("a.com".to_string(), "/api".to_string(), Some(8081))
+        );
+        assert_eq!(
+            explain("x.b.com", "/"),
+            ("*b.com".to_string(), "/".to_string(), Some(8082))
+        );
+        assert_eq!(
+            explain("c.com", "/static/app.js"),
+            ("".to_string(), "/static".to_string(), Some(8083))
+        );
+        assert!(registry.explain("c.com", "/").unwrap().is_none());
+
+        // A route whose only backend is failing matches, but has nowhere to
+        // send the request.
+        let target = registry.lookup("a.com", "/api", &test_request()).unwrap();
+        target.state.health.record(Err("down".to_string()));
+        drop(target);
+        assert_eq!(explain("a.com", "/api").2, None);
+
+        // Explaining doesn't move a round robin on.
+        let round_robin = registry_with_tags(&[
+            ("urlprefix-a.com/ lb=round_robin", 8080),
+            ("urlprefix-a.com/ lb=round_robin", 8081),
+        ]);
+        let explained = round_robin.explain("a.com", "/").unwrap().unwrap().1;
+        assert_eq!(
+            round_robin.explain("a.com", "/").unwrap().unwrap().1,
+            explained
+        );
+        let target = round_robin.lookup("a.com", "/", &test_request()).unwrap();
+        assert_eq!(Some(target.addr), explained);
+
+        let routes = registry.routes().unwrap();
+        let paths: Vec<(&str, &str)> = routes
+            .iter()
+            .map(|route| (route.host.as_str(), route.path.as_str()))
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                ("", "/static"),
+                ("*b.com", "/"),
+                ("a.com", "/api"),
+                ("a.com", "/")
+            ]
+        );
+        let backend = &routes[2].backends[0];
+        assert_eq!(backend.addr, "127.0.0.1:8081");
+        assert_eq!(backend.health_check, Some(Err("down".to_string())));
+        assert_eq!(backend.active, 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let registry = test_registry("a.com", 8080);
+        assert!(registry.stats().unwrap().last_update.is_none());
+        registry.update().unwrap();
+        let target = registry.lookup("a.com", "/", &test_request()).unwrap();
+        assert_eq!(target.route, "a.com/");
+        let stats = registry.stats().unwrap();
+        assert_eq!(stats.routes, 1);
+        assert_eq!(stats.backends.len(), 1);
+        assert_eq!(stats.backends[0].addr, "127.0.0.1:8080");
+        assert_eq!(stats.backends[0].active, 1);
+        assert_eq!(stats.backends[0].health_check, None);
+        assert_eq!(stats.last_update.unwrap().error, None);
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let registry = test_registry("a.com", 8080);
+        registry.update().unwrap();
+        let target = registry.lookup("a.com", "/", &test_request()).unwrap();
+        assert_eq!(registry.routes().unwrap()[0].policy, Policy::Random);
+
+        let settings = RegistrySettings {
+            policy: Policy::RoundRobin,
+            ..test_settings()
+        };
+        registry.reconfigure(settings).unwrap();
+        let routes = registry.routes().unwrap();
+        assert_eq!(routes[0].policy, Policy::RoundRobin);
+        // Connections opened before the change are still counted.
+        assert_eq!(routes[0].backends[0].active, 1);
+        drop(target);
+        assert_eq!(registry.routes().unwrap()[0].backends[0].active, 0);
     }
 }
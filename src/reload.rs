@@ -0,0 +1,108 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Watcher notices when the config file changes or robby gets a SIGHUP.
+pub struct Watcher {
+    files: Vec<PathBuf>,
+    versions: Vec<Option<Version>>,
+    hangup: Arc<AtomicBool>,
+}
+
+/// What a file looked like when it was last checked. The modification time
+/// alone can miss a change made within its resolution, so the contents are
+/// compared too.
+#[derive(Debug, PartialEq)]
+struct Version {
+    modified: Option<SystemTime>,
+    len: usize,
+    hash: u64,
+}
+
+/// The version of each file, or None if it can't be read.
+fn versions(files: &[PathBuf]) -> Vec<Option<Version>> {
+    files
+        .iter()
+        .map(|file| {
+            let contents = fs::read(file).ok()?;
+            let mut hasher = DefaultHasher::new();
+            contents.hash(&mut hasher);
+            Some(Version {
+                modified: fs::metadata(file).and_then(|meta| meta.modified()).ok(),
+                len: contents.len(),
+                hash: hasher.finish(),
+            })
+        })
+        .collect()
+}
+
+impl Watcher {
+    /// Watch `files`, any of which may be missing.
+    pub fn new(files: Vec<PathBuf>) -> Result<Watcher, String> {
+        let hangup = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, hangup.clone())
+            .map_err(|e| format!("Failed to handle SIGHUP: {}", e))?;
+        Ok(Watcher {
+            versions: versions(&files),
+            files,
+            hangup,
+        })
+    }
+
+    /// Whether a file was created, changed or removed, or a SIGHUP arrived,
+    /// since the last call.
+    pub fn changed(&mut self) -> bool {
+        let versions = versions(&self.files);
+        let changed = versions != self.versions;
+        self.versions = versions;
+        self.hangup.swap(false, Ordering::SeqCst) || changed
+    }
+
+    /// Call `reload` after every change, looking once a second. This never
+    /// returns.
+    pub fn watch<F: FnMut()>(mut self, mut reload: F) {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            if self.changed() {
+                reload();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_changed() {
+        let path = env::temp_dir().join(format!("robby-reload-{}.yml", std::process::id()));
+        let mut watcher = Watcher::new(vec![path.clone()]).unwrap();
+        assert!(!watcher.changed());
+
+        fs::write(&path, "retries: 1\n").unwrap();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+        // A change of the same length, likely within the same modification
+        // time.
+        fs::write(&path, "retries: 2\n").unwrap();
+        assert!(watcher.changed());
+
+        watcher.hangup.store(true, Ordering::SeqCst);
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        fs::remove_file(&path).unwrap();
+        assert!(watcher.changed());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::{IpAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    registry::{HealthStatus, ServiceNode, ServiceProvider, ServiceRegistry},
+    reload::Watcher,
+};
+
+/// The routes in a static route file. Each key is what would follow
+/// `urlprefix-` in a tag, e.g. `example.com/api` or
+/// `example.com/ lb=round_robin`, and each value lists its backends as
+/// `host:port`.
+type Routes = BTreeMap<String, Vec<String>>;
+
+// How often the backends' host names are resolved again.
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Parse `text` as YAML, TOML or JSON, going by the extension of `path`.
+fn parse(path: &Path, text: &str) -> Result<Routes, String> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension {
+        "yaml" | "yml" => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+        "toml" => toml::from_str(text).map_err(|e| e.to_string()),
+        "json" => serde_json::from_str(text).map_err(|e| e.to_string()),
+        _ => Err("the extension must be yaml, yml, toml or json".to_string()),
+    }
+}
+
+/// A node for every address each backend resolves to, tagged with its
+/// route. Names are only resolved here, so a change in DNS is picked up
+/// when the file is next loaded.
+fn nodes(routes: Routes) -> Result<Vec<ServiceNode>, String> {
+    let mut nodes = Vec::new();
+    for (route, backends) in routes {
+        if !route.contains('/') {
+            return Err(format!("Route {:?} has no path", route));
+        }
+        if backends.is_empty() {
+            return Err(format!("Route {:?} has no backends", route));
+        }
+        let tags = vec![format!("urlprefix-{}", route)];
+        for backend in backends {
+            let addrs = backend
+                .to_socket_addrs()
+                .map_err(|e| format!("Can't resolve {} for {}: {}", backend, route, e))?;
+            for addr in addrs {
+                let address = match addr.ip() {
+                    IpAddr::V4(ip) => ip.to_string(),
+                    IpAddr::V6(ip) => format!("[{}]", ip),
+                };
+                nodes.push(ServiceNode {
+                    address,
+                    port: addr.port(),
+                    tags: tags.clone(),
+                    status: HealthStatus::Passing,
+                });
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+/// Load the static route file at `path`.
+pub fn load(path: &Path) -> Result<Vec<ServiceNode>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let routes =
+        parse(path, &text).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    nodes(routes)
+}
+
+/// Load the static route file into `registry` again whenever it changes or
+/// robby gets a SIGHUP, and every RESOLVE_INTERVAL to pick up changes to
+/// what its host names resolve to. `nodes` are the routes it was loaded
+/// with at startup. A file that fails to load leaves the routes as they
+/// were. This never returns.
+pub fn watch<T: ServiceProvider>(
+    registry: Arc<ServiceRegistry<T>>,
+    path: PathBuf,
+    mut nodes: Vec<ServiceNode>,
+) {
+    let mut watcher = match Watcher::new(vec![path.clone()]) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            error!("Can't watch {}: {}", path.display(), e);
+            return;
+        }
+    };
+    let mut resolved = Instant::now();
+    let mut failed = false;
+    loop {
+        thread::sleep(Duration::from_secs(1));
+        let changed = watcher.changed();
+        if !changed && resolved.elapsed() < RESOLVE_INTERVAL {
+            continue;
+        }
+        resolved = Instant::now();
+        let loaded = match load(&path) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                error!("Keeping the old static routes: {}", e);
+                registry.static_routes_failed(e);
+                failed = true;
+                continue;
+            }
+        };
+        if !changed && !failed && loaded == nodes {
+            continue;
+        }
+        match registry.set_static_nodes(loaded.clone()) {
+            Ok(()) => info!("Reloaded static routes from {}", path.display()),
+            Err(e) => error!("Failed to update static routes: {}", e),
+        }
+        nodes = loaded;
+        failed = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let yaml = "\
+example.com/:
+  - 127.0.0.1:8080
+  - 127.0.0.1:8081
+\"example.com/api lb=round_robin\": [\"[::1]:9000\"]
+";
+        let toml = "\
+\"example.com/\" = [\"127.0.0.1:8080\", \"127.0.0.1:8081\"]
+\"example.com/api lb=round_robin\" = [\"[::1]:9000\"]
+";
+        let from_yaml = parse(Path::new("routes.yml"), yaml).unwrap();
+        assert_eq!(from_yaml, parse(Path::new("routes.toml"), toml).unwrap());
+        assert!(parse(Path::new("routes.ini"), toml).is_err());
+        assert!(parse(Path::new("routes.toml"), "example.com/ = 1").is_err());
+
+        let loaded = nodes(from_yaml).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded[0].address, "127.0.0.1");
+        assert_eq!(loaded[0].port, 8080);
+        assert_eq!(loaded[0].tags, vec!["urlprefix-example.com/"]);
+        assert_eq!(loaded[2].address, "[::1]");
+        assert_eq!(loaded[2].port, 9000);
+        assert_eq!(
+            loaded[2].tags,
+            vec!["urlprefix-example.com/api lb=round_robin"]
+        );
+
+        let mut routes = Routes::new();
+        routes.insert("example.com".to_string(), vec!["127.0.0.1:80".to_string()]);
+        assert!(nodes(routes).is_err());
+        let mut routes = Routes::new();
+        routes.insert("example.com/".to_string(), vec!["127.0.0.1".to_string()]);
+        assert!(nodes(routes).is_err());
+        let mut routes = Routes::new();
+        routes.insert("example.com/".to_string(), Vec::new());
+        assert!(nodes(routes).is_err());
+    }
+}
@@ -19,6 +19,15 @@ Cache-Control: max-age=0\r
     assert_eq!(result.unwrap(), "www.rust-lang.org");
 }
 
+#[test]
+fn extract_host_port() {
+    let header =
+        "GET / HTTP/1.1\r\nX-Forwarded-Host: evil.com\r\nHOST: WWW.Rust-Lang.org:8443\r\n\r\n";
+    assert_eq!(extract_host(header).unwrap(), "www.rust-lang.org");
+    let header = "GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n";
+    assert_eq!(extract_host(header).unwrap(), "[::1]");
+}
+
 #[test]
 fn extract_host_no_host() {
     let header = "GET / HTTP/1.1\r
@@ -101,6 +110,13 @@ fn get_available_port(mut range: Range<u16>) -> Option<u16> {
     range.find(|port| port_available(*port))
 }
 
+fn http_listener(port: u16) -> Listeners {
+    Listeners {
+        http: format!("127.0.0.1:{}", port),
+        ..Listeners::default()
+    }
+}
+
 #[test]
 fn test_server() {
     let listenport = get_available_port(6000..8000);
@@ -136,7 +152,7 @@ fn test_server() {
     thread::spawn(move || {
         eprintln!(
             "PROXY SERVER RETURNED: {:?}",
-            run_server(&format!("127.0.0.1:{}", proxyport), registry)
+            run_server(http_listener(proxyport), Arc::new(Proxy::new(registry, 16)))
                 .map_err(|e| format!("{:?}", e))
         );
     });
@@ -153,3 +169,866 @@ fn test_server() {
     assert!(text.is_ok());
     assert_eq!(text.unwrap(), "hello world");
 }
+
+// Read a request or response header from `stream`, up to the blank line.
+fn read_head(stream: &mut std::net::TcpStream) -> String {
+    use std::io::Read;
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    String::from_utf8(head).unwrap()
+}
+
+// Read one response with a Content-Length body from `stream`.
+fn read_response(stream: &mut std::net::TcpStream) -> String {
+    use std::io::Read;
+    let head = read_head(stream);
+    let length: usize = extract_header(&head, "Content-Length")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).unwrap();
+    String::from_utf8(body).unwrap()
+}
+
+// Connect to `port`, giving the server there a moment to start listening.
+fn connect(port: u16) -> std::net::TcpStream {
+    (0..100)
+        .find_map(|_| {
+            thread::sleep(Duration::from_millis(10));
+            std::net::TcpStream::connect(format!("127.0.0.1:{}", port)).ok()
+        })
+        .unwrap()
+}
+
+// Send `request` on a new connection to `port` and return the status line
+// of the response. Error responses close the connection, so this reads
+// until it's closed.
+fn status_line(port: u16, request: &[u8]) -> String {
+    use std::io::{Read, Write};
+    let mut stream = connect(port);
+    stream.write_all(request).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.lines().next().unwrap_or("").to_string()
+}
+
+// GET `path` from `port` with `host` in the Host header, giving the server
+// a moment to start listening.
+fn get(port: u16, host: &str, path: &str) -> reqwest::Response {
+    let client = reqwest::Client::new();
+    (0..100)
+        .find_map(|_| {
+            thread::sleep(Duration::from_millis(10));
+            client
+                .get(&format!("http://127.0.0.1:{}{}", port, path))
+                .header(reqwest::header::HOST, host)
+                .send()
+                .ok()
+        })
+        .unwrap()
+}
+
+// Start a backend that answers every request with `text`, and return its
+// port.
+fn text_backend(text: &'static str) -> u16 {
+    let port = get_available_port(6000..8000).unwrap();
+    let server = rouille::Server::new(format!("127.0.0.1:{}", port), move |_| {
+        rouille::Response::text(text)
+    })
+    .unwrap();
+    thread::spawn(move || server.run());
+    port
+}
+
+// Serve `proxy` on a free port, and return the port.
+fn start_proxy(proxy: Proxy<registry::tests::TestConsul>) -> u16 {
+    let proxyport = get_available_port(8000..10000).unwrap();
+    thread::spawn(move || run_server(http_listener(proxyport), Arc::new(proxy)));
+    proxyport
+}
+
+// Serve `proxy` and the admin API on free ports, and return both ports.
+fn start_proxy_with_admin(proxy: Proxy<registry::tests::TestConsul>) -> (u16, u16) {
+    let proxyport = get_available_port(8000..10000).unwrap();
+    let adminport = get_available_port(10000..12000).unwrap();
+    let listeners = Listeners {
+        admin: Some(format!("127.0.0.1:{}", adminport)),
+        ..http_listener(proxyport)
+    };
+    thread::spawn(move || run_server(listeners, Arc::new(proxy)));
+    (proxyport, adminport)
+}
+
+#[test]
+fn test_keep_alive_routing() {
+    use std::io::Write;
+
+    // Two backends, each answering with its own name.
+    let registry = Arc::new(registry::tests::registry_with_routes(&[
+        ("first.com", "/", text_backend("first")),
+        ("second.com", "/", text_backend("second")),
+    ]));
+    let proxyport = start_proxy(Proxy::new(registry, 16));
+
+    let mut stream = connect(proxyport);
+
+    // Each request on the connection is routed on its own Host header,
+    // including pipelined requests.
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: first.com\r\n\r\n")
+        .unwrap();
+    assert_eq!(read_response(&mut stream), "first");
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\nHost: second.com\r\n\r\n\
+              GET / HTTP/1.1\r\nHost: first.com\r\n\r\n",
+        )
+        .unwrap();
+    assert_eq!(read_response(&mut stream), "second");
+    assert_eq!(read_response(&mut stream), "first");
+}
+
+#[test]
+fn test_host_header() {
+    use std::io::Write;
+
+    let registry = Arc::new(registry::tests::registry_with_routes(&[
+        ("good.com", "/", text_backend("good")),
+        ("evil.com", "/", text_backend("evil")),
+        ("[::1]", "/", text_backend("ipv6")),
+    ]));
+    let proxyport = start_proxy(Proxy::new(registry, 16));
+    let mut stream = connect(proxyport);
+
+    // Only the Host header itself is routed on, without its port.
+    for (request, expected) in &[
+        (
+            "GET / HTTP/1.1\r\nX-Forwarded-Host: evil.com\r\nHost: good.com\r\n\r\n",
+            "good",
+        ),
+        ("GET / HTTP/1.1\r\nHost: Good.com:8443\r\n\r\n", "good"),
+        ("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n\r\n", "ipv6"),
+    ] {
+        stream.write_all(request.as_bytes()).unwrap();
+        assert_eq!(read_response(&mut stream), *expected, "{}", request);
+    }
+}
+
+#[test]
+fn test_tls_server() {
+    let listenport = text_backend("hello tls");
+
+    let registry = Arc::new(registry::tests::test_registry(
+        "test-website.com",
+        listenport,
+    ));
+    assert!(registry.update().is_ok());
+
+    let store = Arc::new(tls::CertStore::new());
+    store.set_source(
+        "dir",
+        tls::load_dir(std::path::Path::new("testdata")).unwrap(),
+    );
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls::server_config(store)));
+
+    let proxyport = get_available_port(8000..9000).unwrap();
+    let tlsport = get_available_port(9000..10000).unwrap();
+    let listeners = Listeners {
+        tls: Some((format!("127.0.0.1:{}", tlsport), acceptor)),
+        ..http_listener(proxyport)
+    };
+    thread::spawn(move || run_server(listeners, Arc::new(Proxy::new(registry, 16))));
+
+    // The test certificate is self-signed, for localhost.
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    // Give the proxy a moment to start listening.
+    let mut response = (0..100)
+        .find_map(|_| {
+            thread::sleep(Duration::from_millis(10));
+            client
+                .get(&format!("https://localhost:{}", tlsport))
+                .header(reqwest::header::HOST, "test-website.com")
+                .send()
+                .ok()
+        })
+        .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(response.text().unwrap(), "hello tls");
+}
+
+#[test]
+fn test_passthrough_server() {
+    use read_client_hello::tests::{client_hello, sni};
+    use std::io::{Read, Write};
+
+    let listenport = text_backend("hello passthrough");
+    // A backend that answers a ClientHello with a fixed reply.
+    let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_port = backend.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in backend.incoming() {
+            let mut stream = stream.unwrap();
+            stream.read_exact(&mut [0; 5]).unwrap();
+            stream.write_all(b"wildcard").unwrap();
+        }
+    });
+
+    let proxyport = get_available_port(8000..9000).unwrap();
+    let tlsport = get_available_port(9000..9500).unwrap();
+    let passthroughport = get_available_port(9500..10000).unwrap();
+
+    // The passthrough listener sends "localhost" on to robby's own TLS
+    // listener, which terminates TLS and routes on the Host header.
+    let registry = Arc::new(registry::tests::registry_with_routes(&[
+        ("localhost", "/", tlsport),
+        ("test-website.com", "/", listenport),
+        ("*.example.com", "/", backend_port),
+    ]));
+    let store = Arc::new(tls::CertStore::new());
+    store.set_source(
+        "dir",
+        tls::load_dir(std::path::Path::new("testdata")).unwrap(),
+    );
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls::server_config(store)));
+    let listeners = Listeners {
+        tls: Some((format!("127.0.0.1:{}", tlsport), acceptor)),
+        passthrough: Some(format!("127.0.0.1:{}", passthroughport)),
+        ..http_listener(proxyport)
+    };
+    thread::spawn(move || run_server(listeners, Arc::new(Proxy::new(registry, 16))));
+
+    let client = reqwest::Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let mut response = (0..100)
+        .find_map(|_| {
+            thread::sleep(Duration::from_millis(10));
+            client
+                .get(&format!("https://localhost:{}", passthroughport))
+                .header(reqwest::header::HOST, "test-website.com")
+                .send()
+                .ok()
+        })
+        .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(response.text().unwrap(), "hello passthrough");
+
+    // Server names are matched against wildcard routes like host names.
+    let mut stream = connect(passthroughport);
+    stream
+        .write_all(&client_hello(&sni("app.example.com"), 16384))
+        .unwrap();
+    let mut received = String::new();
+    stream.read_to_string(&mut received).unwrap();
+    assert_eq!(received, "wildcard");
+}
+
+#[test]
+fn test_acme_challenge() {
+    let dir = std::env::temp_dir().join(format!("robby-challenge-test-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("challenges")).unwrap();
+    std::fs::write(dir.join("challenges/abc123"), "abc123.thumbprint").unwrap();
+    let storage = Arc::new(acme::Storage::Dir(dir.clone()));
+
+    // Challenges are answered even for hosts without a route.
+    let registry = Arc::new(registry::tests::registry_with_routes(&[]));
+    let mut proxy = Proxy::new(registry, 16);
+    proxy.acme = Some(storage);
+    let proxyport = start_proxy(proxy);
+
+    let get = |path: &str| get(proxyport, "new-site.com", path);
+    let mut response = get("/.well-known/acme-challenge/abc123");
+    assert!(response.status().is_success());
+    assert_eq!(response.text().unwrap(), "abc123.thumbprint");
+    assert_eq!(
+        get("/.well-known/acme-challenge/nope").status(),
+        reqwest::StatusCode::NOT_FOUND
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_forwarded_headers() {
+    // The backend echoes the forwarding headers it was sent.
+    let listenport = get_available_port(6000..8000).unwrap();
+    let server = rouille::Server::new(format!("127.0.0.1:{}", listenport), |request| {
+        let headers = [
+            "X-Forwarded-For",
+            "X-Forwarded-Proto",
+            "X-Real-IP",
+            "Forwarded",
+        ]
+        .iter()
+        .map(|name| format!("{}={}", name, request.header(name).unwrap_or("")))
+        .collect::<Vec<_>>();
+        rouille::Response::text(headers.join("\n"))
+    })
+    .unwrap();
+    thread::spawn(move || server.run());
+
+    let start = |trusted: &[&str]| {
+        let registry = Arc::new(registry::tests::registry_with_routes(&[(
+            "test-website.com",
+            "/",
+            listenport,
+        )]));
+        let mut proxy = Proxy::new(registry, 16);
+        let trusted: Vec<String> = trusted.iter().map(|net| net.to_string()).collect();
+        proxy.trusted_proxies = TrustedProxies::parse(&trusted).unwrap();
+        let proxyport = get_available_port(8000..10000).unwrap();
+        thread::spawn(move || run_server(http_listener(proxyport), Arc::new(proxy)));
+        proxyport
+    };
+    let get = |proxyport: u16| {
+        (0..100)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(10));
+                reqwest::Client::new()
+                    .get(&format!("http://127.0.0.1:{}", proxyport))
+                    .header(reqwest::header::HOST, "test-website.com")
+                    .header("X-Forwarded-For", "6.6.6.6")
+                    .header("X-Real-IP", "6.6.6.6")
+                    .send()
+                    .ok()
+            })
+            .unwrap()
+            .text()
+            .unwrap()
+    };
+
+    // Headers from an untrusted client are replaced.
+    let untrusted = get(start(&[]));
+    assert_eq!(
+        untrusted,
+        "X-Forwarded-For=127.0.0.1\nX-Forwarded-Proto=http\nX-Real-IP=127.0.0.1\n\
+         Forwarded=for=127.0.0.1;host=test-website.com;proto=http"
+    );
+
+    // Headers from a trusted proxy are kept.
+    let trusted = get(start(&["127.0.0.0/8"]));
+    assert!(trusted.contains("X-Forwarded-For=6.6.6.6, 127.0.0.1\n"));
+    assert!(trusted.contains("X-Real-IP=6.6.6.6\n"));
+}
+
+#[test]
+fn test_proxy_protocol() {
+    use std::io::Write;
+
+    // The backend answers with everything it was sent.
+    let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_port = backend.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in backend.incoming() {
+            let mut stream = stream.unwrap();
+            let received = read_head(&mut stream);
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                received.len()
+            );
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(received.as_bytes()).unwrap();
+        }
+    });
+
+    let registry = Arc::new(registry::tests::registry_with_tags(&[(
+        "urlprefix-pp.com/ proxyproto=v1",
+        backend_port,
+    )]));
+    let proxyport = get_available_port(8000..10000).unwrap();
+    let listeners = Listeners {
+        proxy_protocol: true,
+        ..http_listener(proxyport)
+    };
+    thread::spawn(move || run_server(listeners, Arc::new(Proxy::new(registry, 16))));
+
+    let mut stream = connect(proxyport);
+    stream
+        .write_all(
+            b"PROXY TCP4 1.2.3.4 10.0.0.1 5678 80\r\n\
+              GET / HTTP/1.1\r\nHost: pp.com\r\n\r\n",
+        )
+        .unwrap();
+
+    // The client's address comes from the PROXY header, and is passed on
+    // in a PROXY header of robby's own.
+    let received = read_response(&mut stream);
+    assert!(received.starts_with("PROXY TCP4 1.2.3.4 10.0.0.1 5678 80\r\nGET / HTTP/1.1\r\n"));
+    assert!(received.contains("X-Forwarded-For: 1.2.3.4\r\n"));
+    assert!(received.contains("X-Forwarded-Port: 80\r\n"));
+}
+
+#[test]
+fn test_error_responses() {
+    // Nothing listens on the closed backend's port.
+    let closed_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let registry = Arc::new(registry::tests::registry_with_routes(&[(
+        "closed.com",
+        "/",
+        closed_port,
+    )]));
+    let proxyport = start_proxy(Proxy::new(registry, 16));
+    let status = |request: &[u8]| status_line(proxyport, request);
+
+    assert_eq!(
+        status(b"GET / HTTP/1.1\r\nHost: closed.com\r\n\r\n"),
+        "HTTP/1.1 502 Bad Gateway"
+    );
+    assert_eq!(
+        status(b"POST / HTTP/1.1\r\nHost: closed.com\r\nContent-Length: 2\r\n\r\nhi"),
+        "HTTP/1.1 502 Bad Gateway"
+    );
+    assert_eq!(
+        status(b"GET / HTTP/1.1\r\nHost: unknown.com\r\n\r\n"),
+        "HTTP/1.1 404 Not Found"
+    );
+    assert_eq!(
+        status(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n"),
+        "HTTP/1.1 400 Bad Request"
+    );
+    assert_eq!(
+        status(
+            b"POST / HTTP/1.1\r\nHost: closed.com\r\nContent-Length: 5\r\n\
+              Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n"
+        ),
+        "HTTP/1.1 400 Bad Request"
+    );
+    let huge = format!(
+        "GET / HTTP/1.1\r\nHost: closed.com\r\nX-Big: {}\r\n\r\n",
+        "a".repeat(20000)
+    );
+    assert_eq!(
+        status(huge.as_bytes()),
+        "HTTP/1.1 431 Request Header Fields Too Large"
+    );
+}
+
+#[test]
+fn test_retry_other_instance() {
+    use std::io::Write;
+
+    let port = get_available_port(6000..8000).unwrap();
+    let server = rouille::Server::new(format!("127.0.0.1:{}", port), |request| {
+        let mut body = String::new();
+        std::io::Read::read_to_string(&mut request.data().unwrap(), &mut body).unwrap();
+        rouille::Response::text(format!("got {}", body))
+    })
+    .unwrap();
+    thread::spawn(move || server.run());
+    let closed_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    // One instance of the route is down, so requests that are balanced to
+    // it have to be retried on the other one.
+    let registry = Arc::new(registry::tests::registry_with_routes(&[
+        ("retry.com", "/", closed_port),
+        ("retry.com", "/", port),
+    ]));
+    let proxyport = start_proxy(Proxy::new(registry, 16));
+
+    let mut stream = connect(proxyport);
+    for _ in 0..10 {
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nHost: retry.com\r\nContent-Length: 2\r\n\r\nhi")
+            .unwrap();
+        assert_eq!(read_response(&mut stream), "got hi");
+    }
+}
+
+#[test]
+fn test_truncated_responses() {
+    use std::io::{Read, Write};
+
+    // A backend that promises a longer body than it sends.
+    let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+    let backend_port = backend.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in backend.incoming() {
+            let mut stream = stream.unwrap();
+            read_head(&mut stream);
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nhello")
+                .unwrap();
+        }
+    });
+    let registry = Arc::new(registry::tests::registry_with_routes(&[(
+        "truncated.com",
+        "/",
+        backend_port,
+    )]));
+    let proxyport = start_proxy(Proxy::new(registry.clone(), 16));
+
+    // A backend that breaks off its responses is ejected like one that
+    // can't be reached.
+    for _ in 0..OutlierDetection::default().consecutive_failures {
+        let mut stream = connect(proxyport);
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: truncated.com\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        assert!(response.ends_with(b"hello"));
+    }
+    let ejected = (0..100).any(|_| {
+        thread::sleep(Duration::from_millis(10));
+        registry.routes().unwrap()[0].backends[0].ejected
+    });
+    assert!(ejected);
+}
+
+#[test]
+fn test_timeouts() {
+    // A backend that accepts connections and never answers.
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let silent_port = silent.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let connections: Vec<_> = silent.incoming().collect();
+        drop(connections);
+    });
+    let registry = Arc::new(registry::tests::registry_with_routes(&[(
+        "silent.com",
+        "/",
+        silent_port,
+    )]));
+    let mut proxy = Proxy::new(registry, 16);
+    proxy.timeouts.header_read = Duration::from_millis(300);
+    proxy.timeouts.first_byte = Duration::from_millis(300);
+    let proxyport = start_proxy(proxy);
+    let response = |request: &[u8]| status_line(proxyport, request);
+
+    assert_eq!(
+        response(b"GET / HTTP/1.1\r\nHost: silent.com\r\n"),
+        "HTTP/1.1 408 Request Timeout"
+    );
+    assert_eq!(
+        response(b"GET / HTTP/1.1\r\nHost: silent.com\r\n\r\n"),
+        "HTTP/1.1 504 Gateway Timeout"
+    );
+    // A connection that never sends anything is closed quietly.
+    assert_eq!(response(b""), "");
+}
+
+#[test]
+fn test_handshake_timeouts() {
+    use std::io::Read;
+
+    let registry = Arc::new(registry::tests::registry_with_routes(&[]));
+    let store = Arc::new(tls::CertStore::new());
+    store.set_source(
+        "dir",
+        tls::load_dir(std::path::Path::new("testdata")).unwrap(),
+    );
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls::server_config(store)));
+    let mut proxy = Proxy::new(registry, 16);
+    proxy.timeouts.header_read = Duration::from_millis(300);
+    let proxyport = get_available_port(8000..9000).unwrap();
+    let tlsport = get_available_port(9000..10000).unwrap();
+    let listeners = Listeners {
+        tls: Some((format!("127.0.0.1:{}", tlsport), acceptor)),
+        proxy_protocol: true,
+        ..http_listener(proxyport)
+    };
+    thread::spawn(move || run_server(listeners, Arc::new(proxy)));
+
+    // A client that never finishes its TLS handshake or PROXY header is
+    // disconnected once the header read timeout passes.
+    for port in &[tlsport, proxyport] {
+        let mut stream = connect(*port);
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut received = Vec::new();
+        assert!(stream.read_to_end(&mut received).is_ok());
+        assert!(received.is_empty());
+    }
+}
+
+#[test]
+fn test_metrics() {
+    let port = text_backend("hello");
+    let registry = Arc::new(registry::tests::registry_with_routes(&[(
+        "metrics.com",
+        "/",
+        port,
+    )]));
+    let (proxyport, adminport) = start_proxy_with_admin(Proxy::new(registry, 16));
+
+    let get = |port: u16, host: &str| get(port, host, "/metrics");
+    assert_eq!(get(proxyport, "metrics.com").text().unwrap(), "hello");
+    assert_eq!(get(proxyport, "unknown.com").status().as_u16(), 404);
+
+    let mut response = get(adminport, "localhost");
+    assert!(response.status().is_success());
+    let text = response.text().unwrap();
+    for line in &[
+        format!(
+            "robby_requests_total{{route=\"metrics.com/\",backend=\"127.0.0.1:{}\",status=\"200\"}} 1",
+            port
+        ),
+        "robby_request_duration_seconds_count{route=\"metrics.com/\"} 1".to_string(),
+        "robby_error_responses_total{status=\"404\"} 1".to_string(),
+        "robby_registry_routes 1".to_string(),
+    ] {
+        assert!(text.lines().any(|l| l == line), "missing {} in {}", line, text);
+    }
+}
+
+#[test]
+fn test_access_log() {
+    use std::io::Write;
+
+    let port = text_backend("hello");
+    // A backend that agrees to any upgrade, then closes the connection.
+    let upgrading = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upgrading_port = upgrading.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in upgrading.incoming() {
+            let mut stream = stream.unwrap();
+            read_head(&mut stream);
+            stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n")
+                .unwrap();
+        }
+    });
+    let registry = Arc::new(registry::tests::registry_with_routes(&[
+        ("logged.com", "/", port),
+        ("logged.com", "/ws", upgrading_port),
+    ]));
+    let path = std::env::temp_dir().join(format!("robby-test-access-{}.log", std::process::id()));
+    let mut proxy = Proxy::new(registry, 16);
+    proxy.access_log = Some(AccessLog::file("json".parse().unwrap(), &path).unwrap());
+    let proxyport = start_proxy(proxy);
+
+    let get = |host: &str| get(proxyport, host, "/page?x=1");
+    assert_eq!(get("logged.com").text().unwrap(), "hello");
+    assert_eq!(get("unknown.com").status().as_u16(), 404);
+    let mut stream = connect(proxyport);
+    stream
+        .write_all(
+            b"GET /ws HTTP/1.1\r\nHost: logged.com\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n",
+        )
+        .unwrap();
+    assert!(read_head(&mut stream).starts_with("HTTP/1.1 101 "));
+
+    // A request is logged once its response has been written, which may be
+    // after the client has read it.
+    let log = (0..100)
+        .map(|_| {
+            thread::sleep(Duration::from_millis(10));
+            std::fs::read_to_string(&path).unwrap()
+        })
+        .find(|log| log.lines().count() >= 3)
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines: Vec<serde_json::Value> = log
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0]["host"], "logged.com");
+    assert_eq!(lines[0]["method"], "GET");
+    assert_eq!(lines[0]["uri"], "/page?x=1");
+    assert_eq!(lines[0]["backend"], format!("127.0.0.1:{}", port));
+    assert_eq!(lines[0]["status"], 200);
+    assert!(lines[0]["bytes_out"].as_u64().unwrap() > 5);
+    assert!(lines[0]["backend_duration_ms"].is_number());
+    assert_eq!(lines[1]["host"], "unknown.com");
+    assert_eq!(lines[1]["backend"], serde_json::Value::Null);
+    assert_eq!(lines[1]["status"], 404);
+    assert_eq!(lines[2]["uri"], "/ws");
+    assert_eq!(lines[2]["backend"], format!("127.0.0.1:{}", upgrading_port));
+    assert_eq!(lines[2]["status"], 101);
+}
+
+#[test]
+fn test_admin_api() {
+    let registry = Arc::new(registry::tests::registry_with_routes(&[
+        ("admin.com", "/", 8080),
+        ("admin.com", "/api", 8081),
+    ]));
+    let (_, adminport) = start_proxy_with_admin(Proxy::new(registry, 16));
+
+    let get = |path: &str| get(adminport, "localhost", path);
+    let json = |path: &str| -> serde_json::Value {
+        let mut response = get(path);
+        assert!(response.status().is_success(), "{}", path);
+        serde_json::from_str(&response.text().unwrap()).unwrap()
+    };
+
+    let routes = json("/routes");
+    assert_eq!(routes["routes"].as_array().unwrap().len(), 2);
+    assert_eq!(routes["routes"][0]["path"], "/api");
+    assert_eq!(routes["routes"][0]["policy"], "random");
+    assert_eq!(routes["routes"][0]["backends"][0]["addr"], "127.0.0.1:8081");
+    assert_eq!(routes["routes"][0]["backends"][0]["status"], "passing");
+    assert_eq!(routes["last_update"], serde_json::Value::Null);
+
+    let lookup = json("/lookup?host=admin.com&path=%2Fapi%2Fusers");
+    assert_eq!(lookup["path"], "/api/users");
+    assert_eq!(lookup["route"]["host"], "admin.com");
+    assert_eq!(lookup["route"]["path"], "/api");
+    assert_eq!(lookup["backend"], "127.0.0.1:8081");
+
+    let lookup = json("/lookup?host=other.com");
+    assert_eq!(lookup["route"], serde_json::Value::Null);
+    assert_eq!(get("/lookup").status().as_u16(), 400);
+    assert_eq!(get("/nothing").status().as_u16(), 404);
+}
+
+#[test]
+fn test_reload() {
+    use std::io::Write;
+
+    let listenport = text_backend("hello reload");
+    let registry = Arc::new(registry::tests::registry_with_routes(&[(
+        "reload.com",
+        "/",
+        listenport,
+    )]));
+
+    let oldport = get_available_port(8000..10000).unwrap();
+    let proxy = Proxy::new(registry.clone(), 16);
+    let (_runtime, server) = super::start_server(http_listener(oldport), Arc::new(proxy)).unwrap();
+    let connect = |port: u16| std::net::TcpStream::connect(format!("127.0.0.1:{}", port));
+    let mut old = connect(oldport).unwrap();
+    old.write_all(b"GET / HTTP/1.1\r\nHost: reload.com\r\n\r\n")
+        .unwrap();
+    assert_eq!(read_response(&mut old), "hello reload");
+
+    // Move the listener and switch to JSON error pages.
+    let newport = get_available_port(10000..12000).unwrap();
+    let mut proxy = Proxy::new(registry, 16);
+    proxy.error_pages = ErrorPages::new(error_page::Format::Json);
+    let bound = server.bind(http_listener(newport)).unwrap();
+    server.set_proxy(Arc::new(proxy));
+    server.start(bound);
+
+    let mut new = connect(newport).unwrap();
+    new.write_all(b"GET / HTTP/1.1\r\nHost: missing.com\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut new).starts_with('{'));
+
+    // The connection accepted before the reload keeps the old settings,
+    // but nothing new is accepted on the old port.
+    old.write_all(b"GET / HTTP/1.1\r\nHost: reload.com\r\n\r\n")
+        .unwrap();
+    assert_eq!(read_response(&mut old), "hello reload");
+    old.write_all(b"GET / HTTP/1.1\r\nHost: missing.com\r\n\r\n")
+        .unwrap();
+    assert!(read_response(&mut old).contains("<html"));
+    assert!((0..100).any(|_| {
+        thread::sleep(Duration::from_millis(10));
+        connect(oldport).is_err()
+    }));
+
+    // An address that's taken fails before anything changes.
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let listeners = http_listener(taken.local_addr().unwrap().port());
+    assert!(server.bind(listeners).is_err());
+    assert!(connect(newport).is_ok());
+
+    // Settings that are only read at startup are reported when they change,
+    // lists included.
+    let path = std::env::temp_dir().join(format!("robby-reload-{}.yml", std::process::id()));
+    let options = Options {
+        config: Some(path.clone()),
+        ..Options::default()
+    };
+    let load = |text: &str| {
+        std::fs::write(&path, text).unwrap();
+        get_config(&options, HashMap::new()).unwrap()
+    };
+    let started = load("providers:\n  - type: consul\nretries: 1\n");
+    let same = load("providers:\n  - type: consul\nretries: 2\n");
+    assert!(restart_changes(&same, &started).is_empty());
+    let added = load("providers:\n  - type: consul\n  - type: nomad\nretries: 1\n");
+    assert_eq!(restart_changes(&added, &started), vec!["providers"]);
+    let removed = load("retries: 1\n");
+    assert_eq!(restart_changes(&removed, &started), vec!["providers"]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_get_config() {
+    use std::{env, fs};
+
+    let path = env::temp_dir().join(format!("robby-config-{}.yml", std::process::id()));
+    fs::write(&path, "bind_port: 7000\nretries: 5\nmark_down_secs: 3\n").unwrap();
+    let environment: HashMap<String, String> = vec![
+        ("ROBBY_RETRIES", "6"),
+        ("ROBBY_TRUSTED_PROXIES", "10.0.0.0/8, 127.0.0.1"),
+        ("MARK_DOWN_SECS", "4"),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value.to_string()))
+    .collect();
+    let options = Options {
+        config: Some(path.clone()),
+        bind: Some(("127.0.0.1".to_string(), "7001".to_string())),
+        ..Options::default()
+    };
+
+    // The command line beats the environment, which beats the file.
+    let conf = get_config(&options, environment).unwrap();
+    assert_eq!(conf.get_int("bind_port").unwrap(), 7001);
+    assert_eq!(conf.get_int("retries").unwrap(), 6);
+    assert_eq!(conf.get_int("mark_down_secs").unwrap(), 3);
+    assert_eq!(conf.get_str("load_balancing").unwrap(), "random");
+    assert_eq!(
+        get_list(&conf, "trusted_proxies").unwrap(),
+        vec!["10.0.0.0/8", "127.0.0.1"]
+    );
+    assert!(check_config(&conf).is_ok());
+
+    fs::write(&path, "load_balancing: fastest\n").unwrap();
+    assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_err());
+    fs::write(&path, "retries: 10\nretry_backoff_ms: 100000\n").unwrap();
+    assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_ok());
+    for bad in &[
+        "retries: -1\n",
+        "retries: 40\n",
+        "retry_backoff_ms: -5\n",
+        "client_header_timeout_secs: -1\n",
+        "mark_down_secs: -1\n",
+        "health_check_interval_secs: -1\n",
+        "consul_wait: -1\n",
+    ] {
+        fs::write(&path, bad).unwrap();
+        assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_err());
+    }
+    fs::write(&path, "tls_enabled: true\n").unwrap();
+    assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_ok());
+    fs::write(&path, "provider: nomad\nnomad_namespace: \"*\"\n").unwrap();
+    assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_ok());
+    fs::write(&path, "provider: zookeeper\n").unwrap();
+    assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_err());
+    fs::write(
+        &path,
+        "providers:\n  - type: consul\n  - {type: consul, name: dc2, datacenter: dc2}\n  - type: nomad\n",
+    )
+    .unwrap();
+    assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_ok());
+    fs::write(&path, "providers:\n  - type: consul\n  - type: consul\n").unwrap();
+    assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_err());
+    fs::write(&path, "providers:\n  - {type: nomad, datacenter: dc2}\n").unwrap();
+    assert!(check_config(&get_config(&options, HashMap::new()).unwrap()).is_err());
+    fs::write(&path, "retries: [\n").unwrap();
+    assert!(get_config(&options, HashMap::new()).is_err());
+    fs::remove_file(&path).unwrap();
+    assert!(get_config(&options, HashMap::new()).is_err());
+}
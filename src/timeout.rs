@@ -0,0 +1,302 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::Either;
+use tokio::{
+    io,
+    prelude::*,
+    timer::{Delay, Timeout},
+};
+
+/// How long robby waits on clients and backends. A zero timeout never
+/// expires.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    // For a client to send a complete request header.
+    pub header_read: Duration,
+    // For a connection to a backend to be established.
+    pub connect: Duration,
+    // For a backend to send its response header once it has the request.
+    pub first_byte: Duration,
+    // For a connection to move any data while a body is copied or a
+    // connection is tunneled.
+    pub idle: Duration,
+    // For a client connection as a whole.
+    pub lifetime: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            header_read: Duration::from_secs(30),
+            connect: Duration::from_secs(5),
+            first_byte: Duration::from_secs(60),
+            idle: Duration::from_secs(300),
+            lifetime: Duration::from_secs(0),
+        }
+    }
+}
+
+impl Timeouts {
+    /// These timeouts with a route's overrides applied.
+    pub fn for_route(&self, route: &RouteTimeouts) -> Timeouts {
+        Timeouts {
+            connect: route.connect.unwrap_or(self.connect),
+            first_byte: route.first_byte.unwrap_or(self.first_byte),
+            idle: route.idle.unwrap_or(self.idle),
+            ..*self
+        }
+    }
+}
+
+/// Timeouts set by a route's `connect_timeout=`, `response_timeout=` and
+/// `idle_timeout=` tag options, in seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RouteTimeouts {
+    pub connect: Option<Duration>,
+    pub first_byte: Option<Duration>,
+    pub idle: Option<Duration>,
+}
+
+impl RouteTimeouts {
+    pub fn from_options(options: &HashMap<String, String>) -> Result<RouteTimeouts, String> {
+        let seconds = |key: &str| match options.get(key) {
+            Some(value) => value
+                .parse()
+                .map(|secs| Some(Duration::from_secs(secs)))
+                .map_err(|e| format!("Bad {} {:?}: {}", key, value, e)),
+            None => Ok(None),
+        };
+        Ok(RouteTimeouts {
+            connect: seconds("connect_timeout")?,
+            first_byte: seconds("response_timeout")?,
+            idle: seconds("idle_timeout")?,
+        })
+    }
+}
+
+fn timed_out(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", what))
+}
+
+fn timer_error<E: std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::other(format!("Timer error: {:?}", e))
+}
+
+/// Fail `future` with a TimedOut error if it takes longer than `timeout`.
+pub fn with_timeout<F>(
+    future: F,
+    timeout: Duration,
+    what: &'static str,
+) -> impl Future<Item = F::Item, Error = io::Error>
+where
+    F: Future<Error = io::Error>,
+{
+    if timeout == Duration::from_secs(0) {
+        return Either::A(future);
+    }
+    Either::B(Timeout::new(future, timeout).map_err(move |e| {
+        if e.is_elapsed() {
+            timed_out(what)
+        } else if e.is_inner() {
+            e.into_inner().expect("inner error")
+        } else {
+            timer_error(e)
+        }
+    }))
+}
+
+/// Cut `connection` off once it has been open for `lifetime`.
+pub fn with_lifetime<F>(connection: F, lifetime: Duration) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+{
+    if lifetime == Duration::from_secs(0) {
+        return Either::A(connection);
+    }
+    let expired = Delay::new(Instant::now() + lifetime)
+        .map(|_| debug!("Closing a connection that reached its maximum lifetime"))
+        .map_err(|e| error!("Timer error: {:?}", e));
+    Either::B(connection.select(expired).then(|_| Ok(())))
+}
+
+/// When any of a set of streams last moved data.
+#[derive(Debug, Clone)]
+pub struct Activity(Arc<Mutex<Instant>>);
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// A stream that fails with a TimedOut error when it's waiting to read or
+/// write and none of the streams sharing its Activity have moved any data
+/// for `timeout`. Sharing an Activity between both ends of a proxied
+/// connection keeps a quiet side open while the other side is busy.
+pub struct Idle<S> {
+    stream: S,
+    activity: Activity,
+    timeout: Duration,
+    delay: Option<Delay>,
+}
+
+impl<S> Idle<S> {
+    pub fn new(stream: S, timeout: Duration, activity: Activity) -> Idle<S> {
+        Idle {
+            stream,
+            activity,
+            timeout,
+            delay: None,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Called when the stream would block. Arranges for the task to be
+    /// woken when the timeout could expire, and fails if it has.
+    fn check_idle(&mut self) -> io::Result<()> {
+        if self.timeout == Duration::from_secs(0) {
+            return Ok(());
+        }
+        loop {
+            let deadline = self.activity.last() + self.timeout;
+            if Instant::now() >= deadline {
+                return Err(timed_out("Idle connection"));
+            }
+            let delay = self.delay.get_or_insert_with(|| Delay::new(deadline));
+            if Delay::deadline(delay) != deadline {
+                delay.reset(deadline);
+            }
+            match delay.poll().map_err(timer_error)? {
+                Async::Ready(()) => continue,
+                Async::NotReady => return Ok(()),
+            }
+        }
+    }
+
+    fn track<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        match result {
+            Ok(n) => {
+                self.activity.touch();
+                Ok(n)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.check_idle()?;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<S: Read> Read for Idle<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.stream.read(buf);
+        self.track(result)
+    }
+}
+
+impl<S: Write> Write for Idle<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.stream.write(buf);
+        self.track(result)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = self.stream.flush();
+        self.track(result)
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Idle<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Idle<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stream.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        runtime::current_thread::Runtime,
+    };
+
+    #[test]
+    fn test_route_timeouts() {
+        let options: HashMap<String, String> = vec![
+            ("connect_timeout", "1"),
+            ("idle_timeout", "0"),
+            ("lb", "random"),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        let route = RouteTimeouts::from_options(&options).unwrap();
+        let timeouts = Timeouts::default().for_route(&route);
+        assert_eq!(timeouts.connect, Duration::from_secs(1));
+        assert_eq!(timeouts.first_byte, Timeouts::default().first_byte);
+        assert_eq!(timeouts.idle, Duration::from_secs(0));
+
+        let mut bad = HashMap::new();
+        bad.insert("response_timeout".to_string(), "soon".to_string());
+        assert!(RouteTimeouts::from_options(&bad).is_err());
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut runtime = Runtime::new().unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = listener.incoming().into_future().map_err(|(e, _)| e);
+        let (client, (server, _)) = runtime
+            .block_on(TcpStream::connect(&addr).join(accept))
+            .unwrap();
+        let mut client = client;
+        let server = Idle::new(server.unwrap(), Duration::from_millis(100), Activity::new());
+
+        // Data keeps the stream from timing out.
+        let start = Instant::now();
+        client.write_all(b"hi").unwrap();
+        let (server, buf) = runtime.block_on(io::read_exact(server, [0; 2])).unwrap();
+        assert_eq!(&buf, b"hi");
+
+        let e = runtime
+            .block_on(io::read_exact(server, [0; 1]))
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_with_timeout() {
+        let mut runtime = Runtime::new().unwrap();
+        let never = future::empty::<(), io::Error>();
+        let e = runtime
+            .block_on(with_timeout(never, Duration::from_millis(10), "Test"))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(runtime
+            .block_on(with_timeout(future::ok(()), Duration::from_secs(0), "Test"))
+            .is_ok());
+    }
+}